@@ -0,0 +1,388 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+};
+
+use crate::Args;
+use libmeld::{exists, hash_path, is_dir, Bin, Error};
+use log::{debug, info};
+use similar::{ChangeTag, TextDiff};
+use structopt::StructOpt;
+
+// Define Module Arguments
+#[derive(Debug, StructOpt, Clone)]
+pub struct DiffArgs {
+    #[structopt(
+        short = "r",
+        long = "rev",
+        number_of_values = 1,
+        help = "version number or tag; once to diff against the live file, twice to diff two versions"
+    )]
+    pub(crate) revs: Vec<String>,
+
+    #[structopt(long = "stat", help = "only show a summary of changed lines")]
+    pub(crate) stat: bool,
+
+    #[structopt(help = "config file/folder to diff")]
+    pub(crate) config_path: String,
+}
+
+/// One side of a comparison
+enum Side {
+    Live(String),
+    Stored(String, u32),
+    Absent,
+}
+
+/// Files larger than this are only checked for NUL bytes and invalid UTF-8 in their head
+const BINARY_SNIFF_LEN: usize = 8000;
+
+fn is_binary(data: &[u8]) -> bool {
+    let head = &data[..data.len().min(BINARY_SNIFF_LEN)];
+    if head.contains(&0) {
+        return true;
+    }
+    // a character cut off at the end of the head is not invalid
+    match std::str::from_utf8(head) {
+        Ok(_) => false,
+        Err(e) => e.error_len().is_some(),
+    }
+}
+
+/// Read one side of a comparison; None if it does not exist
+fn read_side(bin: &Bin, side: &Side) -> Result<Option<Vec<u8>>, Error> {
+    match side {
+        Side::Live(path) => {
+            if !exists(path) || is_dir(path)? {
+                return Ok(None);
+            }
+            match fs::read(path) {
+                Ok(b) => Ok(Some(b)),
+                Err(e) => Err(Error::IOError { msg: e.to_string() }),
+            }
+        }
+        Side::Stored(blob, ver) => Ok(Some(bin.read_blob(blob, *ver)?)),
+        Side::Absent => Ok(None),
+    }
+}
+
+/// Dirs are tracked but have no contents to compare
+fn is_dir_side(bin: &Bin, side: &Side) -> Result<bool, Error> {
+    match side {
+        Side::Live(path) => Ok(exists(path) && is_dir(path)?),
+        Side::Stored(blob, ver) => Ok(bin
+            .db
+            .get_versions(blob)?
+            .values()
+            .any(|v| v.ver == *ver && v.data_hash == "DIR")),
+        Side::Absent => Ok(false),
+    }
+}
+
+/// Resolve a version number or tag to a config version
+fn resolve_rev(bin: &Bin, blob: &String, rev: &String) -> Result<u32, Error> {
+    let versions = bin.db.get_versions(blob)?;
+
+    if let Ok(ver) = rev.parse::<u32>() {
+        if versions.values().any(|v| v.ver == ver) {
+            return Ok(ver);
+        }
+    }
+
+    // newest version carrying the tag
+    match versions
+        .values()
+        .filter(|v| &v.tag == rev)
+        .map(|v| v.ver)
+        .max()
+    {
+        Some(v) => Ok(v),
+        None => Err(Error::TagNotFound {
+            msg: rev.to_string(),
+        }),
+    }
+}
+
+/// Resolve a map version number or tag to a map version
+fn resolve_map_rev(bin: &Bin, blob: &String, rev: &String) -> Result<u32, Error> {
+    let maps = bin.db.get_map_versions(blob)?;
+
+    if let Ok(ver) = rev.parse::<u32>() {
+        if maps.iter().any(|m| m.ver == ver) {
+            return Ok(ver);
+        }
+    }
+
+    // maps are newest first
+    match maps.iter().find(|m| &m.tag == rev) {
+        Some(m) => Ok(m.ver),
+        None => Err(Error::TagNotFound {
+            msg: rev.to_string(),
+        }),
+    }
+}
+
+/// Compare two sides, printing a unified diff unless stat is set
+/// Returns (insertions, deletions), or None if the files are binary and differ
+fn diff_sides(
+    bin: &Bin,
+    old: (&String, &Side),
+    new: (&String, &Side),
+    stat: bool,
+) -> Result<Option<(usize, usize)>, Error> {
+    let old_data = read_side(bin, old.1)?.unwrap_or_default();
+    let new_data = read_side(bin, new.1)?.unwrap_or_default();
+
+    if old_data == new_data {
+        return Ok(Some((0, 0)));
+    }
+
+    if is_binary(&old_data) || is_binary(&new_data) {
+        if !stat {
+            println!("Binary files {} and {} differ", old.0, new.0);
+        }
+        return Ok(None);
+    }
+
+    // only the heads were checked as utf8, so anything invalid past them is replaced
+    let old_text = String::from_utf8_lossy(&old_data);
+    let new_text = String::from_utf8_lossy(&new_data);
+    let diff = TextDiff::from_lines(old_text.as_ref(), new_text.as_ref());
+
+    let mut counts = (0, 0);
+    for change in diff.iter_all_changes() {
+        match change.tag() {
+            ChangeTag::Insert => counts.0 += 1,
+            ChangeTag::Delete => counts.1 += 1,
+            ChangeTag::Equal => (),
+        }
+    }
+
+    if !stat {
+        print!(
+            "{}",
+            diff.unified_diff().context_radius(3).header(old.0, new.0)
+        );
+    }
+
+    Ok(Some(counts))
+}
+
+/// Print a diffstat line per changed file and a total
+fn print_stat(stats: &Vec<(String, Option<(usize, usize)>)>) {
+    let width = stats.iter().map(|(p, _)| p.len()).max().unwrap_or(0);
+    let mut totals = (0, 0, 0);
+
+    for (path, counts) in stats {
+        match counts {
+            Some((0, 0)) => continue,
+            Some((ins, del)) => {
+                println!(
+                    " {:width$} | {:>5} {}{}",
+                    path,
+                    ins + del,
+                    "+".repeat((*ins).min(40)),
+                    "-".repeat((*del).min(40)),
+                    width = width
+                );
+                totals.1 += ins;
+                totals.2 += del;
+            }
+            None => println!(" {:width$} | Bin", path, width = width),
+        }
+        totals.0 += 1;
+    }
+
+    println!(
+        " {} files changed, {} insertions(+), {} deletions(-)",
+        totals.0, totals.1, totals.2
+    );
+}
+
+/// Main handler for diffing configs against the bin
+pub fn handler(main_args: Args, args: DiffArgs) -> Result<(), libmeld::Error> {
+    let bin = main_args.open_bin()?;
+
+    if args.revs.len() > 2 {
+        return Err(Error::IOError {
+            msg: "at most two revisions can be compared".to_string(),
+        });
+    }
+
+    let config_map_path = bin.mapper.real_path_to_map(&args.config_path)?;
+    let blob = match bin.db.config_exists(&config_map_path)? {
+        Some(b) => b,
+        None => {
+            return Err(Error::FileNotFound {
+                msg: config_map_path,
+            })
+        }
+    };
+
+    // (display path, old side, new side) for every file compared
+    let mut pairs: Vec<(String, Side, Side)> = Vec::new();
+
+    match bin.db.get_current_map(&blob)? {
+        None => {
+            debug!("Diffing single file");
+            let real_path = bin.mapper.map_to_real_path(&config_map_path)?;
+            let (old, new) = match args.revs.as_slice() {
+                [] => match bin.db.get_current_version(&blob)? {
+                    Some(v) => (Side::Stored(blob.clone(), v.ver), Side::Live(real_path)),
+                    None => return Err(Error::SomethingFailed),
+                },
+                [a] => (
+                    Side::Stored(blob.clone(), resolve_rev(&bin, &blob, a)?),
+                    Side::Live(real_path),
+                ),
+                [a, b] => (
+                    Side::Stored(blob.clone(), resolve_rev(&bin, &blob, a)?),
+                    Side::Stored(blob.clone(), resolve_rev(&bin, &blob, b)?),
+                ),
+                _ => return Err(Error::SomethingFailed),
+            };
+            pairs.push((config_map_path, old, new));
+        }
+        Some(current) => {
+            debug!("Diffing map");
+            let old_ver = match args.revs.first() {
+                Some(r) => resolve_map_rev(&bin, &blob, r)?,
+                None => current.ver,
+            };
+            let old_entries: BTreeMap<String, u32> =
+                bin.get_map_entries(&blob, old_ver)?.into_iter().collect();
+
+            // the new side is either a second snapshot or the live dir
+            let new_entries: Option<HashMap<String, u32>> = match args.revs.get(1) {
+                Some(r) => Some(
+                    bin.get_map_entries(&blob, resolve_map_rev(&bin, &blob, r)?)?
+                        .into_iter()
+                        .collect(),
+                ),
+                None => None,
+            };
+
+            let mut blobs: BTreeMap<String, String> = BTreeMap::new();
+            for b in old_entries.keys() {
+                if let Some(p) = bin.db.get_mapped_path(b)? {
+                    blobs.insert(b.to_string(), p);
+                }
+            }
+
+            match &new_entries {
+                Some(entries) => {
+                    for b in entries.keys() {
+                        if let Some(p) = bin.db.get_mapped_path(b)? {
+                            blobs.insert(b.to_string(), p);
+                        }
+                    }
+                }
+                None => {
+                    // include files created since the snapshot
+                    let dir = bin.mapper.map_to_real_path(&config_map_path)?;
+                    if exists(&dir) {
+                        for path in bin.get_config().walk_dir(&bin.mapper, &dir) {
+                            let map_path = bin.mapper.real_path_to_map(&path)?;
+                            blobs.insert(hash_path(&map_path), map_path);
+                        }
+                    }
+                }
+            }
+
+            for (b, map_path) in blobs {
+                let real_path = bin.mapper.map_to_real_path(&map_path)?;
+                let old = match old_entries.get(&b) {
+                    Some(v) => Side::Stored(b.clone(), *v),
+                    None => Side::Absent,
+                };
+                let new = match &new_entries {
+                    Some(entries) => match entries.get(&b) {
+                        Some(v) => Side::Stored(b.clone(), *v),
+                        None => Side::Absent,
+                    },
+                    None => Side::Live(real_path),
+                };
+                pairs.push((map_path, old, new));
+            }
+        }
+    }
+
+    pairs.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut stats = Vec::new();
+    for (path, old, new) in &pairs {
+        if is_dir_side(&bin, old)? || is_dir_side(&bin, new)? {
+            continue;
+        }
+
+        let old_label = match old {
+            Side::Stored(_, v) => format!("a/{}@{}", path, v),
+            _ => "/dev/null".to_string(),
+        };
+        let new_label = match new {
+            Side::Stored(_, v) => format!("b/{}@{}", path, v),
+            Side::Live(p) if exists(p) => format!("b/{}", path),
+            _ => "/dev/null".to_string(),
+        };
+
+        let counts = diff_sides(&bin, (&old_label, old), (&new_label, new), args.stat)?;
+        stats.push((path.to_string(), counts));
+    }
+
+    info!("Compared {} files", stats.len());
+    if args.stat {
+        print_stat(&stats);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{diff_sides, is_binary, Side, BINARY_SNIFF_LEN};
+    use crate::testing;
+
+    #[test]
+    fn binary_sniffs_head_only() {
+        assert!(!is_binary(b"plain text\n"));
+        assert!(is_binary(b"nul\0byte"));
+        assert!(is_binary(b"bad \xff utf-8"));
+
+        // past the head, neither NULs nor invalid UTF-8 are looked at
+        let mut data = vec![b'a'; BINARY_SNIFF_LEN];
+        data.extend_from_slice(b"\0\xff");
+        assert!(!is_binary(&data));
+
+        // a character split by the end of the head is still text
+        let mut data = vec![b'a'; BINARY_SNIFF_LEN - 1];
+        data.extend_from_slice("é".as_bytes());
+        assert!(!is_binary(&data));
+    }
+
+    #[test]
+    fn invalid_utf8_past_the_head_diffs_as_text() {
+        let dir = tempfile::tempdir().unwrap();
+        let bin = testing::new_bin(dir.path());
+
+        let mut old = vec![b'a'; BINARY_SNIFF_LEN];
+        old.extend_from_slice(b"\nsame \xff\nold\n");
+        let mut new = old.clone();
+        let len = new.len();
+        new[len - 4..].copy_from_slice(b"new\n");
+        let old_path = testing::path(dir.path(), "old");
+        let new_path = testing::path(dir.path(), "new");
+        fs::write(&old_path, old).unwrap();
+        fs::write(&new_path, new).unwrap();
+
+        let counts = diff_sides(
+            &bin,
+            (&old_path, &Side::Live(old_path.clone())),
+            (&new_path, &Side::Live(new_path.clone())),
+            true,
+        )
+        .unwrap();
+        assert_eq!(counts, Some((1, 1)));
+    }
+}
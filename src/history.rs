@@ -0,0 +1,185 @@
+use std::cmp::Reverse;
+
+use crate::Args;
+use chrono::{Local, TimeZone};
+use libmeld::{Bin, Error, Version};
+use serde_json::json;
+use structopt::StructOpt;
+
+// Define Module Arguments
+#[derive(Debug, StructOpt, Clone)]
+pub struct LogArgs {
+    #[structopt(short = "n", long = "limit", help = "only show the newest N versions")]
+    pub(crate) limit: Option<usize>,
+
+    #[structopt(long = "json", help = "output as json")]
+    pub(crate) json: bool,
+
+    #[structopt(help = "config file/folder to show history for")]
+    pub(crate) config_path: String,
+}
+
+/// Format seconds since the unix epoch as a local date and time; - if unknown
+fn format_time(secs: i64) -> String {
+    match Local.timestamp_opt(secs, 0).single() {
+        Some(t) if secs > 0 => t.format("%Y-%m-%d %H:%M").to_string(),
+        _ => "-".to_string(),
+    }
+}
+
+/// Format a version as a single human readable line
+fn version_line(v: &Version) -> String {
+    let hash = if v.data_hash.len() > 16 {
+        &v.data_hash[..16]
+    } else {
+        &v.data_hash
+    };
+
+    // versions pushed before hosts were recorded have no origin
+    let origin = if v.username.is_empty() && v.hostname.is_empty() {
+        "-".to_string()
+    } else {
+        format!("{}@{}", v.username, v.hostname)
+    };
+
+    let mut line = format!(
+        "{:>4}  {}  {}  {}",
+        v.ver,
+        hash,
+        format_time(v.created),
+        origin
+    );
+    if !v.tag.is_empty() {
+        line.push_str(&format!("  ({})", v.tag));
+    }
+    if !v.message.is_empty() {
+        line.push_str(&format!("  {}", v.message));
+    }
+    line
+}
+
+/// Versions of a config, newest first; at most limit if given
+fn history(bin: &Bin, blob: &String, limit: Option<usize>) -> Result<Vec<Version>, Error> {
+    let mut versions: Vec<Version> = bin.db.get_versions(blob)?.into_values().collect();
+    versions.sort_by_key(|v| Reverse(v.ver));
+    if let Some(limit) = limit {
+        versions.truncate(limit);
+    }
+    Ok(versions)
+}
+
+/// Main handler for showing the version history of a config
+pub fn handler(main_args: Args, args: LogArgs) -> Result<(), libmeld::Error> {
+    let bin = main_args.open_bin()?;
+
+    let config_map_path = bin.mapper.real_path_to_map(&args.config_path)?;
+
+    // Look up the config in the db
+    let blob = match bin.db.config_exists(&config_map_path)? {
+        Some(b) => b,
+        None => {
+            return Err(Error::FileNotFound {
+                msg: config_map_path,
+            })
+        }
+    };
+
+    let versions = history(&bin, &blob, args.limit)?;
+
+    if args.json {
+        let versions: Vec<serde_json::Value> = versions
+            .iter()
+            .map(|v| {
+                json!({
+                    "version": v.ver,
+                    "tag": v.tag,
+                    "hash": v.data_hash,
+                    "created": v.created,
+                    "hostname": v.hostname,
+                    "username": v.username,
+                    "message": v.message,
+                    "mode": v.meta.is_known().then(|| format!("{:04o}", v.meta.permissions())),
+                    "user": v.meta.user,
+                    "group": v.meta.group,
+                    "uid": v.meta.uid,
+                    "gid": v.meta.gid,
+                    "mtime": v.meta.mtime,
+                })
+            })
+            .collect();
+        println!(
+            "{}",
+            json!({
+                "map_path": config_map_path,
+                "blob": blob,
+                "versions": versions,
+            })
+        );
+    } else {
+        println!("{}", config_map_path);
+        for v in &versions {
+            println!("{}", version_line(v));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use libmeld::hash_path;
+
+    use super::{history, version_line};
+    use crate::testing;
+
+    #[test]
+    fn history_is_newest_first_and_limited() {
+        let dir = tempfile::tempdir().unwrap();
+        let bin = testing::new_bin(dir.path());
+        let file = testing::write(dir.path(), "a.conf", "");
+        for n in 1..=3 {
+            fs::write(&file, n.to_string()).unwrap();
+            testing::push(&bin, &file).unwrap();
+        }
+        let blob = hash_path("$T$/a.conf");
+
+        let vers = |limit| -> Vec<u32> {
+            history(&bin, &blob, limit)
+                .unwrap()
+                .iter()
+                .map(|v| v.ver)
+                .collect()
+        };
+        assert_eq!(vers(None), vec![3, 2, 1]);
+        assert_eq!(vers(Some(2)), vec![3, 2]);
+        assert_eq!(vers(Some(0)), Vec::<u32>::new());
+    }
+
+    #[test]
+    fn lines_show_what_is_known() {
+        let dir = tempfile::tempdir().unwrap();
+        let bin = testing::new_bin(dir.path());
+        let file = testing::write(dir.path(), "a.conf", "a");
+        testing::push(&bin, &file).unwrap();
+
+        let mut v = history(&bin, &hash_path("$T$/a.conf"), None)
+            .unwrap()
+            .remove(0);
+        let line = version_line(&v);
+        assert!(line.starts_with(&format!("   1  {}  ", &v.data_hash[..16])));
+        assert!(line.ends_with(&format!("{}@{}", v.username, v.hostname)));
+
+        // versions from before times and origins were recorded
+        v.created = 0;
+        v.hostname = String::new();
+        v.username = String::new();
+        v.tag = "release".to_string();
+        v.message = "first".to_string();
+        assert_eq!(
+            version_line(&v),
+            format!("   1  {}  -  -  (release)  first", &v.data_hash[..16])
+        );
+    }
+}
@@ -0,0 +1,128 @@
+use crate::Args;
+use libmeld::{Bin, Error};
+use structopt::StructOpt;
+
+// Define Module Arguments
+#[derive(Debug, StructOpt, Clone)]
+pub struct InfoArgs {
+    #[structopt(help = "binfo action", subcommand)]
+    pub(crate) action: Option<InfoAction>,
+}
+
+#[derive(Debug, StructOpt, Clone)]
+pub enum InfoAction {
+    /// Add or replace a binfo entry, ie description
+    Set {
+        #[structopt(help = "entry name")]
+        key: String,
+        #[structopt(help = "entry value")]
+        value: String,
+    },
+    /// Remove a binfo entry
+    Unset {
+        #[structopt(help = "entry name")]
+        key: String,
+    },
+}
+
+// Units sizes are shown in, each 1024 times the last
+const SIZE_UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+/// Format a byte count in the largest unit it has at least one of
+fn format_size(bytes: u64) -> String {
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < SIZE_UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        return format!("{} {}", bytes, SIZE_UNITS[0]);
+    }
+    format!("{:.1} {}", size, SIZE_UNITS[unit])
+}
+
+/// Lines describing the bin, as meld info prints them
+fn summary(bin: &Bin) -> Result<Vec<String>, Error> {
+    let info = bin.info()?;
+
+    let mut lines = vec![format!("name: {}", bin.get_config().name)];
+    for (key, value) in &info.binfo {
+        lines.push(format!("{}: {}", key, value));
+    }
+    lines.push(format!("configs: {}", info.configs));
+    lines.push(format!("versions: {}", info.versions));
+    lines.push(format!("maps: {}", info.maps));
+    lines.push(format!("objects: {} ({} packs)", info.objects, info.packs));
+    lines.push(format!(
+        "size: {} ({} stored)",
+        format_size(info.size),
+        format_size(info.stored_size)
+    ));
+    Ok(lines)
+}
+
+/// Main handler for showing and editing information about the bin
+pub fn handler(main_args: Args, args: InfoArgs) -> Result<(), libmeld::Error> {
+    let bin = main_args.open_bin()?;
+
+    match args.action {
+        Some(InfoAction::Set { key, value }) => bin.db.set_binfo(&key, &value)?,
+        Some(InfoAction::Unset { key }) => {
+            if !bin.db.remove_binfo(&key)? {
+                return Err(Error::BinfoNotFound { key });
+            }
+        }
+        None => {
+            for line in summary(&bin)? {
+                println!("{}", line);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_size, summary};
+    use crate::testing;
+
+    #[test]
+    fn sizes_use_the_largest_whole_unit() {
+        assert_eq!(format_size(0), "0 B");
+        assert_eq!(format_size(1023), "1023 B");
+        assert_eq!(format_size(1024), "1.0 KiB");
+        assert_eq!(format_size(1536), "1.5 KiB");
+        assert_eq!(format_size(5 * 1024 * 1024), "5.0 MiB");
+        assert_eq!(format_size(2048 * 1024u64.pow(4)), "2048.0 TiB");
+    }
+
+    #[test]
+    fn summary_lists_binfo_and_counts() {
+        let dir = tempfile::tempdir().unwrap();
+        let bin = testing::new_bin(dir.path());
+        testing::write(dir.path(), "d/x", "x");
+        testing::write(dir.path(), "d/y", "y");
+        testing::push(&bin, &testing::path(dir.path(), "home/d")).unwrap();
+        bin.db
+            .set_binfo(&"description".to_string(), &"dotfiles".to_string())
+            .unwrap();
+
+        let lines = summary(&bin).unwrap();
+        assert_eq!(lines[0], "name: bin");
+        assert!(lines.contains(&"description: dotfiles".to_string()));
+        // the counts follow the binfo entries, before the sizes
+        let counts = &lines[lines.len() - 5..lines.len() - 1];
+        assert_eq!(
+            counts,
+            [
+                "configs: 3",
+                "versions: 3",
+                "maps: 1",
+                "objects: 2 (0 packs)"
+            ]
+        );
+    }
+}
@@ -18,11 +18,24 @@ pub struct InitArgs {
         help = "force delete + init of an existing folder"
     )]
     pub(crate) force: bool,
+
+    #[structopt(
+        short = "c",
+        long = "comments",
+        help = "description of the bin, stored in its binfo"
+    )]
+    pub(crate) comments: Option<String>,
 }
 
 /// Main handler for Meld Bin Init
 pub fn handler(main_args: Args, args: InitArgs) -> Result<(), libmeld::Error> {
-    Bin::new(main_args.bin, args.force, args.make_parents)?;
+    Bin::new(
+        main_args.bin.clone(),
+        args.comments,
+        args.force,
+        args.make_parents,
+        main_args.lock_wait(),
+    )?;
 
-    return Ok(());
+    Ok(())
 }
@@ -1,15 +1,27 @@
+use log::debug;
+use log::error;
 use log::info;
 use log::warn;
 
+use crate::db::SCHEMA_VERSION;
+use crate::objects::OBJECTS_DIR;
+use crate::pack::PACKS_DIR;
 use crate::Bin;
+use crate::BinConfig;
 use crate::Database;
 use crate::Error;
+use crate::Journal;
+use crate::Lock;
+use crate::LockMode;
+use crate::LockWait;
+use crate::Mapper;
 
-use std::{fs::DirBuilder, path::PathBuf};
+use std::{cell::OnceCell, fs::DirBuilder, path::PathBuf};
 
 const MAP_DIR: &str = "maps";
 const BLOBS_DIR: &str = "blobs";
-const MELD_DB: &str = "meld.db";
+pub(crate) const MELD_DB: &str = "meld.db";
+pub(crate) const BIN_CONFIG: &str = "config.yml";
 
 impl Bin {
     // Getters
@@ -32,33 +44,278 @@ impl Bin {
     }
 
     fn is_valid(&self) -> bool {
-        return self.path.exists()
+        self.path.exists()
             && self.blobs.exists()
+            && self.objects.exists()
             && self.maps.exists()
             && self.db.path.exists()
-            && self.db.is_valid();
+            && self.db.is_valid()
+    }
+
+    /// Read the contents of a stored config version
+    /// the object is decompressed with the codec recorded for the version, and
+    /// decrypted with the bin's key if the version is encrypted
+    pub fn read_blob(&self, blob: &String, ver: u32) -> Result<Vec<u8>, Error> {
+        debug!("Reading {} V {}", blob, ver);
+        let version = match self.db.get_version(blob, ver)? {
+            Some(v) => v,
+            None => {
+                return Err(Error::FileNotFound {
+                    msg: format!("{} V {}", blob, ver),
+                })
+            }
+        };
+        let hash = self.read_pointer(blob, ver)?;
+        let data = self.read_object(&hash, version.codec)?;
+
+        if version.encrypted {
+            return self.decrypt(&data);
+        }
+        Ok(data)
+    }
+
+    /// Read the <BLOB>-<VER> entries of a map snapshot file
+    pub fn get_map_entries(&self, blob: &String, ver: u32) -> Result<Vec<(String, u32)>, Error> {
+        let map_file = format!("{}/{}-{}", self.get_maps_str()?, blob, ver);
+        info!("Parsing {}", map_file);
+
+        let contents = match std::fs::read_to_string(&map_file) {
+            Ok(c) => c,
+            Err(e) => return Err(Error::IOError { msg: e.to_string() }),
+        };
+
+        let mut entries = Vec::new();
+        for line in contents.lines() {
+            let entry = line
+                .split_once('-')
+                .and_then(|(b, v)| v.parse::<u32>().ok().map(|v| (b.to_string(), v)));
+            match entry {
+                Some(e) => entries.push(e),
+                None => {
+                    return Err(Error::IOError {
+                        msg: format!("{}: malformed entry '{}'", map_file, line),
+                    })
+                }
+            }
+        }
+
+        Ok(entries)
+    }
+
+    /// When a map snapshot was written, from its map file; now if it can't be read
+    pub(crate) fn map_time(&self, blob: &String, ver: u32) -> i64 {
+        crate::modified(&self.maps.join(format!("{}-{}", blob, ver)))
+    }
+
+    /// Fill in when each map snapshot of an older bin was pushed from its map file's time
+    pub(crate) fn migrate_map_created(&self) -> Result<(), Error> {
+        for (blob, ver) in self.db.get_uncreated("maps")? {
+            self.db
+                .set_map_created(&blob, ver, self.map_time(&blob, ver))?;
+        }
+        Ok(())
+    }
+
+    pub fn get_profile(&self) -> &Option<String> {
+        &self.profile
+    }
+
+    /// Bin-wide settings from config.yml
+    pub fn get_config(&self) -> &BinConfig {
+        &self.config
+    }
+
+    // Load the bin's settings from config.yml; a missing file uses the defaults
+    pub(crate) fn load_config(&mut self) -> Result<(), Error> {
+        let path = self.path.join(BIN_CONFIG);
+        if !path.exists() {
+            return Ok(());
+        }
+
+        let contents = match std::fs::read_to_string(&path) {
+            Ok(c) => c,
+            Err(e) => {
+                return Err(Error::IOError {
+                    msg: format!("{:?}: {}", path, e),
+                })
+            }
+        };
+
+        let config: serde_yaml::Value = match serde_yaml::from_str(&contents) {
+            Ok(c) => c,
+            Err(e) => {
+                return Err(Error::InvalidBinConfig {
+                    msg: format!("{:?}: {}", path, e),
+                })
+            }
+        };
+
+        self.config = match BinConfig::parse(&self.path, &config) {
+            Err(Error::InvalidBinConfig { msg }) => {
+                return Err(Error::InvalidBinConfig {
+                    msg: format!("{:?}: {}", path, msg),
+                })
+            }
+            c => c?,
+        };
+        Ok(())
+    }
+
+    /// Run f inside a db transaction with a journal for its file changes
+    /// If f fails, the db changes are rolled back and the journal is undone
+    pub fn transaction<T, F>(&self, f: F) -> Result<T, Error>
+    where
+        F: FnOnce(&mut Journal) -> Result<T, Error>,
+    {
+        let mut journal = Journal::new();
+
+        match self.db.transaction(|| f(&mut journal)) {
+            Ok(v) => {
+                journal.finish();
+                Ok(v)
+            }
+            Err(e) => {
+                error!("Transaction failed; rolling back");
+                journal.rollback();
+                Err(e)
+            }
+        }
+    }
+
+    // Load the bin's mappings into the mapper
+    // an explicit profile is always used; otherwise use this host's profile if defined
+    fn load_mappings(mut self, profile: Option<String>) -> Result<Self, Error> {
+        self.profile = match profile {
+            Some(p) => {
+                if !self.db.get_profiles()?.contains(&p) {
+                    warn!("Profile {} has no mappings; using bin-wide mappings", p);
+                }
+                Some(p)
+            }
+            None => {
+                let host = crate::hostname();
+                if self.db.get_profiles()?.contains(&host) {
+                    info!("Using host profile {}", host);
+                    Some(host)
+                } else {
+                    None
+                }
+            }
+        };
+
+        let mappings = self.db.get_mappings(&None)?;
+        self.mapper = match &self.profile {
+            Some(_) => Mapper::with_profile(mappings, self.db.get_mappings(&self.profile)?),
+            None => Mapper::new(mappings),
+        };
+
+        Ok(self)
+    }
+
+    // Lay out the paths of a bin without checking anything exists
+    pub(crate) fn at(path: &String) -> Self {
+        Bin {
+            path: PathBuf::from(path),
+            maps: PathBuf::from(format!("{}/{}", path, MAP_DIR)),
+            blobs: PathBuf::from(format!("{}/{}", path, BLOBS_DIR)),
+            objects: PathBuf::from(format!("{}/{}", path, OBJECTS_DIR)),
+            packs: PathBuf::from(format!("{}/{}", path, PACKS_DIR)),
+            pack_index: OnceCell::new(),
+            db: Database::new(PathBuf::from(format!("{}/{}", path, MELD_DB))),
+            mapper: Mapper::new(Vec::new()),
+            profile: None,
+            config: BinConfig::new(&PathBuf::from(path)),
+            key: None,
+            lock: None,
+        }
     }
 
     /// Parse a Meld Bin from a Path
-    pub fn from(path: String) -> Result<Self, Error> {
+    /// Mappings are resolved with the given profile, or the host's profile if None
+    /// The bin stays locked in the given mode until dropped
+    pub fn from(
+        path: String,
+        profile: Option<String>,
+        mode: LockMode,
+        wait: LockWait,
+    ) -> Result<Self, Error> {
         info!("Opening bin at {}", path);
-        let bin = Bin {
-            path: PathBuf::from(&path),
-            maps: PathBuf::from(format!("{}/{}", &path, MAP_DIR)),
-            blobs: PathBuf::from(format!("{}/{}", &path, BLOBS_DIR)),
-            db: Database {
-                path: PathBuf::from(format!("{}/{}", &path, MELD_DB)),
-            },
-        };
+        let mut bin = Bin::at(&path);
+
+        if !bin.path.exists() || !bin.db.path.exists() {
+            return Err(Error::InitFailed {
+                msg: "Selected bin is invalid".to_string(),
+            });
+        }
+
+        // bring older bins up to date before checking them
+        // migrating writes to the db, so the bin is held alone while it runs
+        let migrating = bin.db.get_schema_version()? < SCHEMA_VERSION;
+        let first_mode = if migrating { LockMode::Exclusive } else { mode };
+        bin.lock = Some(Lock::acquire(&bin.path, first_mode, wait)?);
+
+        // migrations seed and apply the bin config's mappings
+        bin.load_config()?;
+        bin.migrate()?;
+        // migrating may have rewritten packs
+        bin.pack_index = OnceCell::new();
+
+        if first_mode != mode {
+            bin.lock = None;
+            bin.lock = Some(Lock::acquire(&bin.path, mode, wait)?);
+        }
 
         // sanity check creation
-        return if bin.is_valid() {
-            Ok(bin)
-        } else {
-            Err(Error::InitFailed {
+        if !bin.is_valid() {
+            return Err(Error::InitFailed {
                 msg: "Selected bin is invalid".to_string(),
-            })
-        };
+            });
+        }
+
+        bin.load_mappings(profile)
+    }
+
+    /// Open a Meld Bin as it is on disk, under a shared lock
+    /// The schema is neither migrated nor checked and no mappings are loaded,
+    /// so a broken bin can still be inspected
+    pub fn inspect(path: String, wait: LockWait) -> Result<Self, Error> {
+        info!("Inspecting bin at {}", path);
+        let mut bin = Bin::at(&path);
+
+        if !bin.path.exists() || !bin.db.path.exists() {
+            return Err(Error::InitFailed {
+                msg: "Selected bin is invalid".to_string(),
+            });
+        }
+
+        bin.lock = Some(Lock::acquire(&bin.path, LockMode::Shared, wait)?);
+        Ok(bin)
+    }
+
+    // Upgrade the bin to SCHEMA_VERSION, one transaction per step
+    // steps that change the bin layout convert its files in the same transaction
+    fn migrate(&self) -> Result<(), Error> {
+        let found = self.db.check_schema_version()?;
+
+        for version in found..SCHEMA_VERSION {
+            self.transaction(|journal| {
+                self.db.migrate_step(version)?;
+                match version + 1 {
+                    // bins from before versioning may not have had a mappings table
+                    1 => self.db.init_mappings(&self.config.mappings),
+                    2 => self.migrate_objects(journal),
+                    7 => self.migrate_created(),
+                    9 => self.migrate_map_created(),
+                    11 => self.migrate_map_paths(journal),
+                    _ => Ok(()),
+                }
+            })?;
+        }
+
+        if found < SCHEMA_VERSION {
+            info!("Upgraded bin schema {} -> {}", found, SCHEMA_VERSION);
+        }
+        Ok(())
     }
 
     // Helper function for repeated dir creation
@@ -68,27 +325,28 @@ impl Bin {
             Ok(_) => Ok(()),
             Err(e) => {
                 if e.kind() == std::io::ErrorKind::NotFound {
-                    return Err(Error::ParentsDontExist);
+                    Err(Error::ParentsDontExist)
                 } else {
-                    return Err(Error::InitFailed {
+                    Err(Error::InitFailed {
                         msg: "Failed to Create Valid Bin".to_string(),
-                    });
+                    })
                 }
             }
         }
     }
 
     /// Create and init a new Meld Bin
-    pub fn new(path: String, force: bool, parents: bool) -> Result<Self, Error> {
+    /// comments are stored as the bin's description in the binfo table
+    /// The new bin is held with an exclusive lock until dropped
+    pub fn new(
+        path: String,
+        comments: Option<String>,
+        force: bool,
+        parents: bool,
+        wait: LockWait,
+    ) -> Result<Self, Error> {
         info!("Creating bin at {}", path);
-        let bin = Bin {
-            path: PathBuf::from(&path),
-            maps: PathBuf::from(format!("{}/{}", &path, MAP_DIR)),
-            blobs: PathBuf::from(format!("{}/{}", &path, BLOBS_DIR)),
-            db: Database {
-                path: PathBuf::from(format!("{}/{}", &path, MELD_DB)),
-            },
-        };
+        let mut bin = Bin::at(&path);
 
         // Create dirbuilder and set options
         let mut dirb = DirBuilder::new();
@@ -98,6 +356,8 @@ impl Bin {
             if !force {
                 return Err(Error::BinAlreadyExists { bin: path });
             } else {
+                // never remove a bin out from under another process
+                let _lock = Lock::acquire(&bin.path, LockMode::Exclusive, wait)?;
                 warn!("Removing {}", path);
                 match std::fs::remove_dir_all(path) {
                     Ok(_) => {}
@@ -108,19 +368,137 @@ impl Bin {
 
         // create all needed folders
         Bin::create_dir(&dirb, &bin.path)?;
+        bin.lock = Some(Lock::acquire(&bin.path, LockMode::Exclusive, wait)?);
         Bin::create_dir(&dirb, &bin.maps)?;
         Bin::create_dir(&dirb, &bin.blobs)?;
+        Bin::create_dir(&dirb, &bin.objects)?;
 
         // create and initialize SQLite table
         bin.db.create_db_schema()?;
+        bin.db.init_mappings(&bin.config.mappings)?;
+        bin.init_binfo(&comments)?;
+
+        // write the default settings so they can be edited in place
+        let config_path = bin.path.join(BIN_CONFIG);
+        if let Err(e) = std::fs::write(&config_path, bin.config.to_yaml()?) {
+            return Err(Error::InitFailed {
+                msg: format!("{:?}: {}", config_path, e),
+            });
+        }
 
         // sanity check creation
-        return if bin.is_valid() {
-            Ok(bin)
-        } else {
-            Err(Error::InitFailed {
+        if !bin.is_valid() {
+            return Err(Error::InitFailed {
                 msg: "Failed to Create Valid Bin".to_string(),
-            })
-        };
+            });
+        }
+
+        bin.load_mappings(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use rusqlite::{params, Connection};
+
+    use crate::{hash_bytes, hash_path, Bin, LockMode, LockWait};
+
+    // Write a bin as the first release did: unversioned tables, configs stored by real
+    // path, blob files holding contents, and maps named by the path given to push
+    fn write_baseline_bin(bin: &str, home: &str) {
+        for dir in ["blobs", "maps"] {
+            fs::create_dir_all(format!("{}/{}", bin, dir)).unwrap();
+        }
+        fs::write(
+            format!("{}/config.yml", bin),
+            format!("mappings:\n  $HOME$: {}\n", home),
+        )
+        .unwrap();
+
+        let con = Connection::open(format!("{}/meld.db", bin)).unwrap();
+        con.execute_batch(
+            "CREATE TABLE configs (id TEXT, subset TEXT, family TEXT, map_path TEXT);
+            CREATE TABLE versions (id TEXT, ver INTEGER, tag TEXT, owner TEXT);
+            CREATE TABLE maps (id TEXT, ver INTEGER, nhash TEXT, tag TEXT);",
+        )
+        .unwrap();
+
+        let mut entries = String::new();
+        for (rel, contents) in [("a.conf", Some("a")), ("d", None), ("d/x", Some("x"))] {
+            let path = format!("{}/{}", home, rel);
+            let blob = hash_path(&path);
+            con.execute(
+                "INSERT INTO configs VALUES (?1, '', '', ?2)",
+                params![blob, path],
+            )
+            .unwrap();
+
+            let id = match contents {
+                Some(c) => {
+                    fs::create_dir_all(format!("{}/blobs/{}", bin, blob)).unwrap();
+                    fs::write(format!("{}/blobs/{}/1", bin, blob), c).unwrap();
+                    hash_bytes(c.as_bytes())
+                }
+                None => "DIR".to_string(),
+            };
+            con.execute(
+                "INSERT INTO versions VALUES (?1, 1, '', ?2)",
+                params![id, blob],
+            )
+            .unwrap();
+
+            if rel.starts_with('d') {
+                entries.push_str(&format!("{}-1\n", blob));
+            }
+        }
+
+        // pushed as `meld push d` from the home dir
+        let map = hash_path("d");
+        fs::write(format!("{}/maps/{}-1", bin, map), &entries).unwrap();
+        con.execute(
+            "INSERT INTO maps VALUES (?1, 1, ?2, '')",
+            params![map, hash_bytes(entries.as_bytes())],
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn baseline_bin_moves_to_mapped_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let bin_path = dir.path().join("bin").to_string_lossy().to_string();
+        let home = dir.path().join("home");
+        fs::create_dir_all(home.join("d")).unwrap();
+        let home = home.canonicalize().unwrap().to_string_lossy().to_string();
+        write_baseline_bin(&bin_path, &home);
+
+        let bin = Bin::from(bin_path, None, LockMode::Exclusive, LockWait::Never).unwrap();
+
+        let mut paths: Vec<String> = bin
+            .db
+            .get_configs()
+            .unwrap()
+            .iter()
+            .map(|c| c.get_map_path().to_string())
+            .collect();
+        paths.sort();
+        assert_eq!(paths, vec!["$HOME$/a.conf", "$HOME$/d", "$HOME$/d/x"]);
+
+        let a = hash_path("$HOME$/a.conf");
+        let d = hash_path("$HOME$/d");
+        let x = hash_path("$HOME$/d/x");
+        assert_eq!(bin.read_blob(&a, 1).unwrap(), b"a");
+        assert_eq!(bin.read_blob(&x, 1).unwrap(), b"x");
+
+        let maps = bin.db.get_all_maps().unwrap();
+        assert_eq!(maps.len(), 1);
+        assert_eq!(maps[0].blob, d);
+        assert_eq!(
+            bin.get_map_entries(&d, 1).unwrap(),
+            vec![(d.clone(), 1), (x, 1)]
+        );
+
+        assert!(bin.validate().unwrap().is_empty());
     }
 }
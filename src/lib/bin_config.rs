@@ -0,0 +1,347 @@
+use std::{collections::BTreeMap, fmt, path::Path};
+
+use glob::Pattern;
+use log::{debug, warn};
+use walkdir::WalkDir;
+
+use crate::delta::DEFAULT_SNAPSHOT_EVERY;
+use crate::mapper::DEFAULT_MAPPINGS;
+use crate::Bin;
+use crate::BinConfig;
+use crate::Codec;
+use crate::Error;
+use crate::HashAlgorithm;
+use crate::Mapper;
+use crate::Mapping;
+use crate::Retention;
+
+// Version of the config.yml format this client reads and writes
+pub(crate) const CONFIG_FORMAT: u32 = 1;
+
+// Every key of the bin config; others are ignored with a warning
+const KEYS: [&str; 11] = [
+    "name",
+    "description",
+    "format",
+    "mappings",
+    "hash",
+    "compression",
+    "delta",
+    "snapshot_every",
+    "encrypt_subsets",
+    "retention",
+    "ignore",
+];
+
+impl HashAlgorithm {
+    pub(crate) const ALL: [HashAlgorithm; 1] = [HashAlgorithm::Sha512];
+
+    /// Name used in config.yml
+    pub fn name(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Sha512 => "sha512",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        HashAlgorithm::ALL.into_iter().find(|h| h.name() == name)
+    }
+}
+
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+// A config error for a single key
+fn invalid(msg: &str) -> Error {
+    Error::InvalidBinConfig {
+        msg: msg.to_string(),
+    }
+}
+
+// Read an optional string key
+fn read_string(config: &serde_yaml::Value, key: &str) -> Result<Option<String>, Error> {
+    match config.get(key) {
+        None | Some(serde_yaml::Value::Null) => Ok(None),
+        Some(serde_yaml::Value::String(s)) => Ok(Some(s.clone())),
+        Some(_) => Err(invalid(&format!("{} must be a string", key))),
+    }
+}
+
+impl BinConfig {
+    /// Default settings of a bin at path; the bin is named after its dir
+    pub fn new(path: &Path) -> Self {
+        let name = match path.file_name() {
+            Some(n) => n.to_string_lossy().to_string(),
+            None => String::new(),
+        };
+
+        BinConfig {
+            name,
+            description: String::new(),
+            format: CONFIG_FORMAT,
+            mappings: DEFAULT_MAPPINGS
+                .iter()
+                .map(|(v, p)| (v.to_string(), p.to_string()))
+                .collect(),
+            hash: HashAlgorithm::Sha512,
+            compression: Codec::None,
+            delta: false,
+            snapshot_every: DEFAULT_SNAPSHOT_EVERY,
+            encrypt_subsets: Vec::new(),
+            retention: Retention::default(),
+            ignore: Vec::new(),
+        }
+    }
+
+    /// Parse and validate a bin config over the defaults of a bin at path
+    pub(crate) fn parse(path: &Path, config: &serde_yaml::Value) -> Result<Self, Error> {
+        let mut bin_config = BinConfig::new(path);
+        match config {
+            serde_yaml::Value::Null => return Ok(bin_config),
+            serde_yaml::Value::Mapping(m) => {
+                for (key, _) in m {
+                    if !key.as_str().is_some_and(|k| KEYS.contains(&k)) {
+                        warn!("Ignoring unknown bin config key {:?}", key);
+                    }
+                }
+            }
+            _ => return Err(invalid("config must be a mapping of settings")),
+        }
+
+        // a newer format may change what the keys below mean
+        if let Some(v) = config.get("format") {
+            bin_config.format = match v.as_u64() {
+                Some(f) if f >= 1 && f <= CONFIG_FORMAT as u64 => f as u32,
+                Some(f) if f > CONFIG_FORMAT as u64 => {
+                    return Err(invalid(&format!(
+                        "format {} is newer than this client supports ({})",
+                        f, CONFIG_FORMAT
+                    )))
+                }
+                _ => return Err(invalid("format must be a positive number")),
+            };
+        }
+
+        if let Some(name) = read_string(config, "name")? {
+            bin_config.name = name;
+        }
+        if let Some(description) = read_string(config, "description")? {
+            bin_config.description = description;
+        }
+
+        match config.get("mappings") {
+            None | Some(serde_yaml::Value::Null) => {}
+            Some(serde_yaml::Value::Mapping(m)) => {
+                bin_config.mappings = BTreeMap::new();
+                for (var, prefix) in m {
+                    let (var, prefix) = match (var.as_str(), prefix.as_str()) {
+                        (Some(v), Some(p)) => (v.to_string(), p.to_string()),
+                        _ => return Err(invalid("mappings must map variables to prefixes")),
+                    };
+                    // checked like mappings added with meld mapping add
+                    if let Err(e) = Mapping::new(var.clone(), prefix.clone(), None) {
+                        return Err(invalid(&format!("mappings: {}", e)));
+                    }
+                    bin_config.mappings.insert(var, prefix);
+                }
+            }
+            Some(_) => return Err(invalid("mappings must map variables to prefixes")),
+        }
+
+        if let Some(hash) = read_string(config, "hash")? {
+            bin_config.hash = match HashAlgorithm::from_name(&hash) {
+                Some(h) => h,
+                None => return Err(invalid("hash must be sha512")),
+            };
+        }
+
+        bin_config.compression = Bin::read_codec(config)?;
+        (bin_config.delta, bin_config.snapshot_every) = Bin::read_delta(config)?;
+        bin_config.encrypt_subsets = Bin::read_encrypt_subsets(config)?;
+        bin_config.retention = Retention::from_config(config)?;
+
+        match config.get("ignore") {
+            None | Some(serde_yaml::Value::Null) => {}
+            Some(serde_yaml::Value::Sequence(patterns)) => {
+                for p in patterns {
+                    match p.as_str().map(Pattern::new) {
+                        Some(Ok(p)) => bin_config.ignore.push(p),
+                        Some(Err(e)) => {
+                            return Err(invalid(&format!("ignore pattern {:?}: {}", p, e)))
+                        }
+                        None => return Err(invalid("ignore must be a list of glob patterns")),
+                    }
+                }
+            }
+            Some(_) => return Err(invalid("ignore must be a list of glob patterns")),
+        }
+
+        debug!("Loaded bin config {:?}", bin_config);
+        Ok(bin_config)
+    }
+
+    /// The config as written to config.yml
+    pub(crate) fn to_yaml(&self) -> Result<String, Error> {
+        let mut config = serde_yaml::Mapping::new();
+        config.insert("format".into(), self.format.into());
+        config.insert("name".into(), self.name.clone().into());
+        config.insert("description".into(), self.description.clone().into());
+
+        let mut mappings = serde_yaml::Mapping::new();
+        for (var, prefix) in &self.mappings {
+            mappings.insert(var.clone().into(), prefix.clone().into());
+        }
+        config.insert("mappings".into(), mappings.into());
+
+        config.insert("hash".into(), self.hash.name().into());
+        config.insert("compression".into(), self.compression.name().into());
+        config.insert("delta".into(), self.delta.into());
+        config.insert("snapshot_every".into(), (self.snapshot_every as u64).into());
+        config.insert(
+            "encrypt_subsets".into(),
+            self.encrypt_subsets.clone().into(),
+        );
+        config.insert("retention".into(), self.retention.to_value());
+        config.insert(
+            "ignore".into(),
+            self.ignore
+                .iter()
+                .map(|p| p.as_str().to_string())
+                .collect::<Vec<String>>()
+                .into(),
+        );
+
+        match serde_yaml::to_string(&config) {
+            Ok(c) => Ok(c),
+            Err(e) => Err(Error::IOError { msg: e.to_string() }),
+        }
+    }
+
+    /// Real paths of a dir and everything in it, minus ignored files and dirs
+    pub fn walk_dir(&self, mapper: &Mapper, path: &str) -> Vec<String> {
+        WalkDir::new(path)
+            .into_iter()
+            .filter_entry(|e| {
+                // the dir itself was asked for by name
+                e.depth() == 0
+                    || !mapper
+                        .real_path_to_map(&e.path().to_string_lossy().to_string())
+                        .is_ok_and(|m| self.is_ignored(&m))
+            })
+            .flatten()
+            .map(|e| e.path().to_string_lossy().to_string())
+            .collect()
+    }
+
+    /// Check a path is skipped when pushing or scanning dirs
+    /// patterns with a / match the whole Map Path; others match any file or dir name
+    pub fn is_ignored(&self, map_path: &str) -> bool {
+        let name = map_path.rsplit('/').next().unwrap_or(map_path);
+        self.ignore.iter().any(|p| {
+            if p.as_str().contains('/') {
+                p.matches(map_path)
+            } else {
+                p.matches(name)
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::{BinConfig, Codec, Retention};
+
+    fn parse(yaml: &str) -> Result<BinConfig, crate::Error> {
+        BinConfig::parse(
+            Path::new("/bins/dots"),
+            &serde_yaml::from_str(yaml).unwrap(),
+        )
+    }
+
+    #[test]
+    fn settings_are_parsed_over_defaults() {
+        let defaults = BinConfig::new(Path::new("/bins/dots"));
+        assert_eq!(defaults.name, "dots");
+        assert_eq!(parse("~").unwrap(), defaults);
+
+        let config = parse(
+            "format: 1
+name: work
+description: work machines
+mappings:
+  $CFG$: /etc
+compression: gzip
+delta: true
+snapshot_every: 5
+encrypt_subsets: [secrets]
+retention:
+  keep_last: 3
+ignore: ['*.swp', '$HOME$/.cache']",
+        )
+        .unwrap();
+        assert_eq!(config.name, "work");
+        assert_eq!(config.description, "work machines");
+        assert_eq!(
+            config.mappings.into_iter().collect::<Vec<_>>(),
+            vec![("$CFG$".to_string(), "/etc".to_string())]
+        );
+        assert_eq!(config.compression, Codec::Gzip);
+        assert!(config.delta);
+        assert_eq!(config.snapshot_every, 5);
+        assert_eq!(config.encrypt_subsets, vec!["secrets"]);
+        assert_eq!(
+            config.retention,
+            Retention {
+                keep_last: Some(3),
+                ..Retention::default()
+            }
+        );
+        assert_eq!(config.ignore.len(), 2);
+    }
+
+    #[test]
+    fn invalid_settings_are_refused() {
+        for bad in [
+            "[a, b]",
+            "format: 2",
+            "format: 0",
+            "name: [a]",
+            "mappings: [a]",
+            "mappings:\n  HOME: /home",
+            "hash: md5",
+            "delta: yes please",
+            "snapshot_every: 0",
+            "retention: {keep_forever: true}",
+            "ignore: '*.swp'",
+            "ignore: ['[']",
+        ] {
+            assert!(parse(bad).is_err(), "{}", bad);
+        }
+        // unknown keys are only warned about
+        assert!(parse("colour: blue").is_ok());
+    }
+
+    #[test]
+    fn written_config_parses_back() {
+        let mut config = parse("ignore: ['*.swp']\nretention: {max_age: 2w}").unwrap();
+        config.compression = Codec::Zstd;
+        config.encrypt_subsets = vec!["secrets".to_string()];
+
+        let yaml = config.to_yaml().unwrap();
+        assert_eq!(parse(&yaml).unwrap(), config);
+    }
+
+    #[test]
+    fn ignore_patterns_match_names_or_paths() {
+        let config = parse("ignore: ['*.swp', '$HOME$/.cache']").unwrap();
+        assert!(config.is_ignored("$HOME$/.vimrc.swp"));
+        assert!(config.is_ignored("$HOME$/.cache"));
+        assert!(!config.is_ignored("$HOME$/.config/.cache"));
+        assert!(!config.is_ignored("$HOME$/.vimrc"));
+    }
+}
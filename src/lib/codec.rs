@@ -0,0 +1,191 @@
+use std::{
+    fmt,
+    io::{Read, Write},
+};
+
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use log::debug;
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+
+use crate::Bin;
+use crate::Codec;
+use crate::Error;
+
+// zstd's default; a good tradeoff for small text configs
+const ZSTD_LEVEL: i32 = 3;
+
+impl Codec {
+    pub(crate) const ALL: [Codec; 4] = [Codec::None, Codec::Zstd, Codec::Gzip, Codec::Delta];
+
+    /// Name used in config.yml and the versions table
+    pub fn name(&self) -> &'static str {
+        match self {
+            Codec::None => "none",
+            Codec::Zstd => "zstd",
+            Codec::Gzip => "gzip",
+            Codec::Delta => "delta",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Codec::ALL.into_iter().find(|c| c.name() == name)
+    }
+
+    /// Suffix of objects stored with this codec
+    pub(crate) fn extension(&self) -> &'static str {
+        match self {
+            Codec::None => "",
+            Codec::Zstd => ".zst",
+            Codec::Gzip => ".gz",
+            Codec::Delta => ".delta",
+        }
+    }
+
+    /// Split an object file name into its hash and codec
+    pub(crate) fn from_object_name(name: &str) -> Option<(String, Self)> {
+        let (hash, ext) = match name.find('.') {
+            Some(i) => name.split_at(i),
+            None => (name, ""),
+        };
+        let codec = Codec::ALL.into_iter().find(|c| c.extension() == ext)?;
+        Some((hash.to_string(), codec))
+    }
+
+    pub fn compress(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let res = match self {
+            Codec::None => Ok(data.to_vec()),
+            Codec::Zstd => zstd::encode_all(data, ZSTD_LEVEL),
+            Codec::Gzip => {
+                let mut enc = GzEncoder::new(Vec::new(), Compression::default());
+                enc.write_all(data).and_then(|_| enc.finish())
+            }
+            Codec::Delta => {
+                return Err(Error::IOError {
+                    msg: "deltas are stored with Bin::store_delta".to_string(),
+                })
+            }
+        };
+
+        match res {
+            Ok(d) => Ok(d),
+            Err(e) => Err(Error::IOError {
+                msg: format!("{} compression failed: {}", self, e),
+            }),
+        }
+    }
+
+    pub fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let res = match self {
+            Codec::None => Ok(data.to_vec()),
+            Codec::Zstd => zstd::decode_all(data),
+            Codec::Gzip => {
+                let mut out = Vec::new();
+                GzDecoder::new(data).read_to_end(&mut out).map(|_| out)
+            }
+            Codec::Delta => {
+                return Err(Error::IOError {
+                    msg: "deltas are rebuilt with Bin::rebuild_delta".to_string(),
+                })
+            }
+        };
+
+        match res {
+            Ok(d) => Ok(d),
+            Err(e) => Err(Error::IOError {
+                msg: format!("{} decompression failed: {}", self, e),
+            }),
+        }
+    }
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl ToSql for Codec {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.name()))
+    }
+}
+
+impl FromSql for Codec {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        let name = value.as_str()?;
+        match Codec::from_name(name) {
+            Some(c) => Ok(c),
+            None => Err(FromSqlError::Other(
+                format!("unknown codec '{}'", name).into(),
+            )),
+        }
+    }
+}
+
+impl Bin {
+    /// Read the codec new versions are stored with from the bin's config
+    /// A bin without a compression key stores versions uncompressed
+    pub(crate) fn read_codec(config: &serde_yaml::Value) -> Result<Codec, Error> {
+        let codec = match config.get("compression") {
+            None | Some(serde_yaml::Value::Null) => Some(Codec::None),
+            Some(serde_yaml::Value::String(s)) => match Codec::from_name(s) {
+                Some(Codec::Delta) => None,
+                c => c,
+            },
+            Some(_) => None,
+        };
+
+        match codec {
+            Some(c) => {
+                debug!("Storing new versions with {}", c);
+                Ok(c)
+            }
+            None => Err(Error::InvalidBinConfig {
+                msg: "compression must be one of none, zstd or gzip".to_string(),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Bin, Codec};
+
+    #[test]
+    fn codecs_round_trip() {
+        let data = "a line of a config\n".repeat(100).into_bytes();
+        for codec in [Codec::None, Codec::Zstd, Codec::Gzip] {
+            let stored = codec.compress(&data).unwrap();
+            assert_eq!(codec.decompress(&stored).unwrap(), data, "{}", codec);
+            if codec != Codec::None {
+                assert!(stored.len() < data.len(), "{}", codec);
+            }
+        }
+        assert!(Codec::Zstd.decompress(b"not zstd").is_err());
+        assert!(Codec::Delta.compress(&data).is_err());
+    }
+
+    #[test]
+    fn object_names_carry_the_codec() {
+        for codec in Codec::ALL {
+            let name = format!("abc{}", codec.extension());
+            assert_eq!(
+                Codec::from_object_name(&name),
+                Some(("abc".to_string(), codec))
+            );
+            assert_eq!(Codec::from_name(codec.name()), Some(codec));
+        }
+        assert_eq!(Codec::from_object_name("abc.bz2"), None);
+    }
+
+    #[test]
+    fn compression_is_read_from_config() {
+        let read = |yaml: &str| Bin::read_codec(&serde_yaml::from_str(yaml).unwrap());
+        assert_eq!(read("name: bin").unwrap(), Codec::None);
+        assert_eq!(read("compression: zstd").unwrap(), Codec::Zstd);
+        assert_eq!(read("compression: gzip").unwrap(), Codec::Gzip);
+        for bad in ["compression: delta", "compression: lz4", "compression: 3"] {
+            assert!(read(bad).is_err(), "{}", bad);
+        }
+    }
+}
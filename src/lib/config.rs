@@ -5,6 +5,7 @@ use crate::hash_contents;
 use crate::hash_path;
 use crate::Config;
 use crate::Error;
+use crate::FileMeta;
 
 impl Config {
     // Getters
@@ -16,6 +17,10 @@ impl Config {
         &self.real_path
     }
 
+    pub fn get_map_path(&self) -> &String {
+        &self.map_path
+    }
+
     pub fn get_tag(&self) -> &String {
         &self.tag
     }
@@ -24,6 +29,10 @@ impl Config {
         &self.hash
     }
 
+    pub fn get_meta(&self) -> &FileMeta {
+        &self.meta
+    }
+
     /// Create a Config from a path and arguments
     pub fn from(
         real_path: String,
@@ -41,11 +50,14 @@ impl Config {
             family,
             map_path,
             hash: hash_contents(&real_path)?,
+            meta: FileMeta::read(&real_path)?,
             real_path,
             tag,
+            encrypt: false,
+            message: String::new(),
             versions: HashMap::new(),
         };
 
-        return Ok(config);
+        Ok(config)
     }
 }
@@ -0,0 +1,289 @@
+use std::{fs, path::PathBuf, str::FromStr};
+
+use age::{secrecy::ExposeSecret, x25519::Identity};
+use hmac::{Hmac, Mac};
+use log::{debug, info};
+use sha2::Sha512;
+
+use crate::hash_bytes;
+use crate::Bin;
+use crate::Codec;
+use crate::Config;
+use crate::Error;
+use crate::Journal;
+use crate::Version;
+
+// Every age file starts with this line
+const AGE_HEADER: &[u8] = b"age-encryption.org/v1\n";
+
+/// Check stored contents are an age encrypted file
+pub(crate) fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(AGE_HEADER)
+}
+
+impl Bin {
+    /// Read the subsets whose configs are always encrypted from the bin's config
+    pub(crate) fn read_encrypt_subsets(config: &serde_yaml::Value) -> Result<Vec<String>, Error> {
+        let subsets = match config.get("encrypt_subsets") {
+            None | Some(serde_yaml::Value::Null) => return Ok(Vec::new()),
+            Some(serde_yaml::Value::Sequence(s)) => s,
+            Some(_) => {
+                return Err(Error::InvalidBinConfig {
+                    msg: "encrypt_subsets must be a list of subsets".to_string(),
+                })
+            }
+        };
+
+        subsets
+            .iter()
+            .map(|s| match s.as_str() {
+                Some(s) => Ok(s.to_string()),
+                None => Err(Error::InvalidBinConfig {
+                    msg: "encrypt_subsets must be a list of subsets".to_string(),
+                }),
+            })
+            .collect()
+    }
+
+    /// Check new versions of a config must be encrypted
+    /// configs stay encrypted once any version is, and subsets can require it
+    pub fn must_encrypt(&self, config: &Config, latest: &Option<Version>) -> Result<bool, Error> {
+        // dirs have no contents to encrypt
+        if config.get_hash() == "DIR" {
+            return Ok(false);
+        }
+        if config.encrypt || latest.as_ref().is_some_and(|v| v.encrypted) {
+            return Ok(true);
+        }
+
+        let subset = if config.subset.is_empty() {
+            self.db.get_subset(config.get_blob())?.unwrap_or_default()
+        } else {
+            config.subset.clone()
+        };
+        Ok(!subset.is_empty() && self.config.encrypt_subsets.contains(&subset))
+    }
+
+    /// Use the age identity file at path to encrypt and decrypt versions
+    /// the key is only read when an encrypted version is stored or read
+    pub fn set_key(&mut self, path: PathBuf) {
+        self.key = Some(path);
+    }
+
+    // Load the first X25519 identity from the key file
+    fn load_identity(&self) -> Result<Identity, Error> {
+        let path = match &self.key {
+            Some(p) => p,
+            None => {
+                return Err(Error::KeyError {
+                    msg: "no key file set".to_string(),
+                })
+            }
+        };
+
+        let contents = match fs::read_to_string(path) {
+            Ok(c) => c,
+            Err(e) => {
+                return Err(Error::KeyError {
+                    msg: format!(
+                        "{:?}: {}; create one with `age-keygen -o {}`",
+                        path,
+                        e,
+                        path.to_string_lossy()
+                    ),
+                })
+            }
+        };
+
+        debug!("Loading key from {:?}", path);
+        let key = contents
+            .lines()
+            .map(|l| l.trim())
+            .find(|l| !l.is_empty() && !l.starts_with('#'));
+
+        return match key.map(Identity::from_str) {
+            Some(Ok(i)) => Ok(i),
+            _ => Err(Error::KeyError {
+                msg: format!("{:?} does not hold an age X25519 identity", path),
+            }),
+        };
+    }
+
+    /// Encrypt contents to the bin's key
+    pub fn encrypt(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let identity = self.load_identity()?;
+        match age::encrypt(&identity.to_public(), data) {
+            Ok(d) => Ok(d),
+            Err(e) => Err(Error::KeyError { msg: e.to_string() }),
+        }
+    }
+
+    /// Decrypt contents encrypted to the bin's key
+    pub fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let identity = self.load_identity()?;
+        match age::decrypt(&identity, data) {
+            Ok(d) => Ok(d),
+            Err(e) => Err(Error::KeyError { msg: e.to_string() }),
+        }
+    }
+
+    /// Hash recorded for an encrypted version: an HMAC of its contents hash keyed by the
+    /// bin's key, so the db can not be used to confirm guesses of the plaintext
+    pub fn keyed_hash(&self, hash: &str) -> Result<String, Error> {
+        let identity = self.load_identity()?;
+        let mut mac =
+            match Hmac::<Sha512>::new_from_slice(identity.to_string().expose_secret().as_bytes()) {
+                Ok(m) => m,
+                Err(e) => return Err(Error::KeyError { msg: e.to_string() }),
+            };
+        mac.update(hash.as_bytes());
+        Ok(format!("{:x}", mac.finalize().into_bytes()))
+    }
+
+    /// Hash to record for a new version with the given contents hash
+    pub fn recorded_hash(&self, hash: &str, encrypted: bool) -> Result<String, Error> {
+        if encrypted && hash != "DIR" {
+            return self.keyed_hash(hash);
+        }
+        Ok(hash.to_string())
+    }
+
+    /// Check a version holds contents with the given hash
+    /// encrypted versions need the key; ones recorded before hashes were keyed hold the
+    /// plain hash
+    pub fn matches_hash(&self, version: &Version, hash: &str) -> Result<bool, Error> {
+        if version.data_hash == hash {
+            return Ok(true);
+        }
+        if !version.encrypted {
+            return Ok(false);
+        }
+        Ok(self.keyed_hash(hash)? == version.data_hash)
+    }
+
+    /// Store an encrypted version of a config
+    /// The ciphertext is stored uncompressed as an object named by its own hash, so it
+    /// can be checked without the key; returns that hash
+    pub fn store_encrypted_version(
+        &self,
+        journal: &mut Journal,
+        blob: &String,
+        ver: u32,
+        data: &[u8],
+    ) -> Result<String, Error> {
+        info!("Encrypting {} V {}", blob, ver);
+        let sealed = self.encrypt(data)?;
+        let hash = hash_bytes(&sealed);
+
+        self.store_object(journal, &hash, &sealed, Codec::None)?;
+        self.write_pointer(journal, blob, ver, &hash)?;
+        Ok(hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use age::{secrecy::ExposeSecret, x25519::Identity};
+
+    use crate::{hash_bytes, Bin, Codec, FileMeta, LockWait, Version};
+
+    // A bin with a fresh key of the given name in the temp dir
+    fn keyed_bin(dir: &tempfile::TempDir, name: &str) -> Bin {
+        let key = dir.path().join(name);
+        fs::write(&key, Identity::generate().to_string().expose_secret()).unwrap();
+
+        let path = dir.path().join(format!("bin-{}", name));
+        let mut bin = Bin::new(
+            path.to_string_lossy().to_string(),
+            None,
+            false,
+            false,
+            LockWait::Never,
+        )
+        .unwrap();
+        bin.set_key(key);
+        bin
+    }
+
+    fn encrypted_version(data_hash: String) -> Version {
+        Version {
+            data_hash,
+            ver: 1,
+            tag: String::new(),
+            owner: "blob".to_string(),
+            codec: Codec::None,
+            encrypted: true,
+            created: 0,
+            hostname: String::new(),
+            username: String::new(),
+            message: String::new(),
+            meta: FileMeta::default(),
+        }
+    }
+
+    #[test]
+    fn encrypted_versions_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let bin = keyed_bin(&dir, "a");
+        let other = keyed_bin(&dir, "b");
+        let blob = "blob".to_string();
+        let data = b"password = hunter2\n";
+
+        let sealed = bin
+            .transaction(|j| {
+                let sealed = bin.store_encrypted_version(j, &blob, 1, data)?;
+                let hash = bin.keyed_hash(&hash_bytes(data))?;
+                bin.db.add_version(&encrypted_version(hash))?;
+                Ok(sealed)
+            })
+            .unwrap();
+
+        // stored as an age file named by the hash of the ciphertext
+        let stored = fs::read(bin.object_path(&sealed, Codec::None)).unwrap();
+        assert!(super::is_encrypted(&stored));
+        assert_eq!(hash_bytes(&stored), sealed);
+        assert!(!stored.windows(7).any(|w| w == b"hunter2"));
+
+        assert_eq!(bin.read_blob(&blob, 1).unwrap(), data);
+        assert!(other.decrypt(&stored).is_err());
+    }
+
+    #[test]
+    fn encrypt_subsets_are_parsed() {
+        let read = |yaml: &str| Bin::read_encrypt_subsets(&serde_yaml::from_str(yaml).unwrap());
+        assert!(read("name: bin").unwrap().is_empty());
+        assert_eq!(
+            read("encrypt_subsets: [secrets, keys]").unwrap(),
+            vec!["secrets", "keys"]
+        );
+        assert!(read("encrypt_subsets: secrets").is_err());
+        assert!(read("encrypt_subsets: [1, [2]]").is_err());
+    }
+
+    #[test]
+    fn recorded_hashes_are_keyed() {
+        let dir = tempfile::tempdir().unwrap();
+        let bin = keyed_bin(&dir, "a");
+        let other = keyed_bin(&dir, "b");
+        let hash = hash_bytes(b"secret");
+
+        let keyed = bin.recorded_hash(&hash, true).unwrap();
+        assert_ne!(keyed, hash);
+        assert_eq!(keyed, bin.keyed_hash(&hash).unwrap());
+        assert_ne!(keyed, other.keyed_hash(&hash).unwrap());
+        assert_eq!(bin.recorded_hash(&hash, false).unwrap(), hash);
+        assert_eq!(bin.recorded_hash("DIR", true).unwrap(), "DIR");
+
+        let v = encrypted_version(keyed);
+        assert!(bin.matches_hash(&v, &hash).unwrap());
+        assert!(!bin.matches_hash(&v, &hash_bytes(b"guess")).unwrap());
+        assert!(!other.matches_hash(&v, &hash).unwrap());
+
+        // versions recorded before hashes were keyed
+        assert!(bin
+            .matches_hash(&encrypted_version(hash.clone()), &hash)
+            .unwrap());
+    }
+}
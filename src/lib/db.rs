@@ -1,76 +1,647 @@
-use std::collections::HashMap;
+use std::{
+    cell::OnceCell,
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
+    time::Duration,
+};
 
+use crate::pivot::Rekey;
 use crate::Config;
+use crate::ConfigSummary;
 use crate::Database;
 use crate::Error;
+use crate::FileMeta;
 use crate::Map;
+use crate::Mapping;
 use crate::Version;
-use log::info;
+use log::{error, info};
 use rusqlite::{params, Connection};
 
-const INIT_CONFIGS: &str =
-    "CREATE TABLE configs (id TEXT, subset TEXT, family TEXT, map_path TEXT)";
-const INIT_VERSIONS: &str = "CREATE TABLE versions (id TEXT, ver INTEGER, tag TEXT, owner TEXT)";
-const INIT_MAPPED: &str = "CREATE TABLE maps (id TEXT, ver INTEGER, nhash TEXT, tag TEXT)";
+// Schema version this client writes; stored in PRAGMA user_version
+// bins with a lower version are migrated on open, higher versions are refused
+pub(crate) const SCHEMA_VERSION: u32 = 11;
+
+// MIGRATIONS[n] upgrades a db from version n to n + 1, and runs in one transaction
+// version 0 is an unversioned bin, which may lack the mappings tables; an empty db is also 0
+const MIGRATIONS: [&str; SCHEMA_VERSION as usize] = [
+    // 1: primary keys, unique (owner, ver) versions and an index on configs.map_path
+    "CREATE TABLE IF NOT EXISTS configs (id TEXT, subset TEXT, family TEXT, map_path TEXT);
+    CREATE TABLE IF NOT EXISTS versions (id TEXT, ver INTEGER, tag TEXT, owner TEXT);
+    CREATE TABLE IF NOT EXISTS maps (id TEXT, ver INTEGER, nhash TEXT, tag TEXT);
+    CREATE TABLE IF NOT EXISTS mappings (var TEXT, prefix TEXT);
+    CREATE TABLE IF NOT EXISTS profiles (name TEXT, var TEXT, prefix TEXT);
+
+    ALTER TABLE configs RENAME TO configs_v0;
+    CREATE TABLE configs (
+        id TEXT NOT NULL PRIMARY KEY,
+        subset TEXT NOT NULL DEFAULT '',
+        family TEXT NOT NULL DEFAULT '',
+        map_path TEXT NOT NULL
+    );
+    INSERT OR IGNORE INTO configs
+        SELECT id, IFNULL(subset, ''), IFNULL(family, ''), map_path FROM configs_v0;
+    DROP TABLE configs_v0;
+    CREATE UNIQUE INDEX configs_map_path ON configs (map_path);
+
+    ALTER TABLE versions RENAME TO versions_v0;
+    CREATE TABLE versions (
+        id TEXT NOT NULL,
+        ver INTEGER NOT NULL,
+        tag TEXT NOT NULL DEFAULT '',
+        owner TEXT NOT NULL,
+        PRIMARY KEY (owner, ver)
+    );
+    INSERT OR IGNORE INTO versions SELECT id, ver, IFNULL(tag, ''), owner FROM versions_v0;
+    DROP TABLE versions_v0;
+
+    ALTER TABLE maps RENAME TO maps_v0;
+    CREATE TABLE maps (
+        id TEXT NOT NULL,
+        ver INTEGER NOT NULL,
+        nhash TEXT NOT NULL,
+        tag TEXT NOT NULL DEFAULT '',
+        PRIMARY KEY (id, ver)
+    );
+    INSERT OR IGNORE INTO maps SELECT id, ver, nhash, IFNULL(tag, '') FROM maps_v0;
+    DROP TABLE maps_v0;
+
+    ALTER TABLE mappings RENAME TO mappings_v0;
+    CREATE TABLE mappings (var TEXT NOT NULL PRIMARY KEY, prefix TEXT NOT NULL);
+    INSERT OR IGNORE INTO mappings SELECT var, prefix FROM mappings_v0;
+    DROP TABLE mappings_v0;
+
+    ALTER TABLE profiles RENAME TO profiles_v0;
+    CREATE TABLE profiles (
+        name TEXT NOT NULL,
+        var TEXT NOT NULL,
+        prefix TEXT NOT NULL,
+        PRIMARY KEY (name, var)
+    );
+    INSERT OR IGNORE INTO profiles SELECT name, var, prefix FROM profiles_v0;
+    DROP TABLE profiles_v0;",
+    // 2: version contents move to the objects store; blob files become pointers
+    "CREATE INDEX IF NOT EXISTS versions_id ON versions (id);",
+    // 3: versions record the codec their object is compressed with
+    "ALTER TABLE versions ADD COLUMN codec TEXT NOT NULL DEFAULT 'none';",
+    // 4: versions record whether their object is encrypted
+    "ALTER TABLE versions ADD COLUMN encrypted INTEGER NOT NULL DEFAULT 0;",
+    // 5: versions may use the delta codec; older clients can not read those objects
+    "",
+    // 6: objects and pointers may be packed; older clients can not find them
+    "",
+    // 7: versions record when they were pushed, in seconds since the unix epoch
+    "ALTER TABLE versions ADD COLUMN created INTEGER NOT NULL DEFAULT 0;",
+    // 8: key/value information about the bin itself
+    "CREATE TABLE IF NOT EXISTS binfo (key TEXT NOT NULL PRIMARY KEY, value TEXT NOT NULL);",
+    // 9: versions and maps record the host, user and message they were pushed with;
+    // maps also record when they were pushed
+    "ALTER TABLE versions ADD COLUMN hostname TEXT NOT NULL DEFAULT '';
+    ALTER TABLE versions ADD COLUMN username TEXT NOT NULL DEFAULT '';
+    ALTER TABLE versions ADD COLUMN message TEXT NOT NULL DEFAULT '';
+    ALTER TABLE maps ADD COLUMN created INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE maps ADD COLUMN hostname TEXT NOT NULL DEFAULT '';
+    ALTER TABLE maps ADD COLUMN username TEXT NOT NULL DEFAULT '';
+    ALTER TABLE maps ADD COLUMN message TEXT NOT NULL DEFAULT '';",
+    // 10: versions record the mode, ownership and mtime of the pushed file; mode 0 if unknown
+    "ALTER TABLE versions ADD COLUMN mode INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE versions ADD COLUMN uid INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE versions ADD COLUMN gid INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE versions ADD COLUMN uname TEXT NOT NULL DEFAULT '';
+    ALTER TABLE versions ADD COLUMN gname TEXT NOT NULL DEFAULT '';
+    ALTER TABLE versions ADD COLUMN mtime INTEGER NOT NULL DEFAULT 0;",
+    // 11: configs stored by real path before mappings are re-keyed by their mapped paths
+    "",
+];
+
+// How long sqlite retries a busy db before failing
+const BUSY_TIMEOUT: Duration = Duration::from_secs(30);
+
+// tables and columns every bin must have
+const REQUIRED_SCHEMA: [(&str, &[&str]); 6] = [
+    ("configs", &["id", "subset", "family", "map_path"]),
+    (
+        "versions",
+        &[
+            "id",
+            "ver",
+            "tag",
+            "owner",
+            "codec",
+            "encrypted",
+            "created",
+            "hostname",
+            "username",
+            "message",
+            "mode",
+            "uid",
+            "gid",
+            "uname",
+            "gname",
+            "mtime",
+        ],
+    ),
+    (
+        "maps",
+        &[
+            "id", "ver", "nhash", "tag", "created", "hostname", "username", "message",
+        ],
+    ),
+    ("mappings", &["var", "prefix"]),
+    ("profiles", &["name", "var", "prefix"]),
+    ("binfo", &["key", "value"]),
+];
+
+// Columns of a versions row, in the order row_version reads them
+const VERSION_COLUMNS: &str =
+    "id, ver, tag, owner, codec, encrypted, created, hostname, username, \
+    message, mode, uid, gid, uname, gname, mtime";
+
+// A version from a row selected with VERSION_COLUMNS
+fn row_version(row: &rusqlite::Row) -> rusqlite::Result<Version> {
+    Ok(Version {
+        data_hash: row.get(0)?,
+        ver: row.get(1)?,
+        tag: row.get(2)?,
+        owner: row.get(3)?,
+        codec: row.get(4)?,
+        encrypted: row.get(5)?,
+        created: row.get(6)?,
+        hostname: row.get(7)?,
+        username: row.get(8)?,
+        message: row.get(9)?,
+        meta: FileMeta {
+            mode: row.get(10)?,
+            uid: row.get(11)?,
+            gid: row.get(12)?,
+            user: row.get(13)?,
+            group: row.get(14)?,
+            mtime: row.get(15)?,
+        },
+    })
+}
+
+// Columns of a maps row, in the order row_map reads them
+const MAP_COLUMNS: &str = "id, ver, nhash, tag, created, hostname, username, message";
+
+// Values the migrations give columns older dbs lack; NULLs in unversioned dbs read the same
+const VERSION_DEFAULTS: [(&str, &str); 13] = [
+    ("tag", "''"),
+    ("codec", "'none'"),
+    ("encrypted", "0"),
+    ("created", "0"),
+    ("hostname", "''"),
+    ("username", "''"),
+    ("message", "''"),
+    ("mode", "0"),
+    ("uid", "0"),
+    ("gid", "0"),
+    ("uname", "''"),
+    ("gname", "''"),
+    ("mtime", "0"),
+];
+const MAP_DEFAULTS: [(&str, &str); 5] = [
+    ("tag", "''"),
+    ("created", "0"),
+    ("hostname", "''"),
+    ("username", "''"),
+    ("message", "''"),
+];
+
+// A map from a row selected with MAP_COLUMNS, without its configs
+fn row_map(row: &rusqlite::Row) -> rusqlite::Result<Map> {
+    Ok(Map {
+        blob: row.get(0)?,
+        ver: row.get(1)?,
+        hash: row.get(2)?,
+        tag: row.get(3)?,
+        created: row.get(4)?,
+        hostname: row.get(5)?,
+        username: row.get(6)?,
+        message: row.get(7)?,
+        configs: Vec::new(),
+    })
+}
 
 impl Database {
-    // TODO: Impliment me; logic for sql verification missing
+    pub(crate) fn new(path: PathBuf) -> Self {
+        Database {
+            path,
+            con: OnceCell::new(),
+        }
+    }
+
+    // The bin's connection; opened on first use and shared for the life of the bin
+    fn con(&self) -> Result<&Connection, Error> {
+        if let Some(c) = self.con.get() {
+            return Ok(c);
+        }
+
+        info!("Opening {:?}", self.path);
+        let con = match Connection::open(&self.path) {
+            Ok(c) => c,
+            Err(e) => return Err(Error::SQLError { msg: e.to_string() }),
+        };
+
+        // the bin lock serializes meld itself; this covers anything else touching the db
+        if let Err(e) = con.busy_timeout(BUSY_TIMEOUT) {
+            return Err(Error::SQLError { msg: e.to_string() });
+        }
+
+        Ok(self.con.get_or_init(|| con))
+    }
+
+    /// Run f inside a transaction; committed if f succeeds, rolled back if it fails
+    /// Transactions nest, so f may call methods that start their own
+    pub fn transaction<T, F>(&self, f: F) -> Result<T, Error>
+    where
+        F: FnOnce() -> Result<T, Error>,
+    {
+        let con = self.con()?;
+
+        if let Err(e) = con.execute_batch("SAVEPOINT meld") {
+            return Err(Error::SQLError { msg: e.to_string() });
+        }
+
+        let res = match f() {
+            Ok(v) => match con.execute_batch("RELEASE meld") {
+                Ok(_) => return Ok(v),
+                Err(e) => Error::SQLError { msg: e.to_string() },
+            },
+            Err(e) => e,
+        };
+
+        if let Err(e) = con.execute_batch("ROLLBACK TO meld; RELEASE meld") {
+            error!("Rollback failed: {}", e);
+        }
+
+        Err(res)
+    }
+
+    // Check the db is intact and has every table and column meld needs
     pub(crate) fn is_valid(&self) -> bool {
-        true
+        match self.check_schema() {
+            Ok(problems) => {
+                for p in &problems {
+                    error!("{}", p);
+                }
+                problems.is_empty()
+            }
+            Err(e) => {
+                error!("{}", e);
+                false
+            }
+        }
+    }
+
+    // Find every problem with the db schema; empty if valid
+    pub fn check_schema(&self) -> Result<Vec<String>, Error> {
+        info!("Checking schema of {:?}", self.path);
+        let mut problems = Vec::new();
+
+        let con = self.con()?;
+
+        let integrity: String =
+            match con.query_row("PRAGMA integrity_check", params![], |row| row.get(0)) {
+                Ok(i) => i,
+                Err(e) => return Err(Error::SQLError { msg: e.to_string() }),
+            };
+        if integrity != "ok" {
+            problems.push(format!("integrity check failed: {}", integrity));
+        }
+
+        let version: u32 = match con.query_row("PRAGMA user_version", params![], |row| row.get(0)) {
+            Ok(v) => v,
+            Err(e) => return Err(Error::SQLError { msg: e.to_string() }),
+        };
+        if version != SCHEMA_VERSION {
+            problems.push(format!(
+                "schema version is {}, expected {}",
+                version, SCHEMA_VERSION
+            ));
+        }
+
+        for (table, columns) in REQUIRED_SCHEMA {
+            let found = self.table_columns(table)?;
+            if found.is_empty() {
+                problems.push(format!("table {} is missing", table));
+                continue;
+            }
+
+            for column in columns {
+                if !found.iter().any(|f| f == column) {
+                    problems.push(format!("table {} is missing column {}", table, column));
+                }
+            }
+        }
+
+        Ok(problems)
+    }
+
+    // Get the schema version of the db
+    // Names of the columns of a table; empty if it is missing
+    fn table_columns(&self, table: &str) -> Result<Vec<String>, Error> {
+        let con = self.con()?;
+
+        let mut stmt = match con.prepare(&format!("PRAGMA table_info({})", table)) {
+            Ok(c) => c,
+            Err(e) => return Err(Error::SQLError { msg: e.to_string() }),
+        };
+
+        let columns = match stmt.query_map(params![], |row| row.get(1)) {
+            Ok(i) => i.flatten().collect(),
+            Err(e) => return Err(Error::SQLError { msg: e.to_string() }),
+        };
+        Ok(columns)
+    }
+
+    // Select list for the given columns of a table as an older db has them
+    // columns it lacks, or holds NULL in, read as their default
+    fn salvage_columns(
+        &self,
+        table: &str,
+        columns: &str,
+        defaults: &[(&str, &str)],
+    ) -> Result<String, Error> {
+        let found = self.table_columns(table)?;
+
+        let select: Vec<String> = columns
+            .split(',')
+            .map(|c| c.trim())
+            .map(|c| match defaults.iter().find(|(d, _)| *d == c) {
+                Some((_, default)) if found.iter().any(|f| f == c) => {
+                    format!("IFNULL({}, {})", c, default)
+                }
+                Some((_, default)) => default.to_string(),
+                None => c.to_string(),
+            })
+            .collect();
+        Ok(select.join(", "))
+    }
+
+    pub fn get_schema_version(&self) -> Result<u32, Error> {
+        let con = self.con()?;
+
+        match con.query_row("PRAGMA user_version", params![], |row| row.get(0)) {
+            Ok(v) => Ok(v),
+            Err(e) => Err(Error::SQLError { msg: e.to_string() }),
+        }
+    }
+
+    // Refuse dbs written by a newer client; returns the db's schema version
+    pub(crate) fn check_schema_version(&self) -> Result<u32, Error> {
+        let found = self.get_schema_version()?;
+
+        if found > SCHEMA_VERSION {
+            return Err(Error::SchemaTooNew {
+                found,
+                supported: SCHEMA_VERSION,
+            });
+        }
+
+        Ok(found)
+    }
+
+    // Run the sql of one migration step; callers wrap it in a transaction
+    // user_version is part of the db header, so it commits with the migration
+    pub(crate) fn migrate_step(&self, version: u32) -> Result<(), Error> {
+        info!("Migrating db schema {} -> {}", version, version + 1);
+
+        let con = self.con()?;
+        if let Err(e) = con.execute_batch(MIGRATIONS[version as usize]) {
+            return Err(Error::SQLError {
+                msg: format!("migration to schema {} failed: {}", version + 1, e),
+            });
+        }
+
+        match con.execute_batch(&format!("PRAGMA user_version = {}", version + 1)) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(Error::SQLError { msg: e.to_string() }),
+        }
+    }
+
+    // Bring a db with no bin files to migrate up to SCHEMA_VERSION
+    // existing bins are migrated with Bin::migrate, which also converts their files
+    fn migrate(&self) -> Result<(), Error> {
+        for version in self.check_schema_version()?..SCHEMA_VERSION {
+            self.transaction(|| self.migrate_step(version))?;
+        }
+
+        Ok(())
     }
 
     // Initialize new DB Schema
     pub(crate) fn create_db_schema(&self) -> Result<(), Error> {
         info!("Creating {:?}", self.path);
-        let con = match Connection::open(&self.path) {
+        self.migrate()
+    }
+
+    // Seed the bin config's default mappings into an empty mappings table
+    // only run when the table is created, so removed defaults stay removed
+    pub(crate) fn init_mappings(&self, defaults: &BTreeMap<String, String>) -> Result<(), Error> {
+        let con = self.con()?;
+
+        let count: u32 =
+            match con.query_row("SELECT COUNT(*) FROM mappings", params![], |row| row.get(0)) {
+                Ok(c) => c,
+                Err(e) => return Err(Error::SQLError { msg: e.to_string() }),
+            };
+
+        if count == 0 {
+            info!("Seeding default mappings");
+            for (var, prefix) in defaults {
+                self.add_mapping(&Mapping::new(var.clone(), prefix.clone(), None)?)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Get all bin-wide mappings, or the mappings of a single profile
+    pub fn get_mappings(&self, profile: &Option<String>) -> Result<Vec<Mapping>, Error> {
+        info!("Loading mappings for profile {:?}", profile);
+
+        let con = self.con()?;
+
+        // bin-wide mappings carry a NULL profile
+        let query = match profile {
+            Some(_) => "SELECT var, prefix, name FROM profiles WHERE name = ?1",
+            None => "SELECT var, prefix, ?1 FROM mappings",
+        };
+
+        let mut stmt = match con.prepare(query) {
             Ok(c) => c,
             Err(e) => return Err(Error::SQLError { msg: e.to_string() }),
         };
 
-        match con.execute(INIT_CONFIGS, params![]) {
-            Ok(c) => c,
+        let mappings_iter = match stmt.query_map(params![profile], |row| {
+            Ok(Mapping {
+                var: row.get(0)?,
+                prefix: row.get(1)?,
+                profile: row.get(2)?,
+            })
+        }) {
+            Ok(i) => i,
             Err(e) => return Err(Error::SQLError { msg: e.to_string() }),
         };
 
-        match con.execute(INIT_VERSIONS, params![]) {
+        let mut mappings = Vec::new();
+        for mapping in mappings_iter {
+            match mapping {
+                Ok(m) => mappings.push(m),
+                Err(e) => return Err(Error::SQLError { msg: e.to_string() }),
+            };
+        }
+
+        Ok(mappings)
+    }
+
+    // Get the names of all profiles defined in the bin
+    pub fn get_profiles(&self) -> Result<Vec<String>, Error> {
+        info!("Loading profile names");
+
+        let con = self.con()?;
+
+        let mut stmt = match con.prepare("SELECT DISTINCT name FROM profiles ORDER BY name") {
             Ok(c) => c,
             Err(e) => return Err(Error::SQLError { msg: e.to_string() }),
         };
 
-        match con.execute(INIT_MAPPED, params![]) {
+        let names_iter = match stmt.query_map(params![], |row| row.get(0)) {
+            Ok(i) => i,
+            Err(e) => return Err(Error::SQLError { msg: e.to_string() }),
+        };
+
+        let mut names = Vec::new();
+        for name in names_iter {
+            match name {
+                Ok(n) => names.push(n),
+                Err(e) => return Err(Error::SQLError { msg: e.to_string() }),
+            };
+        }
+
+        Ok(names)
+    }
+
+    // Add or replace a mapping in the mappings or profiles table
+    pub fn add_mapping(&self, m: &Mapping) -> Result<(), Error> {
+        info!("Adding mapping {} -> {} ({:?})", m.var, m.prefix, m.profile);
+
+        self.remove_mapping(&m.var, &m.profile)?;
+
+        let con = self.con()?;
+
+        let res = match &m.profile {
+            Some(p) => con.execute(
+                "INSERT INTO profiles (name, var, prefix) VALUES (?1, ?2, ?3)",
+                params![p, m.var, m.prefix],
+            ),
+            None => con.execute(
+                "INSERT INTO mappings (var, prefix) VALUES (?1, ?2)",
+                params![m.var, m.prefix],
+            ),
+        };
+
+        match res {
             Ok(c) => c,
             Err(e) => return Err(Error::SQLError { msg: e.to_string() }),
         };
 
-        return Ok(());
+        Ok(())
     }
 
-    // Get a map of all versions matching blob
-    pub fn get_versions(&self, owner: &String) -> Result<HashMap<String, Version>, Error> {
-        info!("Finding all versions with owner {}", &owner);
+    // Remove a mapping; returns true if a mapping was removed
+    pub fn remove_mapping(&self, var: &String, profile: &Option<String>) -> Result<bool, Error> {
+        info!("Removing mapping {} ({:?})", var, profile);
 
-        let mut versions: HashMap<String, Version> = HashMap::new();
+        let con = self.con()?;
 
-        let con = match Connection::open(&self.path) {
+        let res = match profile {
+            Some(p) => con.execute(
+                "DELETE FROM profiles WHERE name = ?1 AND var = ?2",
+                params![p, var],
+            ),
+            None => con.execute("DELETE FROM mappings WHERE var = ?1", params![var]),
+        };
+
+        match res {
+            Ok(c) => Ok(c > 0),
+            Err(e) => Err(Error::SQLError { msg: e.to_string() }),
+        }
+    }
+
+    // Get every binfo entry, by key
+    pub fn get_binfo(&self) -> Result<BTreeMap<String, String>, Error> {
+        info!("Loading binfo");
+
+        let con = self.con()?;
+
+        let mut stmt = match con.prepare("SELECT key, value FROM binfo") {
             Ok(c) => c,
             Err(e) => return Err(Error::SQLError { msg: e.to_string() }),
         };
 
+        let entries_iter = match stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?))) {
+            Ok(i) => i,
+            Err(e) => return Err(Error::SQLError { msg: e.to_string() }),
+        };
+
+        let mut entries = BTreeMap::new();
+        for entry in entries_iter {
+            match entry {
+                Ok((k, v)) => entries.insert(k, v),
+                Err(e) => return Err(Error::SQLError { msg: e.to_string() }),
+            };
+        }
+
+        Ok(entries)
+    }
+
+    // Add or replace a binfo entry
+    pub fn set_binfo(&self, key: &String, value: &String) -> Result<(), Error> {
+        info!("Setting binfo {} = {}", key, value);
+
+        let con = self.con()?;
+
+        match con.execute(
+            "INSERT OR REPLACE INTO binfo (key, value) VALUES (?1, ?2)",
+            params![key, value],
+        ) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(Error::SQLError { msg: e.to_string() }),
+        }
+    }
+
+    // Remove a binfo entry; returns true if an entry was removed
+    pub fn remove_binfo(&self, key: &String) -> Result<bool, Error> {
+        info!("Removing binfo {}", key);
+
+        let con = self.con()?;
+
+        match con.execute("DELETE FROM binfo WHERE key = ?1", params![key]) {
+            Ok(c) => Ok(c > 0),
+            Err(e) => Err(Error::SQLError { msg: e.to_string() }),
+        }
+    }
+
+    // Get a map of all versions matching blob, keyed by version number
+    pub fn get_versions(&self, owner: &String) -> Result<HashMap<u32, Version>, Error> {
+        info!("Finding all versions with owner {}", &owner);
+
+        let mut versions: HashMap<u32, Version> = HashMap::new();
+
+        let con = self.con()?;
+
         // select all rows from versions with matching owner
-        let mut stmt = match con.prepare("SELECT * FROM versions where owner = ? ") {
+        let mut stmt = match con.prepare(&format!(
+            "SELECT {} FROM versions where owner = ?",
+            VERSION_COLUMNS
+        )) {
             Ok(c) => c,
             Err(e) => return Err(Error::SQLError { msg: e.to_string() }),
         };
 
         // convert the rows into a MappedRows iterator
-        let versions_iter = match stmt.query_map(params![owner], |row| {
-            Ok(Version {
-                data_hash: row.get(0)?,
-                ver: row.get(1)?,
-                tag: row.get(2)?,
-                owner: row.get(3)?,
-            })
-        }) {
+        let versions_iter = match stmt.query_map(params![owner], row_version) {
             Ok(i) => i,
             Err(e) => return Err(Error::SQLError { msg: e.to_string() }),
         };
@@ -78,88 +649,241 @@ impl Database {
         // map the rows iterator into our hashmap
         for version in versions_iter {
             match version {
-                Ok(v) => versions.insert(v.data_hash.clone(), v),
+                Ok(v) => versions.insert(v.ver, v),
                 _ => None,
             };
         }
 
-        return Ok(versions);
+        Ok(versions)
     }
 
-    // Get the current version of the config
-    pub fn get_current_version(&self, owner: &String) -> Result<Option<Version>, Error> {
-        info!("Finding the current version with owner {}", &owner);
+    // Get every version of every config
+    pub fn get_all_versions(&self) -> Result<Vec<Version>, Error> {
+        info!("Finding all versions");
+        self.select_all_versions(VERSION_COLUMNS)
+    }
 
-        let con = match Connection::open(&self.path) {
+    // get every version of every config from a db of any schema, for repair
+    pub(crate) fn salvage_versions(&self) -> Result<Vec<Version>, Error> {
+        info!("Salvaging all versions");
+        let columns = self.salvage_columns("versions", VERSION_COLUMNS, &VERSION_DEFAULTS)?;
+        self.select_all_versions(&columns)
+    }
+
+    // every versions row, selected in the order row_version reads them
+    fn select_all_versions(&self, columns: &str) -> Result<Vec<Version>, Error> {
+        let con = self.con()?;
+
+        let mut stmt = match con.prepare(&format!(
+            "SELECT {} FROM versions ORDER BY owner, ver",
+            columns
+        )) {
             Ok(c) => c,
             Err(e) => return Err(Error::SQLError { msg: e.to_string() }),
         };
 
+        let versions_iter = match stmt.query_map(params![], row_version) {
+            Ok(i) => i,
+            Err(e) => return Err(Error::SQLError { msg: e.to_string() }),
+        };
+
+        let mut versions = Vec::new();
+        for version in versions_iter {
+            match version {
+                Ok(v) => versions.push(v),
+                Err(e) => return Err(Error::SQLError { msg: e.to_string() }),
+            };
+        }
+
+        Ok(versions)
+    }
+
+    // Get one version of a config
+    pub fn get_version(&self, owner: &String, ver: u32) -> Result<Option<Version>, Error> {
+        info!("Finding version {} with owner {}", ver, &owner);
+
+        let con = self.con()?;
+
+        let mut stmt = match con.prepare(&format!(
+            "SELECT {} FROM versions where owner = ? AND ver = ?",
+            VERSION_COLUMNS
+        )) {
+            Ok(c) => c,
+            Err(e) => return Err(Error::SQLError { msg: e.to_string() }),
+        };
+
+        let mut versions_iter = match stmt.query_map(params![owner, ver], row_version) {
+            Ok(i) => i,
+            Err(e) => return Err(Error::SQLError { msg: e.to_string() }),
+        };
+
+        match versions_iter.next() {
+            Some(v) => match v {
+                Ok(v) => Ok(Some(v)),
+                Err(e) => Err(Error::SQLError { msg: e.to_string() }),
+            },
+            None => Ok(None),
+        }
+    }
+
+    // Get the current version of the config
+    pub fn get_current_version(&self, owner: &String) -> Result<Option<Version>, Error> {
+        info!("Finding the current version with owner {}", &owner);
+
+        let con = self.con()?;
+
         // highest version number with matching owner
-        let mut stmt = match con.prepare("SELECT * FROM versions where owner = ? ORDER BY ver DESC")
-        {
+        let mut stmt = match con.prepare(&format!(
+            "SELECT {} FROM versions where owner = ? ORDER BY ver DESC",
+            VERSION_COLUMNS
+        )) {
             Ok(c) => c,
             Err(e) => return Err(Error::SQLError { msg: e.to_string() }),
         };
 
         // convert the rows into a MappedRows iterator
-        let mut versions_iter = match stmt.query_map(params![owner], |row| {
-            Ok(Version {
-                data_hash: row.get(0)?,
-                ver: row.get(1)?,
-                tag: row.get(2)?,
-                owner: row.get(3)?,
-            })
-        }) {
+        let mut versions_iter = match stmt.query_map(params![owner], row_version) {
             Ok(i) => i,
             Err(e) => return Err(Error::SQLError { msg: e.to_string() }),
         };
 
-        return match versions_iter.next() {
+        match versions_iter.next() {
             Some(v) => match v {
                 Ok(v) => Ok(Some(v)),
                 Err(e) => Err(Error::SQLError { msg: e.to_string() }),
             },
             None => Ok(None),
-        };
+        }
     }
 
     // get the current map (if exists) for a map blob
     pub fn get_current_map(&self, blob: &String) -> Result<Option<Map>, Error> {
         info!("Finding the current map with id {}", &blob);
 
-        let con = match Connection::open(&self.path) {
-            Ok(c) => c,
-            Err(e) => return Err(Error::SQLError { msg: e.to_string() }),
-        };
+        let con = self.con()?;
 
         // highest version number with matching owner
-        let mut stmt = match con.prepare("SELECT * FROM maps where id = ? ORDER BY ver DESC") {
+        let mut stmt = match con.prepare(&format!(
+            "SELECT {} FROM maps where id = ? ORDER BY ver DESC",
+            MAP_COLUMNS
+        )) {
             Ok(c) => c,
             Err(e) => return Err(Error::SQLError { msg: e.to_string() }),
         };
 
         // convert the rows into a MappedRows iterator
-        let mut maps_iter = match stmt.query_map(params![blob], |row| {
-            Ok(Map {
-                blob: row.get(0)?,
-                ver: row.get(1)?,
-                hash: row.get(2)?,
-                tag: row.get(3)?,
-                configs: Vec::new(),
-            })
-        }) {
+        let mut maps_iter = match stmt.query_map(params![blob], row_map) {
             Ok(i) => i,
             Err(e) => return Err(Error::SQLError { msg: e.to_string() }),
         };
 
-        return match maps_iter.next() {
+        match maps_iter.next() {
             Some(v) => match v {
                 Ok(v) => Ok(Some(v)),
                 Err(e) => Err(Error::SQLError { msg: e.to_string() }),
             },
             None => Ok(None),
+        }
+    }
+
+    // get every version of a map blob, newest first
+    pub fn get_map_versions(&self, blob: &String) -> Result<Vec<Map>, Error> {
+        info!("Finding all maps with id {}", &blob);
+
+        let con = self.con()?;
+
+        let mut stmt = match con.prepare(&format!(
+            "SELECT {} FROM maps where id = ? ORDER BY ver DESC",
+            MAP_COLUMNS
+        )) {
+            Ok(c) => c,
+            Err(e) => return Err(Error::SQLError { msg: e.to_string() }),
+        };
+
+        let maps_iter = match stmt.query_map(params![blob], row_map) {
+            Ok(i) => i,
+            Err(e) => return Err(Error::SQLError { msg: e.to_string() }),
         };
+
+        let mut maps = Vec::new();
+        for map in maps_iter {
+            match map {
+                Ok(m) => maps.push(m),
+                Err(e) => return Err(Error::SQLError { msg: e.to_string() }),
+            };
+        }
+
+        Ok(maps)
+    }
+
+    // get every version of every map in the bin
+    pub fn get_all_maps(&self) -> Result<Vec<Map>, Error> {
+        info!("Finding all maps");
+        self.select_all_maps(MAP_COLUMNS)
+    }
+
+    // get every version of every map from a db of any schema, for repair
+    pub(crate) fn salvage_maps(&self) -> Result<Vec<Map>, Error> {
+        info!("Salvaging all maps");
+        let columns = self.salvage_columns("maps", MAP_COLUMNS, &MAP_DEFAULTS)?;
+        self.select_all_maps(&columns)
+    }
+
+    // every maps row, selected in the order row_map reads them
+    fn select_all_maps(&self, columns: &str) -> Result<Vec<Map>, Error> {
+        let con = self.con()?;
+
+        let mut stmt = match con.prepare(&format!("SELECT {} FROM maps ORDER BY id, ver", columns))
+        {
+            Ok(c) => c,
+            Err(e) => return Err(Error::SQLError { msg: e.to_string() }),
+        };
+
+        let maps_iter = match stmt.query_map(params![], row_map) {
+            Ok(i) => i,
+            Err(e) => return Err(Error::SQLError { msg: e.to_string() }),
+        };
+
+        let mut maps = Vec::new();
+        for map in maps_iter {
+            match map {
+                Ok(m) => maps.push(m),
+                Err(e) => return Err(Error::SQLError { msg: e.to_string() }),
+            };
+        }
+
+        Ok(maps)
+    }
+
+    // get the current version of every map in the bin
+    pub fn get_current_maps(&self) -> Result<Vec<Map>, Error> {
+        info!("Finding all current maps");
+
+        let con = self.con()?;
+
+        // sqlite returns the other columns from the row holding MAX(ver)
+        let mut stmt = match con.prepare(
+            "SELECT id, MAX(ver), nhash, tag, created, hostname, username, message \
+            FROM maps GROUP BY id",
+        ) {
+            Ok(c) => c,
+            Err(e) => return Err(Error::SQLError { msg: e.to_string() }),
+        };
+
+        let maps_iter = match stmt.query_map(params![], row_map) {
+            Ok(i) => i,
+            Err(e) => return Err(Error::SQLError { msg: e.to_string() }),
+        };
+
+        let mut maps = Vec::new();
+        for map in maps_iter {
+            match map {
+                Ok(m) => maps.push(m),
+                Err(e) => return Err(Error::SQLError { msg: e.to_string() }),
+            };
+        }
+
+        Ok(maps)
     }
 
     // get the current map (if exists) for a map blob
@@ -167,10 +891,7 @@ impl Database {
     pub fn get_mapped_path(&self, config_blob: &String) -> Result<Option<String>, Error> {
         info!("Checking DB for map_path {}", config_blob);
 
-        let con = match Connection::open(&self.path) {
-            Ok(c) => c,
-            Err(e) => return Err(Error::SQLError { msg: e.to_string() }),
-        };
+        let con = self.con()?;
 
         // highest version number with matching owner
         let mut stmt = match con.prepare("SELECT map_path FROM configs WHERE id = ?") {
@@ -179,28 +900,50 @@ impl Database {
         };
 
         // convert the rows into a MappedRows iterator
-        let mut blobs_iter = match stmt.query_map(params![config_blob], |row| Ok(row.get(0)?)) {
+        let mut blobs_iter = match stmt.query_map(params![config_blob], |row| row.get(0)) {
             Ok(i) => i,
             Err(e) => return Err(Error::SQLError { msg: e.to_string() }),
         };
 
-        return match blobs_iter.next() {
+        match blobs_iter.next() {
             Some(v) => match v {
                 Ok(blob) => Ok(Some(blob)),
                 Err(e) => Err(Error::SQLError { msg: e.to_string() }),
             },
             None => Ok(None),
+        }
+    }
+
+    // Get the subset of a tracked config
+    pub fn get_subset(&self, config_blob: &String) -> Result<Option<String>, Error> {
+        info!("Checking DB for subset of {}", config_blob);
+
+        let con = self.con()?;
+
+        let mut stmt = match con.prepare("SELECT subset FROM configs WHERE id = ?") {
+            Ok(c) => c,
+            Err(e) => return Err(Error::SQLError { msg: e.to_string() }),
+        };
+
+        let mut subset_iter = match stmt.query_map(params![config_blob], |row| row.get(0)) {
+            Ok(i) => i,
+            Err(e) => return Err(Error::SQLError { msg: e.to_string() }),
         };
+
+        match subset_iter.next() {
+            Some(v) => match v {
+                Ok(subset) => Ok(Some(subset)),
+                Err(e) => Err(Error::SQLError { msg: e.to_string() }),
+            },
+            None => Ok(None),
+        }
     }
 
     // Add a new version to the versions table
     pub fn add_config(&self, c: &Config) -> Result<(), Error> {
         info!("Adding config {}", c.get_blob());
 
-        let con = match Connection::open(&self.path) {
-            Ok(c) => c,
-            Err(e) => return Err(Error::SQLError { msg: e.to_string() }),
-        };
+        let con = self.con()?;
 
         // Insert config into DB configs table
         match con.execute(
@@ -211,17 +954,14 @@ impl Database {
             Err(e) => return Err(Error::SQLError { msg: e.to_string() }),
         };
 
-        return Ok(());
+        Ok(())
     }
 
     // Add a new version to the versions table
     pub fn config_exists(&self, config_map_path: &String) -> Result<Option<String>, Error> {
         info!("Checking DB for config {}", config_map_path);
 
-        let con = match Connection::open(&self.path) {
-            Ok(c) => c,
-            Err(e) => return Err(Error::SQLError { msg: e.to_string() }),
-        };
+        let con = self.con()?;
 
         // highest version number with matching owner
         let mut stmt = match con.prepare("SELECT id FROM configs WHERE map_path = ?") {
@@ -230,70 +970,178 @@ impl Database {
         };
 
         // convert the rows into a MappedRows iterator
-        let mut blobs_iter = match stmt.query_map(params![config_map_path], |row| Ok(row.get(0)?)) {
+        let mut blobs_iter = match stmt.query_map(params![config_map_path], |row| row.get(0)) {
             Ok(i) => i,
             Err(e) => return Err(Error::SQLError { msg: e.to_string() }),
         };
 
-        return match blobs_iter.next() {
+        match blobs_iter.next() {
             Some(v) => match v {
                 Ok(blob) => Ok(Some(blob)),
                 Err(e) => Err(Error::SQLError { msg: e.to_string() }),
             },
             None => Ok(None),
-        };
+        }
     }
 
     // Add a new version to the versions table
     pub fn add_version(&self, v: &Version) -> Result<(), Error> {
         info!("Adding version {}", v.data_hash);
 
-        let con = match Connection::open(&self.path) {
+        let con = self.con()?;
+
+        // Insert version into DB versions table
+        match con.execute(
+            "INSERT INTO versions \
+            (id, ver, tag, owner, codec, encrypted, created, hostname, username, message, \
+            mode, uid, gid, uname, gname, mtime) \
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16)",
+            params![
+                v.data_hash,
+                v.ver,
+                v.tag,
+                v.owner,
+                v.codec,
+                v.encrypted,
+                v.created,
+                v.hostname,
+                v.username,
+                v.message,
+                v.meta.mode,
+                v.meta.uid,
+                v.meta.gid,
+                v.meta.user,
+                v.meta.group,
+                v.meta.mtime
+            ],
+        ) {
             Ok(c) => c,
             Err(e) => return Err(Error::SQLError { msg: e.to_string() }),
         };
 
-        // Insert version into DB versions table
+        Ok(())
+    }
+
+    // Remove a version from the versions table
+    pub fn remove_version(&self, owner: &String, ver: u32) -> Result<(), Error> {
+        info!("Removing version {} of {}", ver, owner);
+
+        let con = self.con()?;
+
         match con.execute(
-            "INSERT INTO versions (id, ver, tag, owner) VALUES (?1, ?2, ?3, ?4)",
-            params![v.data_hash, v.ver, v.tag, v.owner],
+            "DELETE FROM versions WHERE owner = ?1 AND ver = ?2",
+            params![owner, ver],
         ) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(Error::SQLError { msg: e.to_string() }),
+        }
+    }
+
+    // Get the (owner, ver) of every row of versions or maps without a created time
+    // only reads columns every schema since 7 has, so it is safe mid-migration
+    pub(crate) fn get_uncreated(&self, table: &str) -> Result<Vec<(String, u32)>, Error> {
+        let con = self.con()?;
+
+        let owner = if table == "maps" { "id" } else { "owner" };
+        let mut stmt = match con.prepare(&format!(
+            "SELECT {}, ver FROM {} WHERE created = 0",
+            owner, table
+        )) {
             Ok(c) => c,
             Err(e) => return Err(Error::SQLError { msg: e.to_string() }),
         };
 
-        return Ok(());
+        let rows_iter = match stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?))) {
+            Ok(i) => i,
+            Err(e) => return Err(Error::SQLError { msg: e.to_string() }),
+        };
+
+        let mut rows = Vec::new();
+        for row in rows_iter {
+            match row {
+                Ok(r) => rows.push(r),
+                Err(e) => return Err(Error::SQLError { msg: e.to_string() }),
+            };
+        }
+
+        Ok(rows)
+    }
+
+    // Set when a version was pushed; used to fill in versions from older bins
+    pub(crate) fn set_version_created(
+        &self,
+        owner: &String,
+        ver: u32,
+        created: i64,
+    ) -> Result<(), Error> {
+        let con = self.con()?;
+
+        match con.execute(
+            "UPDATE versions SET created = ?1 WHERE owner = ?2 AND ver = ?3",
+            params![created, owner, ver],
+        ) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(Error::SQLError { msg: e.to_string() }),
+        }
+    }
+
+    // Set when a map was pushed; used to fill in maps from older bins
+    pub(crate) fn set_map_created(
+        &self,
+        blob: &String,
+        ver: u32,
+        created: i64,
+    ) -> Result<(), Error> {
+        let con = self.con()?;
+
+        match con.execute(
+            "UPDATE maps SET created = ?1 WHERE id = ?2 AND ver = ?3",
+            params![created, blob, ver],
+        ) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(Error::SQLError { msg: e.to_string() }),
+        }
     }
 
     // Add a new map to the maps table
     pub fn add_map(&self, m: &Map) -> Result<(), Error> {
         info!("Adding map {}", m.get_blob());
 
-        let con = match Connection::open(&self.path) {
-            Ok(c) => c,
-            Err(e) => return Err(Error::SQLError { msg: e.to_string() }),
-        };
+        let con = self.con()?;
 
         // Insert config into DB configs table
         match con.execute(
-            "INSERT INTO maps (id, ver, nhash, tag) VALUES (?1, ?2, ?3, ?4)",
-            params![m.blob, m.ver, m.hash, m.tag],
+            "INSERT INTO maps (id, ver, nhash, tag, created, hostname, username, message) \
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+            params![m.blob, m.ver, m.hash, m.tag, m.created, m.hostname, m.username, m.message],
         ) {
             Ok(c) => c,
             Err(e) => return Err(Error::SQLError { msg: e.to_string() }),
         };
 
-        return Ok(());
+        Ok(())
+    }
+
+    // Remove a map snapshot from the maps table
+    pub fn remove_map(&self, blob: &String, ver: u32) -> Result<(), Error> {
+        info!("Removing map {} of {}", ver, blob);
+
+        let con = self.con()?;
+
+        match con.execute(
+            "DELETE FROM maps WHERE id = ?1 AND ver = ?2",
+            params![blob, ver],
+        ) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(Error::SQLError { msg: e.to_string() }),
+        }
     }
 
     // Update a version's tag
     pub fn update_version_tag(&self, v: &Version, tag: &String) -> Result<(), Error> {
         info!("Updating version tag '{}' -> '{}'", v.tag, tag);
 
-        let con = match Connection::open(&self.path) {
-            Ok(c) => c,
-            Err(e) => return Err(Error::SQLError { msg: e.to_string() }),
-        };
+        let con = self.con()?;
 
         // Insert version into DB versions table
         match con.execute(
@@ -304,17 +1152,14 @@ impl Database {
             Err(e) => return Err(Error::SQLError { msg: e.to_string() }),
         };
 
-        return Ok(());
+        Ok(())
     }
 
     // Update a config's subset
     pub fn update_subset(&self, blob: &String, subset: &String) -> Result<(), Error> {
         info!("Updating blobs subset '{}'", subset);
 
-        let con = match Connection::open(&self.path) {
-            Ok(c) => c,
-            Err(e) => return Err(Error::SQLError { msg: e.to_string() }),
-        };
+        let con = self.con()?;
 
         // Insert version into DB versions table
         match con.execute(
@@ -325,17 +1170,14 @@ impl Database {
             Err(e) => return Err(Error::SQLError { msg: e.to_string() }),
         };
 
-        return Ok(());
+        Ok(())
     }
 
     // Update a config's family
     pub fn update_family(&self, blob: &String, family: &String) -> Result<(), Error> {
         info!("Updating blobs family '{}'", family);
 
-        let con = match Connection::open(&self.path) {
-            Ok(c) => c,
-            Err(e) => return Err(Error::SQLError { msg: e.to_string() }),
-        };
+        let con = self.con()?;
 
         // Insert version into DB versions table
         match con.execute(
@@ -346,6 +1188,281 @@ impl Database {
             Err(e) => return Err(Error::SQLError { msg: e.to_string() }),
         };
 
-        return Ok(());
+        Ok(())
+    }
+
+    // Get every config tracked in the bin
+    // only DB backed fields are set; real paths must be resolved by the caller
+    pub fn get_configs(&self) -> Result<Vec<Config>, Error> {
+        info!("Loading all configs");
+
+        let con = self.con()?;
+
+        let mut stmt = match con.prepare("SELECT id, subset, family, map_path FROM configs") {
+            Ok(c) => c,
+            Err(e) => return Err(Error::SQLError { msg: e.to_string() }),
+        };
+
+        let configs_iter = match stmt.query_map(params![], |row| {
+            Ok(Config {
+                blob: row.get(0)?,
+                real_path: String::new(),
+                subset: row.get(1)?,
+                family: row.get(2)?,
+                map_path: row.get(3)?,
+                tag: String::new(),
+                hash: String::new(),
+                meta: FileMeta::default(),
+                encrypt: false,
+                message: String::new(),
+                versions: HashMap::new(),
+            })
+        }) {
+            Ok(i) => i,
+            Err(e) => return Err(Error::SQLError { msg: e.to_string() }),
+        };
+
+        let mut configs = Vec::new();
+        for config in configs_iter {
+            match config {
+                Ok(c) => configs.push(c),
+                Err(e) => return Err(Error::SQLError { msg: e.to_string() }),
+            };
+        }
+
+        Ok(configs)
+    }
+
+    // Re-key configs, versions and maps and rename a mapping variable
+    // callers run this inside a transaction along with the matching file renames
+    pub(crate) fn pivot(
+        &self,
+        rekeys: &Vec<Rekey>,
+        from: &String,
+        to: &String,
+    ) -> Result<(), Error> {
+        info!("Pivoting {} -> {} ({} configs)", from, to, rekeys.len());
+
+        self.rekey(rekeys)?;
+
+        let con = self.con()?;
+        for sql in [
+            "UPDATE mappings SET var = ?1 WHERE var = ?2",
+            "UPDATE profiles SET var = ?1 WHERE var = ?2",
+        ] {
+            if let Err(e) = con.execute(sql, params![to, from]) {
+                return Err(Error::SQLError { msg: e.to_string() });
+            }
+        }
+
+        Ok(())
+    }
+
+    // Re-key configs, versions and maps to new blob names and map paths
+    pub(crate) fn rekey(&self, rekeys: &Vec<Rekey>) -> Result<(), Error> {
+        let con = self.con()?;
+
+        for r in rekeys {
+            let updates = [
+                (
+                    "UPDATE configs SET id = ?1, map_path = ?3 WHERE id = ?2",
+                    params![r.new_blob, r.old_blob, r.map_path],
+                ),
+                (
+                    "UPDATE versions SET owner = ?1 WHERE owner = ?2",
+                    params![r.new_blob, r.old_blob],
+                ),
+                (
+                    "UPDATE maps SET id = ?1 WHERE id = ?2",
+                    params![r.new_blob, r.old_blob],
+                ),
+            ];
+            for (sql, p) in updates {
+                if let Err(e) = con.execute(sql, p) {
+                    return Err(Error::SQLError { msg: e.to_string() });
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Get a summary of every tracked config, ordered by map path
+    pub fn get_config_summaries(&self) -> Result<Vec<ConfigSummary>, Error> {
+        info!("Loading config summaries");
+
+        let con = self.con()?;
+
+        // collect all tags up front; tags are free text so avoid GROUP_CONCAT
+        let mut tags: HashMap<String, Vec<String>> = HashMap::new();
+        let mut stmt = match con
+            .prepare("SELECT owner, tag FROM versions WHERE tag != '' ORDER BY owner, ver")
+        {
+            Ok(c) => c,
+            Err(e) => return Err(Error::SQLError { msg: e.to_string() }),
+        };
+
+        let tags_iter = match stmt.query_map(params![], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        }) {
+            Ok(i) => i,
+            Err(e) => return Err(Error::SQLError { msg: e.to_string() }),
+        };
+
+        for tag in tags_iter {
+            match tag {
+                Ok((owner, tag)) => {
+                    let owner_tags = tags.entry(owner).or_default();
+                    if !owner_tags.contains(&tag) {
+                        owner_tags.push(tag);
+                    }
+                }
+                Err(e) => return Err(Error::SQLError { msg: e.to_string() }),
+            };
+        }
+
+        // a config is a map if any snapshot of it exists
+        let mut stmt = match con.prepare(
+            "SELECT c.id, c.map_path, c.subset, c.family, \
+                (SELECT MAX(v.ver) FROM versions v WHERE v.owner = c.id), \
+                (SELECT MAX(m.ver) FROM maps m WHERE m.id = c.id) \
+             FROM configs c ORDER BY c.map_path",
+        ) {
+            Ok(c) => c,
+            Err(e) => return Err(Error::SQLError { msg: e.to_string() }),
+        };
+
+        let summaries_iter = match stmt.query_map(params![], |row| {
+            Ok(ConfigSummary {
+                blob: row.get(0)?,
+                map_path: row.get(1)?,
+                subset: row.get(2)?,
+                family: row.get(3)?,
+                latest: row.get::<_, Option<u32>>(4)?.unwrap_or(0),
+                tags: Vec::new(),
+                map_ver: row.get(5)?,
+            })
+        }) {
+            Ok(i) => i,
+            Err(e) => return Err(Error::SQLError { msg: e.to_string() }),
+        };
+
+        let mut summaries = Vec::new();
+        for summary in summaries_iter {
+            match summary {
+                Ok(mut s) => {
+                    if let Some(t) = tags.remove(&s.blob) {
+                        s.tags = t;
+                    }
+                    summaries.push(s);
+                }
+                Err(e) => return Err(Error::SQLError { msg: e.to_string() }),
+            };
+        }
+
+        Ok(summaries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{hash_bytes, Bin, Error, FileMeta, LockWait, Map, Version};
+
+    fn new_bin(dir: &tempfile::TempDir) -> Bin {
+        let path = dir.path().join("bin").to_string_lossy().to_string();
+        Bin::new(path, None, false, false, LockWait::Never).unwrap()
+    }
+
+    fn version(owner: &str, ver: u32, hash: &str) -> Version {
+        Version {
+            data_hash: hash.to_string(),
+            ver,
+            tag: String::new(),
+            owner: owner.to_string(),
+            codec: crate::Codec::None,
+            encrypted: false,
+            created: 0,
+            hostname: String::new(),
+            username: String::new(),
+            message: String::new(),
+            meta: FileMeta::default(),
+        }
+    }
+
+    fn map(ver: u32, tag: &str) -> Map {
+        Map {
+            blob: "dir".to_string(),
+            ver,
+            hash: format!("hash{}", ver),
+            tag: tag.to_string(),
+            created: ver as i64 * 1000,
+            hostname: "host".to_string(),
+            username: "user".to_string(),
+            message: format!("message {}", ver),
+            configs: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn failed_transactions_roll_back_rows_and_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let bin = new_bin(&dir);
+        let blob = "blob".to_string();
+        let data = b"contents";
+        let hash = hash_bytes(data);
+
+        let res: Result<(), Error> = bin.transaction(|j| {
+            j.create_dir(bin.blobs.join(&blob).to_string_lossy().to_string())?;
+            let codec = bin.store_version(j, &blob, 1, &hash, data, None)?;
+            bin.db.add_version(&version(&blob, 1, &hash))?;
+
+            // the shared connection sees the transaction's own writes
+            assert_eq!(bin.db.get_all_versions()?.len(), 1);
+            assert!(bin.has_object(&hash, codec));
+            Err(Error::SomethingFailed)
+        });
+
+        assert!(res.is_err());
+        assert!(bin.db.get_all_versions().unwrap().is_empty());
+        assert!(bin.find_object(&hash).is_none());
+        assert!(!bin.blobs.join(&blob).exists());
+    }
+
+    #[test]
+    fn maps_read_back_by_column() {
+        let dir = tempfile::tempdir().unwrap();
+        let bin = new_bin(&dir);
+        bin.db.add_map(&map(1, "first")).unwrap();
+        bin.db.add_map(&map(2, "")).unwrap();
+        let blob = "dir".to_string();
+
+        let fields = |m: &Map| {
+            (
+                m.ver,
+                m.hash.clone(),
+                m.tag.clone(),
+                m.created,
+                m.hostname.clone(),
+                m.username.clone(),
+                m.message.clone(),
+            )
+        };
+        let current = bin.db.get_current_map(&blob).unwrap().unwrap();
+        assert_eq!(fields(&current), fields(&map(2, "")));
+
+        let newest_first: Vec<_> = bin
+            .db
+            .get_map_versions(&blob)
+            .unwrap()
+            .iter()
+            .map(fields)
+            .collect();
+        assert_eq!(
+            newest_first,
+            vec![fields(&map(2, "")), fields(&map(1, "first"))]
+        );
+
+        let all: Vec<_> = bin.db.get_all_maps().unwrap().iter().map(fields).collect();
+        assert_eq!(all, vec![fields(&map(1, "first")), fields(&map(2, ""))]);
     }
 }
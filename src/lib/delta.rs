@@ -0,0 +1,321 @@
+use std::{
+    collections::HashSet,
+    io::{Read, Write},
+};
+
+use log::{debug, info};
+use zstd::stream::raw::CParameter;
+
+use crate::objects::is_hash;
+use crate::Bin;
+use crate::Codec;
+use crate::Error;
+use crate::Journal;
+
+// Versions between full snapshots when snapshot_every is not set
+pub(crate) const DEFAULT_SNAPSHOT_EVERY: usize = 10;
+
+// zstd level used for patches; matches plain zstd objects
+const PATCH_LEVEL: i32 = 3;
+
+// The patch window must cover the base and the new contents; decoders accept up to
+// 2^27 by default, so larger configs are always stored whole
+const MIN_WINDOW_LOG: u32 = 10;
+const MAX_WINDOW_LOG: u32 = 27;
+
+// Contents starting with this are read by zstd as a trained dictionary, not raw content
+const ZSTD_DICT_MAGIC: [u8; 4] = [0x37, 0xA4, 0x30, 0xEC];
+
+/// Compress data as a zstd patch against base, like `zstd --patch-from`
+/// the match tables are sized to the window so all of base can be matched against
+fn encode_patch(base: &[u8], data: &[u8], window_log: u32) -> std::io::Result<Vec<u8>> {
+    let mut enc = zstd::stream::write::Encoder::with_dictionary(Vec::new(), PATCH_LEVEL, base)?;
+    enc.window_log(window_log)?;
+    enc.set_parameter(CParameter::HashLog(window_log))?;
+    enc.set_parameter(CParameter::ChainLog(window_log))?;
+    enc.write_all(data)?;
+    enc.finish()
+}
+
+/// Apply a zstd patch to its base
+fn decode_patch(base: &[u8], patch: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut dec = zstd::stream::read::Decoder::with_dictionary(patch, base)?;
+    dec.window_log_max(MAX_WINDOW_LOG)?;
+    let mut out = Vec::new();
+    dec.read_to_end(&mut out)?;
+    Ok(out)
+}
+
+impl Bin {
+    /// Read the delta settings from the bin's config
+    /// Returns whether deltas are enabled and how often a full snapshot is stored
+    pub(crate) fn read_delta(config: &serde_yaml::Value) -> Result<(bool, usize), Error> {
+        let delta = match config.get("delta") {
+            None | Some(serde_yaml::Value::Null) => false,
+            Some(serde_yaml::Value::Bool(b)) => *b,
+            Some(_) => {
+                return Err(Error::InvalidBinConfig {
+                    msg: "delta must be true or false".to_string(),
+                })
+            }
+        };
+
+        let every = match config.get("snapshot_every") {
+            None | Some(serde_yaml::Value::Null) => DEFAULT_SNAPSHOT_EVERY,
+            Some(v) => match v.as_u64() {
+                Some(n) if n >= 1 => n as usize,
+                _ => {
+                    return Err(Error::InvalidBinConfig {
+                        msg: "snapshot_every must be a positive number".to_string(),
+                    })
+                }
+            },
+        };
+
+        Ok((delta, every))
+    }
+
+    // Split a delta object into its base hash and patch
+    fn read_delta_object(&self, hash: &String) -> Result<(String, Vec<u8>), Error> {
+        let mut data = self.read_stored(hash, Codec::Delta)?;
+
+        let base = data
+            .iter()
+            .position(|b| *b == b'\n')
+            .map(|i| (String::from_utf8_lossy(&data[..i]).to_string(), i));
+        match base {
+            Some((base, i)) if is_hash(&base) => {
+                let patch = data.split_off(i + 1);
+                Ok((base, patch))
+            }
+            _ => Err(Error::IOError {
+                msg: format!("{}: not a delta object", hash),
+            }),
+        }
+    }
+
+    /// Hash of the object a delta object is patched against
+    pub fn delta_base(&self, hash: &String) -> Result<String, Error> {
+        Ok(self.read_delta_object(hash)?.0)
+    }
+
+    /// Rebuild the contents of a delta object from its chain of bases
+    pub(crate) fn rebuild_delta(&self, hash: &String) -> Result<Vec<u8>, Error> {
+        let mut patches = Vec::new();
+        let mut seen = HashSet::new();
+        let mut cur = hash.clone();
+
+        // walk back to the nearest full snapshot
+        let codec = loop {
+            match self.find_object(&cur) {
+                Some(Codec::Delta) => {
+                    if !seen.insert(cur.clone()) {
+                        return Err(Error::IOError {
+                            msg: format!("delta chain of {} loops at {}", hash, cur),
+                        });
+                    }
+                    let (base, patch) = self.read_delta_object(&cur)?;
+                    patches.push(patch);
+                    cur = base;
+                }
+                Some(c) => break c,
+                None => {
+                    return Err(Error::IOError {
+                        msg: format!("delta base {} of {} is missing", cur, hash),
+                    })
+                }
+            }
+        };
+
+        debug!("Rebuilding {} from {} deltas", hash, patches.len());
+        let mut data = self.read_object(&cur, codec)?;
+        for patch in patches.iter().rev() {
+            data = match decode_patch(&data, patch) {
+                Ok(d) => d,
+                Err(e) => {
+                    return Err(Error::IOError {
+                        msg: format!("applying delta for {}: {}", hash, e),
+                    })
+                }
+            };
+        }
+        Ok(data)
+    }
+
+    // Number of deltas between an object and its full snapshot
+    fn delta_depth(&self, hash: &str) -> Result<usize, Error> {
+        let mut depth = 0;
+        let mut cur = hash.to_string();
+        while self.find_object(&cur) == Some(Codec::Delta) {
+            depth += 1;
+            if depth >= self.config.snapshot_every {
+                break;
+            }
+            cur = self.delta_base(&cur)?;
+        }
+        Ok(depth)
+    }
+
+    /// Store contents as a delta against the base object if deltas are enabled
+    /// Returns false if a delta is not allowed or not smaller than a full copy, in
+    /// which case nothing is written
+    pub(crate) fn store_delta(
+        &self,
+        journal: &mut Journal,
+        hash: &String,
+        data: &[u8],
+        base: &String,
+    ) -> Result<bool, Error> {
+        if !self.config.delta {
+            return Ok(false);
+        }
+
+        // bound how many patches a read must apply
+        let base_codec = match self.find_object(base) {
+            Some(c) => c,
+            None => return Ok(false),
+        };
+        if self.delta_depth(base)? + 1 >= self.config.snapshot_every {
+            info!("Storing {} as a full snapshot", hash);
+            return Ok(false);
+        }
+
+        let base_data = self.read_object(base, base_codec)?;
+        if base_data.starts_with(&ZSTD_DICT_MAGIC) {
+            return Ok(false);
+        }
+
+        let size = base_data.len() + data.len();
+        let window_log = (usize::BITS - size.leading_zeros()).max(MIN_WINDOW_LOG);
+        if window_log > MAX_WINDOW_LOG {
+            return Ok(false);
+        }
+
+        let patch = match encode_patch(&base_data, data, window_log) {
+            Ok(p) => p,
+            Err(e) => {
+                return Err(Error::IOError {
+                    msg: format!("delta compression failed: {}", e),
+                })
+            }
+        };
+        let full = self.config.compression.compress(data)?;
+        if patch.len() + base.len() + 1 >= full.len() {
+            debug!("Delta of {} is not smaller than a full copy", hash);
+            return Ok(false);
+        }
+
+        info!(
+            "Storing {} as a {} byte delta against {}",
+            hash,
+            patch.len(),
+            base
+        );
+        let mut contents = format!("{}\n", base).into_bytes();
+        contents.extend(patch);
+
+        let path = self.object_path(hash, Codec::Delta);
+        if let Some(parent) = path.parent() {
+            journal.create_dir(parent.to_string_lossy().to_string())?;
+        }
+        journal.create(path.to_string_lossy().to_string(), &contents)?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DEFAULT_SNAPSHOT_EVERY;
+    use crate::{hash_bytes, Bin, Codec, LockWait};
+
+    // A config of numbered lines, with line n changed for each version
+    fn contents(version: usize) -> Vec<u8> {
+        let mut data = String::new();
+        for i in 0..200 {
+            if i == version {
+                data.push_str(&format!("line {} changed in version {}\n", i, version));
+            } else {
+                data.push_str(&format!("line {} of a config\n", i));
+            }
+        }
+        data.into_bytes()
+    }
+
+    fn new_bin(dir: &tempfile::TempDir) -> Bin {
+        let path = dir.path().join("bin").to_string_lossy().to_string();
+        let mut bin = Bin::new(path, None, false, false, LockWait::Never).unwrap();
+        bin.config.delta = true;
+        bin.config.snapshot_every = 3;
+        bin
+    }
+
+    #[test]
+    fn deltas_rebuild_with_periodic_snapshots() {
+        let dir = tempfile::tempdir().unwrap();
+        let bin = new_bin(&dir);
+        let blob = "blob".to_string();
+
+        let mut stored = Vec::new();
+        let mut base: Option<String> = None;
+        for ver in 1..=5 {
+            let data = contents(ver);
+            let hash = hash_bytes(&data);
+            let codec = bin
+                .transaction(|j| {
+                    bin.store_version(j, &blob, ver as u32, &hash, &data, base.as_ref())
+                })
+                .unwrap();
+            stored.push((hash.clone(), codec, data));
+            base = Some(hash);
+        }
+
+        // every third version is stored whole, so no read applies more than two patches
+        let codecs: Vec<Codec> = stored.iter().map(|(_, c, _)| *c).collect();
+        let full = bin.config.compression;
+        assert_eq!(
+            codecs,
+            vec![full, Codec::Delta, Codec::Delta, full, Codec::Delta]
+        );
+        assert_eq!(bin.delta_base(&stored[2].0).unwrap(), stored[1].0);
+
+        for (hash, codec, data) in &stored {
+            assert_eq!(&bin.read_object(hash, *codec).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn deltas_only_when_smaller() {
+        let dir = tempfile::tempdir().unwrap();
+        let bin = new_bin(&dir);
+        let blob = "blob".to_string();
+
+        let first = contents(0);
+        let first_hash = hash_bytes(&first);
+        bin.transaction(|j| bin.store_version(j, &blob, 1, &first_hash, &first, None))
+            .unwrap();
+
+        // unrelated contents gain nothing from the base
+        let mut seed: u32 = 1;
+        let noise: Vec<u8> = (0..512)
+            .map(|_| {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                (seed >> 16) as u8
+            })
+            .collect();
+        let hash = hash_bytes(&noise);
+        let codec = bin
+            .transaction(|j| bin.store_version(j, &blob, 2, &hash, &noise, Some(&first_hash)))
+            .unwrap();
+        assert_ne!(codec, Codec::Delta);
+        assert_eq!(bin.read_object(&hash, codec).unwrap(), noise);
+    }
+
+    #[test]
+    fn delta_settings() {
+        let read = |yaml: &str| Bin::read_delta(&serde_yaml::from_str(yaml).unwrap());
+        assert_eq!(read("name: x").unwrap(), (false, DEFAULT_SNAPSHOT_EVERY));
+        assert_eq!(read("delta: true\nsnapshot_every: 4").unwrap(), (true, 4));
+        assert!(read("delta: yes please").is_err());
+        assert!(read("snapshot_every: 0").is_err());
+    }
+}
@@ -0,0 +1,135 @@
+use std::collections::BTreeSet;
+
+use chrono::{Local, SecondsFormat};
+use log::info;
+use walkdir::WalkDir;
+
+use crate::Bin;
+use crate::BinInfo;
+use crate::Error;
+
+// binfo keys recorded when a bin is created
+const BINFO_DESCRIPTION: &str = "description";
+const BINFO_CREATOR: &str = "creator";
+const BINFO_CREATED: &str = "created";
+const BINFO_CLIENT: &str = "client";
+const BINFO_CLIENT_VERSION: &str = "client_version";
+
+impl Bin {
+    // Record who created the bin, when and with which client
+    pub(crate) fn init_binfo(&self, comments: &Option<String>) -> Result<(), Error> {
+        let entries = [
+            (
+                BINFO_CREATOR,
+                format!("{}@{}", crate::username(), crate::hostname()),
+            ),
+            (
+                BINFO_CREATED,
+                Local::now().to_rfc3339_opts(SecondsFormat::Secs, false),
+            ),
+            (BINFO_CLIENT, env!("CARGO_PKG_NAME").to_string()),
+            (BINFO_CLIENT_VERSION, env!("CARGO_PKG_VERSION").to_string()),
+        ];
+        for (key, value) in entries {
+            self.db.set_binfo(&key.to_string(), &value)?;
+        }
+
+        if let Some(c) = comments {
+            self.db.set_binfo(&BINFO_DESCRIPTION.to_string(), c)?;
+        }
+        Ok(())
+    }
+
+    /// Summarize the bin: its binfo entries, what it tracks and its size on disk
+    pub fn info(&self) -> Result<BinInfo, Error> {
+        info!("Summarizing bin {:?}", self.path);
+
+        let mut objects: BTreeSet<String> = BTreeSet::new();
+        let mut stored_size = 0;
+        for (name, path) in self.loose_objects() {
+            stored_size += path.metadata().map_or(0, |md| md.len());
+            objects.insert(name);
+        }
+        for pack in self.get_packs() {
+            stored_size += pack.path.metadata().map_or(0, |md| md.len());
+            objects.extend(pack.objects.keys().cloned());
+        }
+
+        let size = WalkDir::new(&self.path)
+            .into_iter()
+            .flatten()
+            .filter_map(|e| e.metadata().ok())
+            .filter(|md| md.is_file())
+            .map(|md| md.len())
+            .sum();
+
+        Ok(BinInfo {
+            binfo: self.db.get_binfo()?,
+            configs: self.db.get_configs()?.len(),
+            versions: self.db.get_all_versions()?.len(),
+            maps: self.db.get_all_maps()?.len(),
+            objects: objects.len(),
+            packs: self.get_packs().len(),
+            size,
+            stored_size,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{hash_bytes, Bin, LockWait};
+
+    #[test]
+    fn new_bins_record_their_origin() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bin").to_string_lossy().to_string();
+        let bin = Bin::new(
+            path,
+            Some("dotfiles".to_string()),
+            false,
+            false,
+            LockWait::Never,
+        )
+        .unwrap();
+
+        let info = bin.info().unwrap();
+        let keys: Vec<&String> = info.binfo.keys().collect();
+        assert_eq!(
+            keys,
+            vec![
+                "client",
+                "client_version",
+                "created",
+                "creator",
+                "description"
+            ]
+        );
+        assert_eq!(info.binfo["description"], "dotfiles");
+        assert_eq!(info.binfo["client_version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(
+            (info.configs, info.versions, info.maps, info.objects),
+            (0, 0, 0, 0)
+        );
+    }
+
+    #[test]
+    fn stored_objects_are_counted_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bin").to_string_lossy().to_string();
+        let bin = Bin::new(path, None, false, false, LockWait::Never).unwrap();
+        let empty = bin.info().unwrap();
+
+        let data = b"contents".repeat(100);
+        let hash = hash_bytes(&data);
+        for blob in ["a", "b"] {
+            bin.transaction(|j| bin.store_version(j, &blob.to_string(), 1, &hash, &data, None))
+                .unwrap();
+        }
+
+        let info = bin.info().unwrap();
+        assert_eq!(info.objects, 1);
+        assert_eq!(info.stored_size, data.len() as u64);
+        assert!(info.size >= empty.size + info.stored_size);
+    }
+}
@@ -0,0 +1,151 @@
+use std::{fs, path::Path};
+
+use log::{debug, error, warn};
+
+use crate::Error;
+use crate::FileMeta;
+use crate::Journal;
+use crate::JournalOp;
+
+// Suffix of files set aside until a transaction commits
+const BACKUP_SUFFIX: &str = "journal";
+
+impl Journal {
+    pub(crate) fn new() -> Self {
+        Journal { ops: Vec::new() }
+    }
+
+    /// Rename a file or dir
+    pub fn rename(&mut self, from: String, to: String) -> Result<(), Error> {
+        debug!("rename {} -> {}", from, to);
+        if let Err(e) = fs::rename(&from, &to) {
+            return Err(Error::IOError { msg: e.to_string() });
+        }
+        self.ops.push(JournalOp::Rename { from, to });
+        Ok(())
+    }
+
+    /// Move a file aside; it is deleted on commit and restored on rollback
+    pub fn backup(&mut self, path: String) -> Result<(), Error> {
+        let backup = format!("{}.{}", path, BACKUP_SUFFIX);
+        debug!("backup {} -> {}", path, backup);
+        if let Err(e) = fs::rename(&path, &backup) {
+            return Err(Error::IOError { msg: e.to_string() });
+        }
+        self.ops.push(JournalOp::Backup { path, backup });
+        Ok(())
+    }
+
+    // Record a written file; a failed write may still leave a partial file behind
+    fn created(&mut self, path: String) {
+        if Path::new(&path).exists() {
+            self.ops.push(JournalOp::Create { path });
+        }
+    }
+
+    /// Create a new file; fails if it already exists
+    pub fn create(&mut self, path: String, contents: &[u8]) -> Result<(), Error> {
+        debug!("create {}", path);
+        if crate::exists(&path) {
+            return Err(Error::IOError {
+                msg: format!("{} already exists", path),
+            });
+        }
+        let res = fs::write(&path, contents);
+        self.created(path);
+        match res {
+            Ok(_) => Ok(()),
+            Err(e) => Err(Error::IOError { msg: e.to_string() }),
+        }
+    }
+
+    /// Write a file, backing up the existing one if any
+    /// a replaced file keeps its permissions
+    pub fn write(&mut self, path: String, contents: &[u8]) -> Result<(), Error> {
+        debug!("write {}", path);
+        let perms = match fs::metadata(&path) {
+            Ok(md) => {
+                self.backup(path.clone())?;
+                Some(md.permissions())
+            }
+            Err(_) => None,
+        };
+
+        let mut res = fs::write(&path, contents);
+        if let (Ok(_), Some(p)) = (&res, perms) {
+            res = fs::set_permissions(&path, p);
+        }
+
+        let display = path.clone();
+        self.created(path);
+        match res {
+            Ok(_) => Ok(()),
+            Err(e) => Err(Error::IOError {
+                msg: format!("{}: {}", display, e),
+            }),
+        }
+    }
+
+    /// Create a dir and any missing parents
+    pub fn create_dir(&mut self, path: String) -> Result<(), Error> {
+        // parents are created first, so collect them top down
+        let mut missing: Vec<String> = Path::new(&path)
+            .ancestors()
+            .take_while(|p| !p.as_os_str().is_empty() && !p.exists())
+            .map(|p| p.to_string_lossy().to_string())
+            .collect();
+        missing.reverse();
+
+        for dir in missing {
+            debug!("mkdir {}", dir);
+            if let Err(e) = fs::create_dir(&dir) {
+                return Err(Error::IOError {
+                    msg: format!("{}: {}", dir, e),
+                });
+            }
+            self.ops.push(JournalOp::CreateDir { path: dir });
+        }
+        Ok(())
+    }
+
+    /// Restore recorded metadata onto a file or dir; its current metadata is put back on rollback
+    pub fn set_meta(&mut self, path: String, meta: &FileMeta) -> Result<(), Error> {
+        debug!("set meta {}", path);
+        let previous = FileMeta::read(&path)?;
+        // recorded first, as a failed restore may have changed some of it
+        self.ops.push(JournalOp::Meta {
+            path: path.clone(),
+            previous,
+        });
+        meta.restore(&path)
+    }
+
+    /// Drop the backups once the transaction is committed
+    pub(crate) fn finish(self) {
+        for op in self.ops {
+            if let JournalOp::Backup { backup, .. } = op {
+                if let Err(e) = fs::remove_file(&backup) {
+                    warn!("Failed to remove {}: {}", backup, e);
+                }
+            }
+        }
+    }
+
+    /// Undo every recorded change, newest first
+    pub(crate) fn rollback(self) {
+        for op in self.ops.into_iter().rev() {
+            let res = match &op {
+                JournalOp::Rename { from, to } => fs::rename(to, from),
+                JournalOp::Backup { path, backup } => fs::rename(backup, path),
+                JournalOp::Create { path } => fs::remove_file(path),
+                JournalOp::CreateDir { path } => fs::remove_dir(path),
+                JournalOp::Meta { path, previous } => previous
+                    .restore(path)
+                    .map_err(|e| std::io::Error::other(e.to_string())),
+            };
+            if let Err(e) = res {
+                error!("Rollback step failed; bin may need repair: {}", e);
+            }
+        }
+    }
+}
@@ -1,13 +1,35 @@
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, TimeZone};
 use log::{error, info, warn};
+use rusqlite::Connection;
 use sha2::{Digest, Sha512};
 use snafu::{self, Snafu};
-use std::{collections::HashMap, fs, path::PathBuf};
+use std::{
+    cell::OnceCell,
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 mod bin;
+mod bin_config;
+mod codec;
 mod config;
+mod crypt;
 mod db;
+mod delta;
+mod info;
+mod journal;
+mod lock;
 mod map;
 pub mod mapper;
+mod meta;
+mod objects;
+mod pack;
+mod pivot;
+mod prune;
+mod repair;
+mod validate;
 mod version;
 
 #[derive(Debug, Snafu)]
@@ -19,9 +41,28 @@ pub enum Error {
     ParentsDontExist,
     #[snafu(display("Init Failed: {msg}"))]
     InitFailed { msg: String },
+    #[snafu(display(
+        "Bin schema {found} is newer than this client supports ({supported}); upgrade meld"
+    ))]
+    SchemaTooNew { found: u32, supported: u32 },
     // Bin Errors
+    #[snafu(display("Bin is locked by {holder}; use --wait to wait for it"))]
+    BinLocked { holder: String },
+    #[snafu(display("Invalid bin config: {msg}"))]
+    InvalidBinConfig { msg: String },
+    #[snafu(display("Bin info entry not defined: {key}"))]
+    BinfoNotFound { key: String },
+    #[snafu(display("Encryption key error: {msg}"))]
+    KeyError { msg: String },
     #[snafu(display("Map Update Not Needed"))]
     UpdateNotNeeded,
+    // Mapping Errors
+    #[snafu(display("Mapping variable not defined: {var}"))]
+    MappingNotFound { var: String },
+    #[snafu(display("Invalid mapping: {msg}"))]
+    InvalidMapping { msg: String },
+    #[snafu(display("Pivot Failed: {msg}"))]
+    PivotFailed { msg: String },
     //SQL Errors
     #[snafu(display("SQL Failed: {msg}"))]
     SQLError { msg: String },
@@ -32,19 +73,179 @@ pub enum Error {
     FileNotFound { msg: String },
     #[snafu(display("Tag Not Found: {msg}"))]
     TagNotFound { msg: String },
+    #[snafu(display("Invalid time: {msg}"))]
+    InvalidTime { msg: String },
+    #[snafu(display("No version of {msg} was pushed by then"))]
+    NoVersionAt { msg: String },
     #[snafu(display("Something unexpected happened"))]
     SomethingFailed,
+    // Status Errors
+    #[snafu(display("{count} configs have drifted from the bin"))]
+    DriftDetected { count: usize },
+    #[snafu(display("Bin failed validation with {count} issues"))]
+    ValidationFailed { count: usize },
+}
+
+impl Error {
+    /// Process exit code for the error
+    /// drift is reported separately so scripts can tell it apart from failures
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::DriftDetected { .. } => 2,
+            Error::ValidationFailed { .. } => 3,
+            _ => 1,
+        }
+    }
 }
 
 pub struct Database {
     path: PathBuf,
+    con: OnceCell<Connection>,
 }
 
 pub struct Bin {
     path: PathBuf,
     maps: PathBuf,
     blobs: PathBuf,
+    objects: PathBuf,
+    packs: PathBuf,
+    // packs are read on first use
+    pack_index: OnceCell<Vec<Pack>>,
     pub db: Database,
+    pub mapper: Mapper,
+    profile: Option<String>,
+    // settings from the bin's config.yml
+    config: BinConfig,
+    // age identity file; kept outside the bin
+    key: Option<PathBuf>,
+    // released when the bin is dropped, after the db is closed
+    lock: Option<Lock>,
+}
+
+/// Bin-wide settings, stored in the bin's config.yml
+/// Bin::new writes the defaults; keys missing from older bins take them too
+#[derive(Debug, Clone, PartialEq)]
+pub struct BinConfig {
+    pub name: String,
+    pub description: String,
+    // version of the config.yml format; newer formats are refused
+    pub format: u32,
+    // var -> prefix mappings seeded into a bin that has none
+    pub mappings: BTreeMap<String, String>,
+    pub hash: HashAlgorithm,
+    // codec new versions are stored with
+    pub compression: Codec,
+    // store new versions as deltas against the previous one, with a full
+    // snapshot every snapshot_every versions
+    pub delta: bool,
+    pub snapshot_every: usize,
+    // subsets whose configs are always encrypted
+    pub encrypt_subsets: Vec<String>,
+    // blobs can override it in their own config.yml
+    pub retention: Retention,
+    // files and dirs skipped when pushing or scanning dirs
+    pub ignore: Vec<glob::Pattern>,
+}
+
+/// Hash used to name objects, blobs and packs
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HashAlgorithm {
+    Sha512,
+}
+
+/// How a bin is locked while open
+/// any number of shared locks can be held at once; an exclusive lock is held alone
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LockMode {
+    Shared,
+    Exclusive,
+}
+
+/// What to do when the bin is locked by another process
+#[derive(Debug, Clone, Copy)]
+pub enum LockWait {
+    Never,
+    Forever,
+    For(Duration),
+}
+
+/// A held bin lock; the lock file is removed on drop
+pub struct Lock {
+    path: PathBuf,
+}
+
+/// Compression applied to a stored version's contents
+/// Delta objects are zstd patches against another object
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Codec {
+    None,
+    Zstd,
+    Gzip,
+    Delta,
+}
+
+/// A pack of objects consolidated into one file, and the pointers packed with them
+/// objects maps object file names to their offset and length in the pack
+pub struct Pack {
+    path: PathBuf,
+    objects: HashMap<String, (u64, u64)>,
+    pointers: HashMap<(String, u32), String>,
+}
+
+/// What a pack consolidated
+/// loose holds the number of loose files removed, replaced the number of old packs
+pub struct PackReport {
+    pub objects: usize,
+    pub pointers: usize,
+    pub loose: usize,
+    pub replaced: usize,
+}
+
+/// What meld info reports about a bin
+/// objects counts each stored object once, whether loose, packed or both
+pub struct BinInfo {
+    pub binfo: BTreeMap<String, String>,
+    pub configs: usize,
+    pub versions: usize,
+    pub maps: usize,
+    pub objects: usize,
+    pub packs: usize,
+    // bytes on disk, of the whole bin and of its loose objects and packs
+    pub size: u64,
+    pub stored_size: u64,
+}
+
+/// Which versions of a config prune keeps
+/// a version is kept if any rule keeps it; nothing is pruned unless keep_last or
+/// max_age is set, and the latest version is always kept
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Retention {
+    pub keep_last: Option<u32>,
+    // tagged versions are kept unless this is false
+    pub keep_tagged: Option<bool>,
+    // in seconds
+    pub max_age: Option<u64>,
+}
+
+/// What a prune removed, or would remove on a dry run
+pub struct PruneReport {
+    pub versions: Vec<(String, u32)>,
+    pub maps: Vec<(String, u32)>,
+    pub objects: Vec<String>,
+}
+
+/// A variable used to shorten real paths into portable map paths
+/// ie $HOME$ -> ${HOME}
+/// Mappings with a profile only apply on hosts using that profile
+pub struct Mapping {
+    pub var: String,
+    pub prefix: String,
+    pub profile: Option<String>,
+}
+
+/// Translates between real paths and map paths using the bin's mappings
+pub struct Mapper {
+    mappings: Vec<Mapping>,
 }
 
 pub struct Config {
@@ -55,14 +256,136 @@ pub struct Config {
     map_path: String,
     tag: String,
     hash: String,
+    // mode, ownership and mtime of the live file
+    meta: FileMeta,
+    // encrypt new versions when pushed
+    pub encrypt: bool,
+    // recorded on new versions when pushed
+    pub message: String,
     pub versions: HashMap<String, Version>,
 }
 
+/// A tracked config as stored in the db, with its latest version and tags
+pub struct ConfigSummary {
+    pub blob: String,
+    pub map_path: String,
+    pub subset: String,
+    pub family: String,
+    pub latest: u32,
+    pub tags: Vec<String>,
+    pub map_ver: Option<u32>,
+}
+
 pub struct Version {
     pub data_hash: String,
     pub ver: u32,
     pub tag: String,
     pub owner: String,
+    pub codec: Codec,
+    pub encrypted: bool,
+    // when the version was pushed, in seconds since the unix epoch, and by whom
+    pub created: i64,
+    pub hostname: String,
+    pub username: String,
+    // free text given with push -m
+    pub message: String,
+    // mode, ownership and mtime of the file that was pushed
+    pub meta: FileMeta,
+}
+
+/// Permission bits, ownership and modification time of a config file or dir
+/// versions pushed before these were recorded have a mode of 0
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FileMeta {
+    pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    // owner and group names on the pushing host; empty if the ids had none
+    pub user: String,
+    pub group: String,
+    // seconds since the unix epoch
+    pub mtime: i64,
+}
+
+/// A problem found while validating a bin
+pub enum Issue {
+    Schema {
+        msg: String,
+    },
+    HashMismatch {
+        blob: String,
+        ver: u32,
+    },
+    MissingBlob {
+        blob: String,
+        ver: u32,
+    },
+    MissingObject {
+        blob: String,
+        ver: u32,
+        hash: String,
+    },
+    CorruptObject {
+        hash: String,
+    },
+    CorruptPack {
+        pack: String,
+    },
+    OrphanObject {
+        hash: String,
+    },
+    OrphanBlob {
+        blob: String,
+    },
+    OrphanBlobFile {
+        blob: String,
+        file: String,
+    },
+    OrphanVersion {
+        blob: String,
+        ver: u32,
+    },
+    UnversionedConfig {
+        blob: String,
+    },
+    MissingMapFile {
+        blob: String,
+        ver: u32,
+    },
+    OrphanMapFile {
+        file: String,
+    },
+    InvalidBlobConfig {
+        blob: String,
+    },
+    BadMapEntry {
+        file: String,
+        entry: String,
+    },
+}
+
+/// What a repair was able to rebuild
+/// unrecovered holds config blobs whose map path could not be found
+pub struct RepairReport {
+    pub versions: usize,
+    pub maps: usize,
+    pub configs: usize,
+    pub quarantined: Vec<String>,
+    pub unrecovered: Vec<String>,
+}
+
+/// A filesystem change made inside a transaction
+enum JournalOp {
+    Create { path: String },
+    CreateDir { path: String },
+    Rename { from: String, to: String },
+    Backup { path: String, backup: String },
+    Meta { path: String, previous: FileMeta },
+}
+
+/// Filesystem changes made during a transaction; undone in reverse if it fails
+pub struct Journal {
+    ops: Vec<JournalOp>,
 }
 
 pub struct Map {
@@ -70,6 +393,12 @@ pub struct Map {
     pub ver: u32,
     pub hash: String,
     pub tag: String,
+    // when the snapshot was pushed, in seconds since the unix epoch, and by whom
+    pub created: i64,
+    pub hostname: String,
+    pub username: String,
+    // free text given with push -m
+    pub message: String,
     pub configs: Vec<Config>,
 }
 
@@ -83,8 +412,68 @@ pub fn is_dir(path: &str) -> Result<bool, Error> {
     }
 }
 
+// Seconds since the unix epoch
+pub fn now() -> i64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs() as i64,
+        Err(_) => 0,
+    }
+}
+
+// Parse a local date and time into seconds since the unix epoch
+// accepts RFC 3339, "YYYY-MM-DD HH:MM[:SS]" and "YYYY-MM-DD"; a bare date means the end of that day
+pub fn parse_time(s: &str) -> Result<i64, Error> {
+    if let Ok(t) = DateTime::parse_from_rfc3339(s) {
+        return Ok(t.timestamp());
+    }
+
+    let date_time = [
+        "%Y-%m-%d %H:%M:%S",
+        "%Y-%m-%d %H:%M",
+        "%Y-%m-%dT%H:%M:%S",
+        "%Y-%m-%dT%H:%M",
+    ]
+    .iter()
+    .find_map(|f| NaiveDateTime::parse_from_str(s, f).ok())
+    .or_else(|| {
+        NaiveDate::parse_from_str(s, "%Y-%m-%d")
+            .ok()
+            .and_then(|d| d.and_hms_opt(23, 59, 59))
+    });
+
+    match date_time.and_then(|t| Local.from_local_datetime(&t).earliest()) {
+        Some(t) => Ok(t.timestamp()),
+        None => Err(Error::InvalidTime { msg: s.to_string() }),
+    }
+}
+
+// When a file was last modified, in seconds since the unix epoch; now if it can't be read
+pub(crate) fn modified(path: &PathBuf) -> i64 {
+    let modified = fs::metadata(path).and_then(|md| md.modified());
+    match modified.map(|m| m.duration_since(UNIX_EPOCH)) {
+        Ok(Ok(d)) => d.as_secs() as i64,
+        _ => now(),
+    }
+}
+
+// Name of the current host; used to select the default mapping profile
+pub fn hostname() -> String {
+    gethostname::gethostname().to_string_lossy().to_string()
+}
+
+// Name of the current user; the uid if the environment does not name one
+pub fn username() -> String {
+    for var in ["USER", "LOGNAME"] {
+        match std::env::var(var) {
+            Ok(u) if !u.is_empty() => return u,
+            _ => {}
+        }
+    }
+    unsafe { libc::getuid() }.to_string()
+}
+
 pub fn exists(path: &String) -> bool {
-    return fs::metadata(path).is_ok();
+    fs::metadata(path).is_ok()
 }
 
 // SHA512 of mapped file name
@@ -95,14 +484,58 @@ pub fn hash_path(path: &str) -> String {
     format!("{:x}", hasher.finalize())
 }
 
+// SHA512 hash of in memory contents
+pub fn hash_bytes(data: &[u8]) -> String {
+    let mut hasher = Sha512::new();
+    hasher.update(data);
+    format!("{:x}", hasher.finalize())
+}
+
 // SHA512 hash of file contents
 pub fn hash_contents(path: &str) -> Result<String, Error> {
     if is_dir(path)? {
         warn!("not hashing {}. dir", path);
         return Ok(String::from("DIR"));
     }
-    let mut file = fs::File::open(path).unwrap();
+    let mut file = match fs::File::open(path) {
+        Ok(f) => f,
+        Err(e) => {
+            return Err(Error::IOError {
+                msg: format!("{}: {}", path, e),
+            })
+        }
+    };
     let mut hasher = Sha512::new();
-    std::io::copy(&mut file, &mut hasher).unwrap();
+    if let Err(e) = std::io::copy(&mut file, &mut hasher) {
+        return Err(Error::IOError {
+            msg: format!("{}: {}", path, e),
+        });
+    }
     Ok(format!("{:x}", hasher.finalize()))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::parse_time;
+
+    #[test]
+    fn times_parse() {
+        assert_eq!(parse_time("2024-05-01T12:00:00Z").unwrap(), 1714564800);
+        assert_eq!(parse_time("2024-05-01T12:00:00+02:00").unwrap(), 1714557600);
+
+        // local times, in any of the accepted forms
+        let noon = parse_time("2024-05-01 12:00").unwrap();
+        assert_eq!(parse_time("2024-05-01T12:00").unwrap(), noon);
+        assert_eq!(parse_time("2024-05-01 12:00:00").unwrap(), noon);
+        assert_eq!(parse_time("2024-05-01 12:00:30").unwrap(), noon + 30);
+        // a bare date is the end of the day
+        assert_eq!(
+            parse_time("2024-05-01").unwrap(),
+            parse_time("2024-05-01 23:59:59").unwrap()
+        );
+
+        for bad in ["yesterday", "2024-13-01", "2024-05-01 25:00", ""] {
+            assert!(parse_time(bad).is_err(), "{}", bad);
+        }
+    }
+}
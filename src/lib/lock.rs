@@ -0,0 +1,321 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicU64, Ordering},
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use log::{debug, info, warn};
+
+use crate::Error;
+use crate::Lock;
+use crate::LockMode;
+use crate::LockWait;
+
+pub(crate) const LOCK_FILE: &str = "meld.lock";
+pub(crate) const READERS_DIR: &str = "locks";
+
+// How often a waiting process checks the lock again
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+// Shared locks taken by this process so far; part of each reader file name
+static READERS_TAKEN: AtomicU64 = AtomicU64::new(0);
+
+// Name of a new reader file, unique to this acquire
+// the count separates shared locks held at once by one process, and the time separates
+// processes that reuse a pid under the same hostname, as containers may
+fn reader_name(holder: &Holder) -> String {
+    let count = READERS_TAKEN.fetch_add(1, Ordering::Relaxed);
+    let nanos = match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_nanos(),
+        Err(_) => 0,
+    };
+    format!("{}@{}.{}-{}", holder.pid, holder.host, nanos, count)
+}
+
+/// The process named in a lock file
+struct Holder {
+    pid: u32,
+    host: String,
+}
+
+impl Holder {
+    fn current() -> Self {
+        Holder {
+            pid: std::process::id(),
+            host: crate::hostname(),
+        }
+    }
+
+    fn read(path: &Path) -> Option<Self> {
+        let contents = fs::read_to_string(path).ok()?;
+        let (pid, host) = contents.trim().split_once(' ')?;
+        Some(Holder {
+            pid: pid.parse().ok()?,
+            host: host.to_string(),
+        })
+    }
+
+    fn describe(&self) -> String {
+        format!("pid {} on {}", self.pid, self.host)
+    }
+
+    /// A lock is stale if its process is gone; only checkable on the same host
+    fn is_stale(&self) -> bool {
+        if self.host != crate::hostname() {
+            return false;
+        }
+        // signal 0 only checks the process exists
+        let res = unsafe { libc::kill(self.pid as libc::pid_t, 0) };
+        res != 0 && std::io::Error::last_os_error().raw_os_error() == Some(libc::ESRCH)
+    }
+}
+
+// Name in the bin root for a file set aside from a lock file, unique to this process
+// kept out of locks/ so it is never taken for a reader
+fn aside(root: &Path, path: &Path, suffix: &str) -> PathBuf {
+    let name = match path.file_name() {
+        Some(n) => n.to_string_lossy().to_string(),
+        None => String::new(),
+    };
+    root.join(format!("{}.{}.{}", name, std::process::id(), suffix))
+}
+
+/// Remove a lock file if its holder is gone; returns true if removed
+/// The file is renamed aside before it is removed, so only one process takes over a stale
+/// lock; if a live lock replaced it before the rename, that lock is put back
+fn clear_stale(root: &Path, path: &Path) -> bool {
+    match Holder::read(path) {
+        Some(h) if h.is_stale() => {}
+        _ => return false,
+    }
+
+    let stale = aside(root, path, "stale");
+    if fs::rename(path, &stale).is_err() {
+        // another process took it over first
+        return false;
+    }
+
+    let removed = match Holder::read(&stale) {
+        Some(h) if h.is_stale() => {
+            warn!("Removing stale lock held by {}", h.describe());
+            true
+        }
+        _ => {
+            if let Err(e) = fs::hard_link(&stale, path) {
+                warn!("Failed to put back lock {:?}: {}", path, e);
+            }
+            false
+        }
+    };
+    let _ = fs::remove_file(&stale);
+    removed
+}
+
+/// Create a lock file only if it does not exist
+/// the holder is written to a temp file in the bin root first and linked into place,
+/// so the lock file is never seen empty
+fn try_create(root: &Path, path: &Path) -> Result<bool, Error> {
+    let holder = Holder::current();
+    let tmp = aside(root, path, "tmp");
+
+    if let Err(e) = fs::write(&tmp, format!("{} {}\n", holder.pid, holder.host)) {
+        return Err(Error::IOError {
+            msg: format!("{:?}: {}", tmp, e),
+        });
+    }
+
+    let res = fs::hard_link(&tmp, path);
+    let _ = fs::remove_file(&tmp);
+
+    match res {
+        Ok(_) => Ok(true),
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => Ok(false),
+        Err(e) => Err(Error::IOError {
+            msg: format!("{:?}: {}", path, e),
+        }),
+    }
+}
+
+/// First live reader lock, if any
+fn live_reader(root: &Path, dir: &Path) -> Option<Holder> {
+    let entries = fs::read_dir(dir).ok()?;
+    for e in entries.flatten() {
+        let path = e.path();
+        if clear_stale(root, &path) {
+            continue;
+        }
+        if let Some(h) = Holder::read(&path) {
+            return Some(h);
+        }
+    }
+    None
+}
+
+/// Tracks how long to keep waiting for a lock
+struct Waiter {
+    wait: LockWait,
+    start: Instant,
+    announced: bool,
+}
+
+impl Waiter {
+    /// Sleep before trying again, or fail if the wait policy is used up
+    fn wait(&mut self, holder: Option<Holder>) -> Result<(), Error> {
+        let holder = match holder {
+            Some(h) => h.describe(),
+            None => "another process".to_string(),
+        };
+
+        let expired = match self.wait {
+            LockWait::Never => true,
+            LockWait::Forever => false,
+            LockWait::For(d) => self.start.elapsed() >= d,
+        };
+        if expired {
+            return Err(Error::BinLocked { holder });
+        }
+
+        if !self.announced {
+            warn!("Waiting for bin lock held by {}", holder);
+            self.announced = true;
+        }
+        thread::sleep(POLL_INTERVAL);
+        Ok(())
+    }
+}
+
+impl Lock {
+    /// Lock the bin at root
+    /// Exclusive locks own meld.lock and wait for readers to finish; shared locks
+    /// each add a file to locks/ and wait while meld.lock exists
+    pub(crate) fn acquire(root: &Path, mode: LockMode, wait: LockWait) -> Result<Self, Error> {
+        let lock_file = root.join(LOCK_FILE);
+        let readers = root.join(READERS_DIR);
+        let mut waiter = Waiter {
+            wait,
+            start: Instant::now(),
+            announced: false,
+        };
+
+        if let Err(e) = fs::create_dir_all(&readers) {
+            return Err(Error::IOError {
+                msg: format!("{:?}: {}", readers, e),
+            });
+        }
+
+        match mode {
+            LockMode::Exclusive => {
+                while !try_create(root, &lock_file)? {
+                    if !clear_stale(root, &lock_file) {
+                        waiter.wait(Holder::read(&lock_file))?;
+                    }
+                }
+
+                // new readers now back off; wait for the current ones to leave
+                let lock = Lock { path: lock_file };
+                while let Some(h) = live_reader(root, &readers) {
+                    // dropping the lock on failure releases meld.lock
+                    waiter.wait(Some(h))?;
+                }
+
+                debug!("Holding exclusive lock");
+                Ok(lock)
+            }
+            LockMode::Shared => {
+                let holder = Holder::current();
+
+                loop {
+                    if lock_file.exists() {
+                        if !clear_stale(root, &lock_file) {
+                            waiter.wait(Holder::read(&lock_file))?;
+                        }
+                        continue;
+                    }
+
+                    let reader = readers.join(reader_name(&holder));
+                    if !try_create(root, &reader)? {
+                        continue;
+                    }
+
+                    // a writer may have locked between the check and the create
+                    if lock_file.exists() {
+                        let _ = fs::remove_file(&reader);
+                        continue;
+                    }
+
+                    debug!("Holding shared lock");
+                    return Ok(Lock { path: reader });
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        info!("Releasing lock {:?}", self.path);
+        if let Err(e) = fs::remove_file(&self.path) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                warn!("Failed to release lock {:?}: {}", self.path, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::{clear_stale, LOCK_FILE, READERS_DIR};
+    use crate::{Lock, LockMode, LockWait};
+
+    // No process has this pid, as it is above any pid_max
+    const GONE: u32 = i32::MAX as u32;
+
+    #[test]
+    fn stale_locks_are_taken_over() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let host = crate::hostname();
+        fs::create_dir_all(root.join(READERS_DIR)).unwrap();
+        fs::write(root.join(LOCK_FILE), format!("{} {}\n", GONE, host)).unwrap();
+        let reader = root.join(READERS_DIR).join(format!("{}@{}", GONE, host));
+        fs::write(&reader, format!("{} {}\n", GONE, host)).unwrap();
+
+        let lock = Lock::acquire(root, LockMode::Exclusive, LockWait::Never).unwrap();
+        assert!(!reader.exists());
+        let held = fs::read_to_string(root.join(LOCK_FILE)).unwrap();
+        assert_eq!(held, format!("{} {}\n", std::process::id(), host));
+
+        // a live lock is never cleared, and nothing is left aside
+        assert!(!clear_stale(root, &root.join(LOCK_FILE)));
+        drop(lock);
+        let mut left: Vec<String> = fs::read_dir(root)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        left.sort();
+        assert_eq!(left, vec![READERS_DIR]);
+        assert_eq!(fs::read_dir(root.join(READERS_DIR)).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn shared_locks_nest() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        let readers = || fs::read_dir(root.join(READERS_DIR)).unwrap().count();
+
+        let outer = Lock::acquire(root, LockMode::Shared, LockWait::Never).unwrap();
+        let inner = Lock::acquire(root, LockMode::Shared, LockWait::Never).unwrap();
+        assert_ne!(outer.path, inner.path);
+        assert_eq!(readers(), 2);
+
+        drop(inner);
+        assert_eq!(readers(), 1);
+        assert!(Lock::acquire(root, LockMode::Exclusive, LockWait::Never).is_err());
+        drop(outer);
+        assert!(Lock::acquire(root, LockMode::Exclusive, LockWait::Never).is_ok());
+    }
+}
@@ -1,12 +1,13 @@
 use log::info;
 use sha2::{Digest, Sha512};
-use walkdir::WalkDir;
 
 use crate::hash_path;
-use crate::mapper;
+use crate::Bin;
+use crate::BinConfig;
 use crate::Config;
 use crate::Error;
 use crate::Map;
+use crate::Mapper;
 
 impl Map {
     // Getters
@@ -16,30 +17,26 @@ impl Map {
 
     /// Add vec of configs to the map
     fn build_configs(
+        mapper: &Mapper,
+        bin_config: &BinConfig,
         path: &str,
         subset: String,
         family: String,
-        tag: &String,
+        tag: &str,
     ) -> Result<Vec<Config>, Error> {
         let mut configs: Vec<Config> = Vec::new();
 
-        for entity in WalkDir::new(path) {
-            match entity {
-                Ok(e) => {
-                    let map_path =
-                        mapper::real_path_to_map(&e.path().to_str().unwrap().to_string())?;
-                    configs.push(Config::from(
-                        e.path().to_str().unwrap().to_string(),
-                        map_path,
-                        subset.clone(),
-                        family.clone(),
-                        tag.clone(),
-                    )?);
-                }
-                Err(_) => (),
-            }
+        for real_path in bin_config.walk_dir(mapper, path) {
+            let map_path = mapper.real_path_to_map(&real_path)?;
+            configs.push(Config::from(
+                real_path,
+                map_path,
+                subset.clone(),
+                family.clone(),
+                tag.to_string(),
+            )?);
         }
-        return Ok(configs);
+        Ok(configs)
     }
 
     /// Calculate the hash of all blobs in the vec
@@ -53,22 +50,49 @@ impl Map {
     }
 
     /// Create a Map from a path and arguments
-    pub fn new(path: &String, subset: String, family: String, tag: String) -> Result<Self, Error> {
+    /// files and dirs the bin config ignores are left out
+    pub fn new(
+        mapper: &Mapper,
+        bin_config: &BinConfig,
+        path: &String,
+        subset: String,
+        family: String,
+        tag: String,
+    ) -> Result<Self, Error> {
         info!("Building map for {}", path);
 
         let clean_path = path_clean::clean(path);
 
         // generate variables for the new map
-        let map_blob = hash_path(&clean_path);
-        let config_vec = Map::build_configs(&clean_path, subset, family, &tag)?;
+        // the map blob is named by the mapped dir so it is portable across systems
+        let map_blob = hash_path(&mapper.real_path_to_map(&clean_path)?);
+        let config_vec = Map::build_configs(mapper, bin_config, &clean_path, subset, family, &tag)?;
         let map_hash = Map::get_map_hash(&config_vec);
 
-        return Ok(Map {
+        Ok(Map {
             blob: map_blob,
             ver: 0,
             hash: map_hash,
-            tag: tag,
+            tag,
+            created: crate::now(),
+            hostname: crate::hostname(),
+            username: crate::username(),
+            message: String::new(),
             configs: config_vec,
-        });
+        })
+    }
+}
+
+impl Bin {
+    /// Hash a map's configs as their versions record them
+    /// configs stored encrypted count by their keyed hash, as the plain one is not recorded
+    pub fn recorded_map_hash(&self, configs: &[Config]) -> Result<String, Error> {
+        let mut hasher = Sha512::new();
+        for c in configs {
+            let latest = self.db.get_current_version(c.get_blob())?;
+            let encrypt = self.must_encrypt(c, &latest)?;
+            hasher.update(self.recorded_hash(c.get_hash(), encrypt)?);
+        }
+        Ok(format!("{:x}", hasher.finalize()))
     }
 }
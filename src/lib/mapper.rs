@@ -1,29 +1,340 @@
-use std::fs;
+use std::{env, fs, path::PathBuf};
 
-use log::info;
+use log::{debug, info, warn};
 
 use crate::Error;
+use crate::Mapper;
+use crate::Mapping;
 
-// TODO: actually do file mapping
-pub fn real_path_to_map(path: &String) -> Result<String, Error> {
-    // if the file doesnt exist, it cannot be cannonicalized
-    // make a best guess, strip, and look for it based in the current folder
-    if !crate::exists(path) {
-        let clean = path_clean::clean(path);
-        info!("File does not exist: best guess - {}", clean);
-        return Ok(clean);
+/// Mappings created in every new bin
+pub const DEFAULT_MAPPINGS: [(&str, &str); 1] = [("$HOME$", "${HOME}")];
+
+impl Mapping {
+    /// Create a Mapping, validating the variable name and prefix
+    pub fn new(var: String, prefix: String, profile: Option<String>) -> Result<Self, Error> {
+        let mapping = Mapping {
+            var,
+            prefix,
+            profile,
+        };
+        mapping.validate()?;
+        Ok(mapping)
     }
 
-    info!("mapping: {}", path);
-    //return Ok(path_clean::clean(&path));
-    return Ok(fs::canonicalize(&path)
-        .unwrap()
-        .to_str()
-        .unwrap()
-        .to_string());
+    /// Variables are wrapped in '$' and cannot contain path separators
+    pub fn validate(&self) -> Result<(), Error> {
+        let inner = match self.var.strip_prefix('$').and_then(|v| v.strip_suffix('$')) {
+            Some(i) => i,
+            None => {
+                return Err(Error::InvalidMapping {
+                    msg: format!("{} must be wrapped in '$' (ie $HOME$)", self.var),
+                })
+            }
+        };
+
+        if inner.is_empty() || inner.contains('$') || inner.contains('/') {
+            return Err(Error::InvalidMapping {
+                msg: format!("{} is not a valid variable name", self.var),
+            });
+        }
+
+        if let Some(p) = &self.profile {
+            if p.is_empty() {
+                return Err(Error::InvalidMapping {
+                    msg: "profile names cannot be empty".to_string(),
+                });
+            }
+        }
+
+        if self.prefix.is_empty() {
+            return Err(Error::InvalidMapping {
+                msg: format!("{} has an empty prefix", self.var),
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Resolve the prefix on this system
+    /// ${NAME} references are expanded from the environment
+    pub fn resolve(&self) -> Option<String> {
+        let mut resolved = String::new();
+        let mut rest = self.prefix.as_str();
+
+        while let Some(start) = rest.find("${") {
+            resolved.push_str(&rest[..start]);
+            let end = match rest[start..].find('}') {
+                Some(e) => start + e,
+                None => {
+                    warn!("Unterminated env reference in {}", self.prefix);
+                    return None;
+                }
+            };
+            match env::var(&rest[start + 2..end]) {
+                Ok(v) => resolved.push_str(&v),
+                Err(_) => {
+                    debug!("{} not set; skipping {}", &rest[start + 2..end], self.var);
+                    return None;
+                }
+            }
+            rest = &rest[end + 1..];
+        }
+        resolved.push_str(rest);
+
+        // prefixes are matched on whole components; drop trailing separators
+        let trimmed = resolved.trim_end_matches('/');
+        if trimmed.is_empty() {
+            return None;
+        }
+
+        // resolve symlinks so prefixes match canonicalized configs
+        match fs::canonicalize(trimmed) {
+            Ok(p) => p.to_str().map(|s| s.to_string()),
+            Err(_) => Some(trimmed.to_string()),
+        }
+    }
+}
+
+impl Mapper {
+    pub fn new(mappings: Vec<Mapping>) -> Self {
+        Mapper { mappings }
+    }
+
+    /// Build a mapper from the bin-wide mappings with a profile layered on top
+    /// profile mappings replace bin-wide mappings with the same variable
+    pub fn with_profile(mut mappings: Vec<Mapping>, profile: Vec<Mapping>) -> Self {
+        for p in profile {
+            mappings.retain(|m| m.var != p.var);
+            mappings.push(p);
+        }
+        Mapper { mappings }
+    }
+
+    // Getters
+    pub fn get_mappings(&self) -> &Vec<Mapping> {
+        &self.mappings
+    }
+
+    /// Convert a (possibly relative) path into a clean absolute path
+    fn absolute_path(path: &String) -> Result<String, Error> {
+        if crate::exists(path) {
+            return match fs::canonicalize(path) {
+                Ok(p) => match p.to_str() {
+                    Some(s) => Ok(s.to_string()),
+                    None => Err(Error::IOError {
+                        msg: format!("{} is not valid unicode", path),
+                    }),
+                },
+                Err(e) => Err(Error::IOError { msg: e.to_string() }),
+            };
+        }
+
+        // if the file doesnt exist, it cannot be cannonicalized
+        // clean it from the current folder, then canonicalize the deepest dir that exists so
+        // it matches prefixes resolved through symlinks
+        let joined = match env::current_dir() {
+            Ok(cwd) => cwd.join(path),
+            Err(e) => return Err(Error::IOError { msg: e.to_string() }),
+        };
+        let clean = PathBuf::from(path_clean::clean(joined.to_str().unwrap_or(path)));
+
+        let mut missing = Vec::new();
+        let mut existing = clean.as_path();
+        while !existing.exists() {
+            match (existing.parent(), existing.file_name()) {
+                (Some(parent), Some(name)) => {
+                    missing.push(name);
+                    existing = parent;
+                }
+                _ => break,
+            }
+        }
+        let mut real = fs::canonicalize(existing).unwrap_or_else(|_| existing.to_path_buf());
+        real.extend(missing.iter().rev());
+
+        let guess = real.to_string_lossy().to_string();
+        info!("File does not exist: best guess - {}", guess);
+        Ok(guess)
+    }
+
+    /// Map a real path to its stored form using the longest matching prefix
+    /// /home/icon/.config/i3/config -> $HOME$/.config/i3/config
+    pub fn real_path_to_map(&self, path: &String) -> Result<String, Error> {
+        let real_path = Mapper::absolute_path(path)?;
+        info!("mapping: {}", real_path);
+
+        let mut best: Option<(&Mapping, String)> = None;
+        for m in &self.mappings {
+            let prefix = match m.resolve() {
+                Some(p) => p,
+                None => continue,
+            };
+
+            let matches = real_path == prefix
+                || (real_path.starts_with(&prefix) && real_path[prefix.len()..].starts_with('/'));
+
+            let longer = match &best {
+                Some((_, p)) => prefix.len() > p.len(),
+                None => true,
+            };
+
+            if matches && longer {
+                best = Some((m, prefix));
+            }
+        }
+
+        Ok(match best {
+            Some((m, prefix)) => {
+                let map_path = format!("{}{}", m.var, &real_path[prefix.len()..]);
+                debug!("{} -> {}", real_path, map_path);
+                map_path
+            }
+            None => real_path,
+        })
+    }
+
+    /// Resolve a stored map path back to a path on this system
+    /// $HOME$/.config/i3/config -> /home/icon/.config/i3/config
+    pub fn map_to_real_path(&self, path: &String) -> Result<String, Error> {
+        // unmapped paths are stored as-is
+        if !path.starts_with('$') {
+            return Ok(path.to_string());
+        }
+
+        let var = match path[1..].find('$') {
+            Some(end) => &path[..end + 2],
+            None => {
+                return Err(Error::InvalidMapping {
+                    msg: format!("{} has an unterminated variable", path),
+                })
+            }
+        };
+
+        let mapping = match self.mappings.iter().find(|m| m.var == var) {
+            Some(m) => m,
+            None => {
+                return Err(Error::MappingNotFound {
+                    var: var.to_string(),
+                })
+            }
+        };
+
+        let prefix = match mapping.resolve() {
+            Some(p) => p,
+            None => {
+                return Err(Error::MappingNotFound {
+                    var: format!("{} ({} does not resolve)", var, mapping.prefix),
+                })
+            }
+        };
+
+        let real_path = format!("{}{}", prefix, &path[var.len()..]);
+        debug!("{} -> {}", path, real_path);
+
+        Ok(real_path)
+    }
 }
 
-// TODO: actually do file mapping
-pub fn map_to_real_path(path: &String) -> Result<String, Error> {
-    Ok(path.to_string())
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::{Bin, LockMode, LockWait, Mapper, Mapping};
+
+    fn mapping(var: &str, prefix: &str, profile: Option<&str>) -> Mapping {
+        Mapping::new(
+            var.to_string(),
+            prefix.to_string(),
+            profile.map(|p| p.to_string()),
+        )
+        .unwrap()
+    }
+
+    fn real(dir: &tempfile::TempDir) -> String {
+        dir.path()
+            .canonicalize()
+            .unwrap()
+            .to_string_lossy()
+            .to_string()
+    }
+
+    #[test]
+    fn longest_prefix_wins() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = real(&dir);
+        fs::create_dir_all(format!("{}/home/.config", root)).unwrap();
+
+        let mapper = Mapper::new(vec![
+            mapping("$HOME$", &format!("{}/home", root), None),
+            mapping("$CFG$", &format!("{}/home/.config/", root), None),
+        ]);
+
+        let map = |p: &str| mapper.real_path_to_map(&format!("{}/{}", root, p)).unwrap();
+        assert_eq!(map("home/.config/i3/config"), "$CFG$/i3/config");
+        assert_eq!(map("home/.config"), "$CFG$");
+        assert_eq!(map("home/.bashrc"), "$HOME$/.bashrc");
+        // prefixes match whole components only
+        assert_eq!(map("home/.configs"), "$HOME$/.configs");
+        assert_eq!(map("homework"), format!("{}/homework", root));
+
+        let back = mapper
+            .map_to_real_path(&"$CFG$/i3/config".to_string())
+            .unwrap();
+        assert_eq!(back, format!("{}/home/.config/i3/config", root));
+        assert!(mapper.map_to_real_path(&"$NONE$/x".to_string()).is_err());
+    }
+
+    #[test]
+    fn profile_replaces_same_variable() {
+        let mapper = Mapper::with_profile(
+            vec![
+                mapping("$HOME$", "/home/a", None),
+                mapping("$ETC$", "/etc", None),
+            ],
+            vec![mapping("$HOME$", "/Users/a", Some("mac"))],
+        );
+
+        let vars: Vec<(&str, &str)> = mapper
+            .get_mappings()
+            .iter()
+            .map(|m| (m.var.as_str(), m.prefix.as_str()))
+            .collect();
+        assert_eq!(vars, vec![("$ETC$", "/etc"), ("$HOME$", "/Users/a")]);
+
+        let back = mapper
+            .map_to_real_path(&"$HOME$/.vimrc".to_string())
+            .unwrap();
+        assert_eq!(back, "/Users/a/.vimrc");
+    }
+
+    #[test]
+    fn bin_opens_with_profile() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = real(&dir);
+        let bin_path = format!("{}/bin", root);
+
+        let bin = Bin::new(bin_path.clone(), None, false, false, LockWait::Never).unwrap();
+        bin.db
+            .add_mapping(&mapping("$T$", "/bin-wide", None))
+            .unwrap();
+        bin.db
+            .add_mapping(&mapping("$T$", "/work", Some("work")))
+            .unwrap();
+        drop(bin);
+
+        let open = |profile: Option<&str>| {
+            let bin = Bin::from(
+                bin_path.clone(),
+                profile.map(|p| p.to_string()),
+                LockMode::Shared,
+                LockWait::Never,
+            )
+            .unwrap();
+            bin.mapper.map_to_real_path(&"$T$/x".to_string()).unwrap()
+        };
+        assert_eq!(open(None), "/bin-wide/x");
+        assert_eq!(open(Some("work")), "/work/x");
+        // a profile without mappings falls back to the bin-wide ones
+        assert_eq!(open(Some("other")), "/bin-wide/x");
+    }
 }
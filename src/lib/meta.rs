@@ -0,0 +1,259 @@
+use std::{
+    ffi::{CStr, CString},
+    fs,
+    os::unix::fs::{chown, MetadataExt, PermissionsExt},
+    time::{Duration, UNIX_EPOCH},
+};
+
+use log::{debug, warn};
+
+use crate::Error;
+use crate::FileMeta;
+
+// Permission bits of a mode, including setuid, setgid and sticky
+const PERMISSION_BITS: u32 = 0o7777;
+
+// Dropped when the recorded owner can not be restored
+const SET_ID_BITS: u32 = 0o6000;
+
+// Name of a user id on this host; empty if it has none
+fn user_name(uid: u32) -> String {
+    let pw = unsafe { libc::getpwuid(uid) };
+    if pw.is_null() {
+        return String::new();
+    }
+    unsafe { CStr::from_ptr((*pw).pw_name) }
+        .to_string_lossy()
+        .to_string()
+}
+
+// Name of a group id on this host; empty if it has none
+fn group_name(gid: u32) -> String {
+    let gr = unsafe { libc::getgrgid(gid) };
+    if gr.is_null() {
+        return String::new();
+    }
+    unsafe { CStr::from_ptr((*gr).gr_name) }
+        .to_string_lossy()
+        .to_string()
+}
+
+// Id of a named user on this host
+fn user_id(name: &str) -> Option<u32> {
+    let name = CString::new(name).ok().filter(|n| !n.is_empty())?;
+    let pw = unsafe { libc::getpwnam(name.as_ptr()) };
+    if pw.is_null() {
+        return None;
+    }
+    Some(unsafe { (*pw).pw_uid })
+}
+
+// Id of a named group on this host
+fn group_id(name: &str) -> Option<u32> {
+    let name = CString::new(name).ok().filter(|n| !n.is_empty())?;
+    let gr = unsafe { libc::getgrnam(name.as_ptr()) };
+    if gr.is_null() {
+        return None;
+    }
+    Some(unsafe { (*gr).gr_gid })
+}
+
+impl FileMeta {
+    /// Read the metadata of a file or dir, following links
+    /// the mode is kept whole, so it is never 0 for a real file
+    pub fn read(path: &str) -> Result<Self, Error> {
+        let md = match fs::metadata(path) {
+            Ok(md) => md,
+            Err(e) => {
+                return Err(Error::IOError {
+                    msg: format!("{}: {}", path, e),
+                })
+            }
+        };
+
+        Ok(FileMeta {
+            mode: md.mode(),
+            uid: md.uid(),
+            gid: md.gid(),
+            user: user_name(md.uid()),
+            group: group_name(md.gid()),
+            mtime: md.mtime(),
+        })
+    }
+
+    /// Check the metadata was recorded; versions pushed before it was are not
+    pub fn is_known(&self) -> bool {
+        self.mode != 0
+    }
+
+    /// Permission bits of the mode
+    pub fn permissions(&self) -> u32 {
+        self.mode & PERMISSION_BITS
+    }
+
+    // Local ids of the recorded owner and group, looked up by name since ids differ across
+    // hosts; those that do not exist here are None, unless running as root, which can set
+    // any id and so falls back to the recorded one
+    fn local_owner(&self) -> (Option<u32>, Option<u32>) {
+        let root = unsafe { libc::geteuid() } == 0;
+        let uid = match user_id(&self.user) {
+            Some(id) => Some(id),
+            None if root || (self.user.is_empty() && !user_name(self.uid).is_empty()) => {
+                Some(self.uid)
+            }
+            None => None,
+        };
+        let gid = match group_id(&self.group) {
+            Some(id) => Some(id),
+            None if root || (self.group.is_empty() && !group_name(self.gid).is_empty()) => {
+                Some(self.gid)
+            }
+            None => None,
+        };
+        (uid, gid)
+    }
+
+    /// Check the permissions or ownership of a live file differ from this recorded metadata
+    /// Only permission bits are compared, and owners only where they exist on this host or
+    /// when running as root; setuid and setgid are ignored when the owner is not compared.
+    /// Unrecorded metadata never differs; mtimes are restored but not compared
+    pub fn differs(&self, live: &FileMeta) -> bool {
+        if !self.is_known() {
+            return false;
+        }
+
+        let (uid, gid) = self.local_owner();
+        let mut bits = PERMISSION_BITS;
+        if uid.is_none() || gid.is_none() {
+            bits &= !SET_ID_BITS;
+        }
+
+        self.mode & bits != live.mode & bits
+            || uid.is_some_and(|u| u != live.uid)
+            || gid.is_some_and(|g| g != live.gid)
+    }
+
+    /// Restore the recorded metadata onto a file or dir
+    /// Owners are looked up by name, falling back to the recorded ids. If they do not exist
+    /// here or can not be set, as when not running as root, the file keeps its owner and
+    /// drops setuid and setgid
+    pub fn restore(&self, path: &str) -> Result<(), Error> {
+        if !self.is_known() {
+            return Ok(());
+        }
+        debug!(
+            "Restoring {:o} {}:{} on {}",
+            self.mode, self.user, self.group, path
+        );
+
+        // set first, as the restored mode may not allow opening the file
+        if self.mtime > 0 {
+            let mtime = UNIX_EPOCH + Duration::from_secs(self.mtime as u64);
+            if let Err(e) = fs::File::open(path).and_then(|f| f.set_modified(mtime)) {
+                warn!("Could not set mtime of {}: {}", path, e);
+            }
+        }
+
+        let (uid, gid) = self.local_owner();
+        let live = FileMeta::read(path)?;
+
+        let mut mode = self.permissions();
+        if uid.is_none() || gid.is_none() {
+            debug!(
+                "Keeping owner of {}; {}:{} not found",
+                path, self.user, self.group
+            );
+            mode &= !SET_ID_BITS;
+        }
+        let uid = uid.filter(|u| *u != live.uid);
+        let gid = gid.filter(|g| *g != live.gid);
+        if uid.is_some() || gid.is_some() {
+            match chown(path, uid, gid) {
+                Ok(_) => {}
+                Err(e) if unsafe { libc::geteuid() } != 0 => {
+                    warn!("Keeping owner of {}: {}", path, e);
+                    mode &= !SET_ID_BITS;
+                }
+                Err(e) => {
+                    return Err(Error::IOError {
+                        msg: format!("{}: {}", path, e),
+                    })
+                }
+            }
+        }
+
+        match fs::set_permissions(path, fs::Permissions::from_mode(mode)) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(Error::IOError {
+                msg: format!("{}: {}", path, e),
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, os::unix::fs::PermissionsExt};
+
+    use crate::{Bin, Error, FileMeta, LockWait};
+
+    fn chmod(path: &str, mode: u32) {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode)).unwrap();
+    }
+
+    #[test]
+    fn only_permissions_and_local_owners_differ() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("f").to_string_lossy().to_string();
+        fs::write(&path, "f").unwrap();
+        chmod(&path, 0o640);
+        let recorded = FileMeta::read(&path).unwrap();
+
+        assert!(!recorded.differs(&FileMeta::read(&path).unwrap()));
+        assert!(!FileMeta::default().differs(&recorded));
+        // mtimes are not compared
+        let mut touched = recorded.clone();
+        touched.mtime += 60;
+        assert!(!touched.differs(&recorded));
+
+        chmod(&path, 0o600);
+        assert!(recorded.differs(&FileMeta::read(&path).unwrap()));
+        chmod(&path, 0o640);
+
+        // an owner unknown here is only compared by id when running as root
+        let mut elsewhere = recorded.clone();
+        elsewhere.user = "meld-no-such-user".to_string();
+        elsewhere.uid = recorded.uid + 4242;
+        let root = unsafe { libc::geteuid() } == 0;
+        assert_eq!(elsewhere.differs(&recorded), root);
+        // as are setuid and setgid, which need the owner
+        elsewhere.uid = recorded.uid;
+        elsewhere.mode |= 0o4000;
+        assert_eq!(elsewhere.differs(&recorded), root);
+    }
+
+    #[test]
+    fn restored_metadata_rolls_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let bin_path = dir.path().join("bin").to_string_lossy().to_string();
+        let bin = Bin::new(bin_path, None, false, false, LockWait::Never).unwrap();
+        let path = dir.path().join("f").to_string_lossy().to_string();
+        fs::write(&path, "f").unwrap();
+        chmod(&path, 0o600);
+        let before = FileMeta::read(&path).unwrap();
+
+        let mut recorded = before.clone();
+        recorded.mode = (recorded.mode & !0o7777) | 0o644;
+        let res: Result<(), Error> = bin.transaction(|journal| {
+            journal.set_meta(path.clone(), &recorded)?;
+            assert_eq!(FileMeta::read(&path)?.permissions(), 0o644);
+            Err(Error::SomethingFailed)
+        });
+        assert!(res.is_err());
+        assert_eq!(FileMeta::read(&path).unwrap(), before);
+
+        bin.transaction(|journal| journal.set_meta(path.clone(), &recorded))
+            .unwrap();
+        assert!(!recorded.differs(&FileMeta::read(&path).unwrap()));
+    }
+}
@@ -0,0 +1,332 @@
+use std::{fs, path::PathBuf};
+
+use log::{debug, info};
+
+use crate::hash_bytes;
+use crate::Bin;
+use crate::Codec;
+use crate::Error;
+use crate::Journal;
+
+pub(crate) const OBJECTS_DIR: &str = "objects";
+
+// Length of a hex SHA512 hash
+const HASH_LEN: usize = 128;
+
+/// Check a string looks like a hex SHA512 hash
+pub(crate) fn is_hash(s: &str) -> bool {
+    s.len() == HASH_LEN && s.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+impl Bin {
+    /// Path of the object holding contents with the given hash
+    /// objects are fanned out by the first two characters of their hash, and named
+    /// with the extension of the codec they are compressed with
+    pub fn object_path(&self, hash: &String, codec: Codec) -> PathBuf {
+        self.objects
+            .join(&hash[..2])
+            .join(format!("{}{}", hash, codec.extension()))
+    }
+
+    /// Check an object is stored with the given codec, loose or in a pack
+    pub fn has_object(&self, hash: &String, codec: Codec) -> bool {
+        if self.object_path(hash, codec).exists() {
+            return true;
+        }
+        let name = format!("{}{}", hash, codec.extension());
+        self.get_packs()
+            .iter()
+            .any(|p| p.objects.contains_key(&name))
+    }
+
+    /// Codec of the stored object with the given hash, if any
+    pub fn find_object(&self, hash: &String) -> Option<Codec> {
+        Codec::ALL.into_iter().find(|c| self.has_object(hash, *c))
+    }
+
+    /// File names and paths of every loose object
+    /// files that are not objects are left out; validate reports them
+    pub(crate) fn loose_objects(&self) -> Vec<(String, PathBuf)> {
+        let mut objects = Vec::new();
+        let fans = match fs::read_dir(&self.objects) {
+            Ok(f) => f,
+            Err(_) => return objects,
+        };
+        for entry in fans
+            .flatten()
+            .flat_map(|f| fs::read_dir(f.path()))
+            .flatten()
+        {
+            let entry = match entry {
+                Ok(e) => e,
+                Err(_) => continue,
+            };
+            let name = entry.file_name().to_string_lossy().to_string();
+            match Codec::from_object_name(&name) {
+                Some((hash, _)) if is_hash(&hash) => objects.push((name, entry.path())),
+                _ => {}
+            }
+        }
+        objects
+    }
+
+    /// Path of the pointer file for a config version
+    pub(crate) fn pointer_path(&self, blob: &String, ver: u32) -> PathBuf {
+        self.blobs.join(blob).join(ver.to_string())
+    }
+
+    /// Read the object hash a config version points to
+    /// versions without a pointer file may have theirs in a pack
+    pub fn read_pointer(&self, blob: &String, ver: u32) -> Result<String, Error> {
+        let path = self.pointer_path(blob, ver);
+        let contents = match fs::read_to_string(&path) {
+            Ok(c) => c,
+            Err(e) => {
+                let key = (blob.clone(), ver);
+                if let Some(hash) = self.get_packs().iter().find_map(|p| p.pointers.get(&key)) {
+                    return Ok(hash.clone());
+                }
+                return Err(Error::IOError {
+                    msg: format!("{:?}: {}", path, e),
+                });
+            }
+        };
+
+        let hash = contents.trim();
+        if !is_hash(hash) {
+            return Err(Error::IOError {
+                msg: format!("{:?}: not an object pointer", path),
+            });
+        }
+        Ok(hash.to_string())
+    }
+
+    /// When a version's pointer was written, in seconds since the unix epoch
+    /// packed pointers use the time of their pack; now if neither can be read
+    pub(crate) fn pointer_time(&self, blob: &String, ver: u32) -> i64 {
+        let key = (blob.clone(), ver);
+        let path = match self
+            .get_packs()
+            .iter()
+            .find(|p| p.pointers.contains_key(&key))
+        {
+            Some(pack) if !self.pointer_path(blob, ver).exists() => pack.path.clone(),
+            _ => self.pointer_path(blob, ver),
+        };
+
+        crate::modified(&path)
+    }
+
+    /// Fill in when each version of an older bin was pushed from its pointer's time
+    pub(crate) fn migrate_created(&self) -> Result<(), Error> {
+        for (owner, ver) in self.db.get_uncreated("versions")? {
+            let created = self.pointer_time(&owner, ver);
+            self.db.set_version_created(&owner, ver, created)?;
+        }
+        Ok(())
+    }
+
+    /// Read an object as stored, from its loose file or a pack
+    pub(crate) fn read_stored(&self, hash: &String, codec: Codec) -> Result<Vec<u8>, Error> {
+        let path = self.object_path(hash, codec);
+        debug!("Reading {:?}", path);
+
+        match fs::read(&path) {
+            Ok(b) => Ok(b),
+            Err(e) => {
+                let name = format!("{}{}", hash, codec.extension());
+                match self
+                    .get_packs()
+                    .iter()
+                    .find(|p| p.objects.contains_key(&name))
+                {
+                    Some(pack) => pack.read(&name),
+                    None => Err(Error::IOError {
+                        msg: format!("{:?}: {}", path, e),
+                    }),
+                }
+            }
+        }
+    }
+
+    /// Read the decompressed contents of an object
+    /// delta objects are rebuilt from their bases
+    pub fn read_object(&self, hash: &String, codec: Codec) -> Result<Vec<u8>, Error> {
+        if codec == Codec::Delta {
+            return self.rebuild_delta(hash);
+        }
+        codec.decompress(&self.read_stored(hash, codec)?)
+    }
+
+    /// Store contents as an object with the given codec unless an identical one exists
+    /// Returns the codec the stored object uses, which is the existing one's if any
+    pub fn store_object(
+        &self,
+        journal: &mut Journal,
+        hash: &String,
+        data: &[u8],
+        codec: Codec,
+    ) -> Result<Codec, Error> {
+        if let Some(existing) = self.find_object(hash) {
+            info!("Object {} already stored with {}; reusing", hash, existing);
+            return Ok(existing);
+        }
+
+        let path = self.object_path(hash, codec);
+        if let Some(parent) = path.parent() {
+            journal.create_dir(parent.to_string_lossy().to_string())?;
+        }
+        journal.create(path.to_string_lossy().to_string(), &codec.compress(data)?)?;
+        Ok(codec)
+    }
+
+    /// Point a config version at the object with the given hash
+    /// the blob dir is recreated if packing removed it
+    pub(crate) fn write_pointer(
+        &self,
+        journal: &mut Journal,
+        blob: &String,
+        ver: u32,
+        hash: &String,
+    ) -> Result<(), Error> {
+        let pointer = self.pointer_path(blob, ver);
+        if let Some(parent) = pointer.parent() {
+            journal.create_dir(parent.to_string_lossy().to_string())?;
+        }
+        journal.create(
+            pointer.to_string_lossy().to_string(),
+            format!("{}\n", hash).as_bytes(),
+        )
+    }
+
+    /// Store a new version of a config: its contents as an object and a pointer to it
+    /// With deltas enabled, contents are stored as a delta against base when smaller
+    /// Returns the codec the version's object is stored with
+    pub fn store_version(
+        &self,
+        journal: &mut Journal,
+        blob: &String,
+        ver: u32,
+        hash: &String,
+        data: &[u8],
+        base: Option<&String>,
+    ) -> Result<Codec, Error> {
+        debug!("Storing {} V {}", blob, ver);
+        let codec = match base {
+            Some(b)
+                if self.find_object(hash).is_none()
+                    && self.store_delta(journal, hash, data, b)? =>
+            {
+                Codec::Delta
+            }
+            _ => self.store_object(journal, hash, data, self.config.compression)?,
+        };
+        self.write_pointer(journal, blob, ver, hash)?;
+        Ok(codec)
+    }
+
+    /// Move the contents of format 1 blob files into objects, leaving pointers behind
+    pub(crate) fn migrate_objects(&self, journal: &mut Journal) -> Result<(), Error> {
+        journal.create_dir(self.objects.to_string_lossy().to_string())?;
+
+        // collect paths first; pointers are written back into the same dirs
+        let mut paths = Vec::new();
+        for blob_dir in list_dir(&self.blobs)? {
+            paths.extend(list_dir(&blob_dir)?);
+        }
+
+        for path in &paths {
+            let data = match fs::read(path) {
+                Ok(d) => d,
+                Err(e) => {
+                    return Err(Error::IOError {
+                        msg: format!("{:?}: {}", path, e),
+                    })
+                }
+            };
+
+            let hash = hash_bytes(&data);
+            let object = self.object_path(&hash, Codec::None);
+            let path_str = path.to_string_lossy().to_string();
+
+            // the first copy becomes the object; duplicates are dropped on commit
+            if object.exists() {
+                journal.backup(path_str.clone())?;
+            } else {
+                if let Some(parent) = object.parent() {
+                    journal.create_dir(parent.to_string_lossy().to_string())?;
+                }
+                journal.rename(path_str.clone(), object.to_string_lossy().to_string())?;
+            }
+            journal.create(path_str, format!("{}\n", hash).as_bytes())?;
+        }
+
+        info!("Moved {} blob files into objects", paths.len());
+        Ok(())
+    }
+}
+
+/// Paths of every entry in a dir
+fn list_dir(path: &PathBuf) -> Result<Vec<PathBuf>, Error> {
+    match fs::read_dir(path) {
+        Ok(e) => Ok(e.flatten().map(|e| e.path()).collect()),
+        Err(e) => Err(Error::IOError {
+            msg: format!("{:?}: {}", path, e),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::{hash_bytes, Bin, Codec, LockWait};
+
+    fn new_bin(dir: &tempfile::TempDir) -> Bin {
+        let path = dir.path().join("bin").to_string_lossy().to_string();
+        Bin::new(path, None, false, false, LockWait::Never).unwrap()
+    }
+
+    fn store(bin: &Bin, blob: &str, ver: u32, data: &[u8]) -> Codec {
+        let hash = hash_bytes(data);
+        bin.transaction(|j| bin.store_version(j, &blob.to_string(), ver, &hash, data, None))
+            .unwrap()
+    }
+
+    #[test]
+    fn identical_contents_share_an_object() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut bin = new_bin(&dir);
+        let hash = hash_bytes(b"shared");
+
+        store(&bin, "a", 1, b"shared");
+        store(&bin, "a", 2, b"changed");
+        // a revert and another config with the same contents reuse the first object,
+        // even after the bin's codec changed
+        bin.config.compression = Codec::Zstd;
+        assert_eq!(store(&bin, "a", 3, b"shared"), Codec::None);
+        assert_eq!(store(&bin, "b", 1, b"shared"), Codec::None);
+
+        assert_eq!(bin.loose_objects().len(), 2);
+        for (blob, ver) in [("a", 1), ("a", 3), ("b", 1)] {
+            assert_eq!(bin.read_pointer(&blob.to_string(), ver).unwrap(), hash);
+        }
+        assert_eq!(bin.read_object(&hash, Codec::None).unwrap(), b"shared");
+        assert_eq!(
+            fs::read_to_string(bin.pointer_path(&"b".to_string(), 1)).unwrap(),
+            format!("{}\n", hash)
+        );
+    }
+
+    #[test]
+    fn pointers_must_name_an_object() {
+        let dir = tempfile::tempdir().unwrap();
+        let bin = new_bin(&dir);
+        store(&bin, "a", 1, b"a");
+        let blob = "a".to_string();
+
+        fs::write(bin.pointer_path(&blob, 1), "contents\n").unwrap();
+        assert!(bin.read_pointer(&blob, 1).is_err());
+        assert!(bin.read_pointer(&blob, 2).is_err());
+    }
+}
@@ -0,0 +1,465 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    io::{Read, Seek, SeekFrom},
+    path::PathBuf,
+};
+
+use log::{debug, info, warn};
+
+use crate::hash_bytes;
+use crate::objects::is_hash;
+use crate::Bin;
+use crate::Codec;
+use crate::Error;
+use crate::Journal;
+use crate::Pack;
+use crate::PackReport;
+
+pub(crate) const PACKS_DIR: &str = "packs";
+
+// First line of every pack index
+const INDEX_HEADER: &str = "meld pack 1";
+
+pub(crate) const PACK_EXT: &str = "pack";
+pub(crate) const INDEX_EXT: &str = "idx";
+
+// Object file names -> stored bytes, and (blob, ver) -> object hash
+pub(crate) type PackContents = (BTreeMap<String, Vec<u8>>, BTreeMap<(String, u32), String>);
+
+impl Pack {
+    /// Load a pack from its index
+    /// every object entry must lie inside the pack file next to the index
+    pub(crate) fn open(index: &PathBuf) -> Result<Pack, Error> {
+        let path = index.with_extension(PACK_EXT);
+        let size = match fs::metadata(&path) {
+            Ok(md) => md.len(),
+            Err(e) => {
+                return Err(Error::IOError {
+                    msg: format!("{:?}: {}", path, e),
+                })
+            }
+        };
+        let contents = match fs::read_to_string(index) {
+            Ok(c) => c,
+            Err(e) => {
+                return Err(Error::IOError {
+                    msg: format!("{:?}: {}", index, e),
+                })
+            }
+        };
+
+        let mut lines = contents.lines();
+        if lines.next() != Some(INDEX_HEADER) {
+            return Err(Error::IOError {
+                msg: format!("{:?}: not a pack index", index),
+            });
+        }
+
+        let mut pack = Pack {
+            path,
+            objects: HashMap::new(),
+            pointers: HashMap::new(),
+        };
+        for line in lines {
+            let fields: Vec<&str> = line.split(' ').collect();
+            let valid = match fields[..] {
+                ["object", name, offset, len] => {
+                    match (
+                        Codec::from_object_name(name),
+                        offset.parse::<u64>(),
+                        len.parse::<u64>(),
+                    ) {
+                        (Some((hash, _)), Ok(o), Ok(l)) if is_hash(&hash) && o + l <= size => {
+                            pack.objects.insert(name.to_string(), (o, l));
+                            true
+                        }
+                        _ => false,
+                    }
+                }
+                ["pointer", blob, ver, hash] => match ver.parse::<u32>() {
+                    Ok(v) if is_hash(hash) => {
+                        pack.pointers
+                            .insert((blob.to_string(), v), hash.to_string());
+                        true
+                    }
+                    _ => false,
+                },
+                _ => false,
+            };
+            if !valid {
+                return Err(Error::IOError {
+                    msg: format!("{:?}: malformed entry '{}'", index, line),
+                });
+            }
+        }
+
+        Ok(pack)
+    }
+
+    /// Name of the pack; the hash of its contents and index
+    pub fn name(&self) -> String {
+        match self.path.file_stem() {
+            Some(s) => s.to_string_lossy().to_string(),
+            None => String::new(),
+        }
+    }
+
+    /// Read the stored bytes of a packed object, as they would be in a loose file
+    pub(crate) fn read(&self, name: &String) -> Result<Vec<u8>, Error> {
+        let (offset, len) = match self.objects.get(name) {
+            Some(e) => *e,
+            None => {
+                return Err(Error::IOError {
+                    msg: format!("{:?}: no object {}", self.path, name),
+                })
+            }
+        };
+        debug!("Reading {} from {:?}", name, self.path);
+
+        let mut data = vec![0; len as usize];
+        let res = fs::File::open(&self.path).and_then(|mut f| {
+            f.seek(SeekFrom::Start(offset))?;
+            f.read_exact(&mut data)
+        });
+        match res {
+            Ok(_) => Ok(data),
+            Err(e) => Err(Error::IOError {
+                msg: format!("{:?}: {}", self.path, e),
+            }),
+        }
+    }
+}
+
+/// Sorted paths of every entry in a dir, with their names
+fn list_entries(path: &PathBuf) -> Result<Vec<(String, PathBuf)>, Error> {
+    let mut entries: Vec<(String, PathBuf)> = match fs::read_dir(path) {
+        Ok(e) => e
+            .flatten()
+            .map(|e| (e.file_name().to_string_lossy().to_string(), e.path()))
+            .collect(),
+        Err(e) => {
+            return Err(Error::IOError {
+                msg: format!("{:?}: {}", path, e),
+            })
+        }
+    };
+    entries.sort();
+    Ok(entries)
+}
+
+impl Bin {
+    /// Every readable pack of the bin; loaded on first use
+    /// unreadable packs are skipped here and reported by validate
+    pub(crate) fn get_packs(&self) -> &Vec<Pack> {
+        self.pack_index.get_or_init(|| {
+            let mut packs = Vec::new();
+            if !self.packs.exists() {
+                return packs;
+            }
+
+            for index in self.pack_indexes() {
+                match Pack::open(&index) {
+                    Ok(p) => packs.push(p),
+                    Err(e) => warn!("Skipping pack: {}", e),
+                }
+            }
+            debug!("Loaded {} packs", packs.len());
+            packs
+        })
+    }
+
+    /// Paths of the index of every pack in the bin
+    pub(crate) fn pack_indexes(&self) -> Vec<PathBuf> {
+        match list_entries(&self.packs) {
+            Ok(e) => e
+                .into_iter()
+                .map(|(_, p)| p)
+                .filter(|p| p.extension().is_some_and(|e| e == INDEX_EXT))
+                .collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    /// Read the objects and pointers of every pack
+    pub(crate) fn read_packs(&self) -> Result<PackContents, Error> {
+        let mut objects: BTreeMap<String, Vec<u8>> = BTreeMap::new();
+        let mut pointers: BTreeMap<(String, u32), String> = BTreeMap::new();
+        for pack in self.get_packs() {
+            for name in pack.objects.keys() {
+                objects.insert(name.clone(), pack.read(name)?);
+            }
+            pointers.extend(pack.pointers.clone());
+        }
+        Ok((objects, pointers))
+    }
+
+    /// Write the given objects and pointers as a single pack, replacing every existing one
+    /// Nothing is written if both are empty; returns the number of packs replaced
+    pub(crate) fn replace_packs(
+        &self,
+        journal: &mut Journal,
+        objects: &BTreeMap<String, Vec<u8>>,
+        pointers: &BTreeMap<(String, u32), String>,
+    ) -> Result<usize, Error> {
+        let mut data = Vec::new();
+        let mut index = format!("{}\n", INDEX_HEADER);
+        for (name, bytes) in objects {
+            index.push_str(&format!("object {} {} {}\n", name, data.len(), bytes.len()));
+            data.extend_from_slice(bytes);
+        }
+        for ((blob, ver), hash) in pointers {
+            index.push_str(&format!("pointer {} {} {}\n", blob, ver, hash));
+        }
+
+        let name = if objects.is_empty() && pointers.is_empty() {
+            None
+        } else {
+            Some(hash_bytes(&[data.as_slice(), index.as_bytes()].concat()))
+        };
+
+        let old = self.get_packs();
+        let packs = self.packs.to_string_lossy().to_string();
+        // an identical pack is already in place if only duplicates were loose
+        if let Some(name) = name
+            .as_ref()
+            .filter(|n| !old.iter().any(|p| p.name() == **n))
+        {
+            info!("Writing pack {} with {} objects", name, objects.len());
+            journal.create_dir(packs.clone())?;
+            journal.create(format!("{}/{}.{}", packs, name, PACK_EXT), &data)?;
+            journal.create(
+                format!("{}/{}.{}", packs, name, INDEX_EXT),
+                index.as_bytes(),
+            )?;
+        }
+
+        let mut replaced = 0;
+        for pack in old {
+            if name.as_ref() == Some(&pack.name()) {
+                continue;
+            }
+            journal.backup(pack.path.to_string_lossy().to_string())?;
+            journal.backup(
+                pack.path
+                    .with_extension(INDEX_EXT)
+                    .to_string_lossy()
+                    .to_string(),
+            )?;
+            replaced += 1;
+        }
+        Ok(replaced)
+    }
+
+    /// Consolidate loose objects, pointers and existing packs into a single pack
+    /// Loose files are removed once the pack is written; blob and fan dirs left empty
+    /// are removed too. Objects are packed as stored, so compressed, delta and
+    /// encrypted objects are unchanged
+    pub fn pack(&self) -> Result<PackReport, Error> {
+        info!("Packing bin {:?}", self.path);
+        let mut report = PackReport {
+            objects: 0,
+            pointers: 0,
+            loose: 0,
+            replaced: 0,
+        };
+
+        // object file name -> path of every loose object
+        let mut loose_objects: Vec<(String, PathBuf)> = Vec::new();
+        for (fan, fan_path) in list_entries(&self.objects)? {
+            if !fan_path.is_dir() {
+                continue;
+            }
+            for (name, path) in list_entries(&fan_path)? {
+                match Codec::from_object_name(&name) {
+                    Some((hash, _)) if hash.starts_with(&fan) && is_hash(&hash) => {
+                        loose_objects.push((name, path))
+                    }
+                    _ => warn!("Not packing {:?}; not an object", path),
+                }
+            }
+        }
+
+        // (blob, ver) -> hash and path of every loose pointer
+        let mut loose_pointers: Vec<((String, u32), String, PathBuf)> = Vec::new();
+        for (blob, blob_path) in list_entries(&self.blobs)? {
+            if !blob_path.is_dir() {
+                continue;
+            }
+            for (file, path) in list_entries(&blob_path)? {
+                let ver = match file.parse::<u32>() {
+                    Ok(v) => v,
+                    Err(_) => continue,
+                };
+                match self.read_pointer(&blob, ver) {
+                    Ok(hash) => loose_pointers.push(((blob.clone(), ver), hash, path)),
+                    Err(e) => warn!("Not packing {}", e),
+                }
+            }
+        }
+
+        let old = self.get_packs();
+        if loose_objects.is_empty() && loose_pointers.is_empty() && old.len() <= 1 {
+            info!("Nothing to pack");
+            return Ok(report);
+        }
+
+        // loose files take precedence over packed copies
+        let (mut objects, mut pointers) = self.read_packs()?;
+        for (name, path) in &loose_objects {
+            let data = match fs::read(path) {
+                Ok(d) => d,
+                Err(e) => {
+                    return Err(Error::IOError {
+                        msg: format!("{:?}: {}", path, e),
+                    })
+                }
+            };
+            objects.insert(name.clone(), data);
+        }
+        for (key, hash, _) in &loose_pointers {
+            pointers.insert(key.clone(), hash.clone());
+        }
+
+        report.objects = objects.len();
+        report.pointers = pointers.len();
+        report.loose = loose_objects.len() + loose_pointers.len();
+
+        let emptied = self.transaction(|journal| {
+            report.replaced = self.replace_packs(journal, &objects, &pointers)?;
+
+            let mut emptied = Vec::new();
+            for (_, path) in &loose_objects {
+                journal.backup(path.to_string_lossy().to_string())?;
+                emptied.extend(path.parent().map(|p| p.to_path_buf()));
+            }
+            for (_, _, path) in &loose_pointers {
+                journal.backup(path.to_string_lossy().to_string())?;
+                emptied.extend(path.parent().map(|p| p.to_path_buf()));
+            }
+            Ok(emptied)
+        })?;
+
+        // only dirs emptied here are removed; dirs are stored as empty blob dirs
+        for dir in emptied {
+            if fs::remove_dir(&dir).is_ok() {
+                debug!("Removed empty {:?}", dir);
+            }
+        }
+
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{BTreeMap, HashMap},
+        fs,
+        path::PathBuf,
+    };
+
+    use super::{INDEX_EXT, INDEX_HEADER, PACK_EXT};
+    use crate::{hash_bytes, Bin, LockMode, LockWait, Pack};
+
+    fn bin_path(dir: &tempfile::TempDir) -> String {
+        dir.path().join("bin").to_string_lossy().to_string()
+    }
+
+    #[test]
+    fn index_written_and_parsed() {
+        let dir = tempfile::tempdir().unwrap();
+        let bin = Bin::new(bin_path(&dir), None, false, false, LockWait::Never).unwrap();
+
+        let a = hash_bytes(b"a");
+        let b = hash_bytes(b"b");
+        let objects = BTreeMap::from([
+            (a.clone(), b"first".to_vec()),
+            (format!("{}.zst", b), b"second".to_vec()),
+        ]);
+        let pointers = BTreeMap::from([
+            (("blob".to_string(), 1), a.clone()),
+            (("blob".to_string(), 2), b.clone()),
+        ]);
+        bin.transaction(|j| bin.replace_packs(j, &objects, &pointers))
+            .unwrap();
+
+        let index = bin.pack_indexes();
+        assert_eq!(index.len(), 1);
+        let written = fs::read_to_string(&index[0]).unwrap();
+        assert_eq!(
+            written,
+            format!(
+                "{}\nobject {} 0 5\nobject {}.zst 5 6\npointer blob 1 {}\npointer blob 2 {}\n",
+                INDEX_HEADER, a, b, a, b
+            )
+        );
+
+        let pack = Pack::open(&index[0]).unwrap();
+        let data = fs::read(index[0].with_extension(PACK_EXT)).unwrap();
+        assert_eq!(
+            pack.name(),
+            hash_bytes(&[data, written.into_bytes()].concat())
+        );
+        for (name, bytes) in &objects {
+            assert_eq!(&pack.read(name).unwrap(), bytes);
+        }
+        let expected: HashMap<(String, u32), String> = pointers.into_iter().collect();
+        assert_eq!(pack.pointers, expected);
+    }
+
+    #[test]
+    fn malformed_indexes_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let index = dir.path().join(format!("p.{}", INDEX_EXT));
+        fs::write(index.with_extension(PACK_EXT), b"0123456789").unwrap();
+        let open = |contents: String| -> bool {
+            fs::write(&index, contents).unwrap();
+            Pack::open(&PathBuf::from(&index)).is_ok()
+        };
+
+        let hash = hash_bytes(b"a");
+        assert!(open(format!("{}\nobject {} 2 8\n", INDEX_HEADER, hash)));
+        assert!(!open(format!("meld pack 2\nobject {} 2 8\n", hash)));
+        // objects must lie inside the pack
+        assert!(!open(format!("{}\nobject {} 2 9\n", INDEX_HEADER, hash)));
+        assert!(!open(format!("{}\nobject {}.xz 0 1\n", INDEX_HEADER, hash)));
+        assert!(!open(format!("{}\nobject nothash 0 1\n", INDEX_HEADER)));
+        assert!(!open(format!(
+            "{}\npointer blob one {}\n",
+            INDEX_HEADER, hash
+        )));
+        assert!(!open(format!("{}\npointer blob 1 nothash\n", INDEX_HEADER)));
+        assert!(!open(format!("{}\nobject {} 0\n", INDEX_HEADER, hash)));
+    }
+
+    #[test]
+    fn packed_versions_read_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = bin_path(&dir);
+        let bin = Bin::new(path.clone(), None, false, false, LockWait::Never).unwrap();
+
+        let blob = "blob".to_string();
+        let mut stored = Vec::new();
+        for ver in 1..=3u32 {
+            let data = format!("version {}\n", ver).into_bytes();
+            let hash = hash_bytes(&data);
+            let codec = bin
+                .transaction(|j| bin.store_version(j, &blob, ver, &hash, &data, None))
+                .unwrap();
+            stored.push((ver, hash, codec, data));
+        }
+        let report = bin.pack().unwrap();
+        assert_eq!((report.objects, report.pointers, report.loose), (3, 3, 6));
+        drop(bin);
+
+        // loose files are gone and the versions are read from the pack
+        let bin = Bin::from(path, None, LockMode::Shared, LockWait::Never).unwrap();
+        assert!(bin.loose_objects().is_empty());
+        assert!(!bin.blobs.join(&blob).exists());
+        for (ver, hash, codec, data) in &stored {
+            assert_eq!(&bin.read_pointer(&blob, *ver).unwrap(), hash);
+            assert_eq!(&bin.read_object(hash, *codec).unwrap(), data);
+        }
+    }
+}
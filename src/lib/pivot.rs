@@ -0,0 +1,265 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs,
+};
+
+use log::{info, warn};
+
+use crate::hash_path;
+use crate::Bin;
+use crate::Error;
+use crate::Journal;
+use crate::Mapper;
+use crate::Mapping;
+
+/// A config whose blob name changes because its map path changed
+pub(crate) struct Rekey {
+    pub(crate) old_blob: String,
+    pub(crate) new_blob: String,
+    pub(crate) map_path: String,
+}
+
+/// Replace the leading variable of a map path, if it matches
+fn pivot_map_path(map_path: &str, from: &str, to: &str) -> Option<String> {
+    match map_path.strip_prefix(from) {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => Some(format!("{}{}", to, rest)),
+        _ => None,
+    }
+}
+
+impl Bin {
+    /// Rename a mapping variable across the bin
+    /// Configs, versions, maps and their files are re-keyed since blob
+    /// names are derived from map paths; returns the number of configs moved
+    pub fn pivot(&self, from: &String, to: &String) -> Result<usize, Error> {
+        info!("Pivoting {} -> {}", from, to);
+
+        // validate both names; the prefix is unused
+        Mapping::new(from.to_string(), "-".to_string(), None)?;
+        Mapping::new(to.to_string(), "-".to_string(), None)?;
+
+        let configs = self.db.get_configs()?;
+
+        // the new variable cannot already exist bin-wide or in any profile
+        let mut profiles: Vec<Option<String>> =
+            self.db.get_profiles()?.into_iter().map(Some).collect();
+        profiles.push(None);
+        for p in &profiles {
+            if self.db.get_mappings(p)?.iter().any(|m| &m.var == to) {
+                return Err(Error::PivotFailed {
+                    msg: format!("{} is already defined", to),
+                });
+            }
+        }
+
+        // plan every rekey before touching anything
+        let mut rekeys = Vec::new();
+        for c in &configs {
+            if let Some(map_path) = pivot_map_path(c.get_map_path(), from, to) {
+                let new_blob = hash_path(&map_path);
+                if configs.iter().any(|o| o.get_blob() == &new_blob) {
+                    return Err(Error::PivotFailed {
+                        msg: format!("{} is already tracked", map_path),
+                    });
+                }
+                rekeys.push(Rekey {
+                    old_blob: c.get_blob().to_string(),
+                    new_blob,
+                    map_path,
+                });
+            }
+        }
+
+        if rekeys.is_empty() {
+            warn!("No configs use {}; only renaming the variable", from);
+        }
+
+        self.transaction(|journal| {
+            self.db.pivot(&rekeys, from, to)?;
+            self.pivot_files(&rekeys, journal)
+        })?;
+
+        Ok(rekeys.len())
+    }
+
+    /// Re-key configs of an older bin, stored by real path, by their mapped paths
+    /// Paths are mapped with the bin-wide mappings; paths no mapping covers, or whose
+    /// mapped path is already tracked, are kept. Maps of older bins were named by the path
+    /// given to push, so each map follows the dir listed first in its map files
+    pub(crate) fn migrate_map_paths(&self, journal: &mut Journal) -> Result<(), Error> {
+        let mapper = Mapper::new(self.db.get_mappings(&None)?);
+        let configs = self.db.get_configs()?;
+        let tracked: HashSet<&String> = configs.iter().map(|c| c.get_blob()).collect();
+
+        // old blob -> new blob of every config that moves
+        let mut moved: HashMap<String, String> = HashMap::new();
+        let mut rekeys = Vec::new();
+        for c in &configs {
+            if c.get_map_path().starts_with('$') {
+                continue;
+            }
+            let map_path = mapper.real_path_to_map(c.get_map_path())?;
+            if &map_path == c.get_map_path() {
+                continue;
+            }
+
+            let new_blob = hash_path(&map_path);
+            if tracked.contains(&new_blob) {
+                warn!(
+                    "Not moving {}; {} is already tracked",
+                    c.get_map_path(),
+                    map_path
+                );
+                continue;
+            }
+            moved.insert(c.get_blob().to_string(), new_blob.clone());
+            rekeys.push(Rekey {
+                old_blob: c.get_blob().to_string(),
+                new_blob,
+                map_path,
+            });
+        }
+
+        let maps = self.db.get_all_maps()?;
+        let map_ids: HashSet<&String> = maps.iter().map(|m| &m.blob).collect();
+        let mut followed: HashSet<&String> = HashSet::new();
+        for m in &maps {
+            if moved.contains_key(&m.blob) || !followed.insert(&m.blob) {
+                continue;
+            }
+
+            // the dir itself is the first entry of its map files
+            let dir = match self.get_map_entries(&m.blob, m.ver) {
+                Ok(entries) => entries.into_iter().next().map(|(b, _)| b),
+                Err(e) => {
+                    warn!("Not moving map {}: {}", m.blob, e);
+                    None
+                }
+            };
+            let dir = match dir.and_then(|d| configs.iter().find(|c| c.get_blob() == &d)) {
+                Some(c) => c,
+                None => continue,
+            };
+
+            let new_blob = moved.get(dir.get_blob()).unwrap_or(dir.get_blob());
+            if new_blob == &m.blob {
+                continue;
+            }
+            if map_ids.contains(new_blob) {
+                warn!(
+                    "Not moving map {}; {} has its own",
+                    m.blob,
+                    dir.get_map_path()
+                );
+                continue;
+            }
+            rekeys.push(Rekey {
+                old_blob: m.blob.clone(),
+                new_blob: new_blob.to_string(),
+                map_path: dir.get_map_path().to_string(),
+            });
+        }
+
+        if rekeys.is_empty() {
+            return Ok(());
+        }
+        info!("Moving {} configs and maps to mapped paths", rekeys.len());
+        self.db.rekey(&rekeys)?;
+        self.pivot_files(&rekeys, journal)
+    }
+
+    /// Rename blob dirs, rewrite map files and re-key packed pointers for a set of rekeys
+    fn pivot_files(&self, rekeys: &Vec<Rekey>, journal: &mut Journal) -> Result<(), Error> {
+        let blobs = self.get_blobs_str()?;
+        let maps = self.get_maps_str()?;
+
+        let lookup: HashMap<&str, &str> = rekeys
+            .iter()
+            .map(|r| (r.old_blob.as_str(), r.new_blob.as_str()))
+            .collect();
+
+        for r in rekeys {
+            let old_dir = format!("{}/{}", blobs, r.old_blob);
+            let new_dir = format!("{}/{}", blobs, r.new_blob);
+            // maps have no blob dir of their own
+            if !crate::exists(&old_dir) {
+                continue;
+            }
+            if crate::exists(&new_dir) {
+                return Err(Error::PivotFailed {
+                    msg: format!("{} already exists", new_dir),
+                });
+            }
+            journal.rename(old_dir, new_dir)?;
+        }
+
+        // packed pointers are keyed by blob too; packs are rewritten as pack does
+        let (objects, pointers) = self.read_packs()?;
+        if pointers
+            .keys()
+            .any(|(b, _)| lookup.contains_key(b.as_str()))
+        {
+            let pointers: BTreeMap<(String, u32), String> = pointers
+                .into_iter()
+                .map(|((blob, ver), hash)| match lookup.get(blob.as_str()) {
+                    Some(b) => ((b.to_string(), ver), hash),
+                    None => ((blob, ver), hash),
+                })
+                .collect();
+            self.replace_packs(journal, &objects, &pointers)?;
+        }
+
+        // collect names first; rewritten files are added to the same dir
+        let names: Vec<String> = match fs::read_dir(&maps) {
+            Ok(e) => e
+                .flatten()
+                .map(|e| e.file_name().to_string_lossy().to_string())
+                .collect(),
+            Err(e) => return Err(Error::IOError { msg: e.to_string() }),
+        };
+
+        for name in names {
+            let (map_blob, map_ver) = match name.split_once('-') {
+                Some(parts) => parts,
+                None => continue,
+            };
+
+            let contents = match fs::read_to_string(format!("{}/{}", maps, name)) {
+                Ok(c) => c,
+                Err(e) => return Err(Error::IOError { msg: e.to_string() }),
+            };
+
+            // each line of a map file is <BLOB>-<VER>
+            let mut new_contents = String::new();
+            let mut changed = false;
+            for line in contents.lines() {
+                let rekeyed = line
+                    .split_once('-')
+                    .and_then(|(blob, ver)| lookup.get(blob).map(|b| (b, ver)));
+                match rekeyed {
+                    Some((blob, ver)) => {
+                        new_contents.push_str(&format!("{}-{}\n", blob, ver));
+                        changed = true;
+                    }
+                    None => new_contents.push_str(&format!("{}\n", line)),
+                }
+            }
+
+            let new_name = match lookup.get(map_blob) {
+                Some(b) => format!("{}-{}", b, map_ver),
+                None => name.clone(),
+            };
+
+            if !changed && new_name == name {
+                continue;
+            }
+
+            // keep the original until the pivot is committed
+            let old_path = format!("{}/{}", maps, name);
+            journal.backup(old_path)?;
+            journal.create(format!("{}/{}", maps, new_name), new_contents.as_bytes())?;
+        }
+
+        Ok(())
+    }
+}
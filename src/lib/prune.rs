@@ -0,0 +1,524 @@
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fmt, fs,
+    path::PathBuf,
+};
+
+use log::{debug, info};
+
+use crate::bin::BIN_CONFIG;
+use crate::Bin;
+use crate::Codec;
+use crate::Error;
+use crate::Journal;
+use crate::PruneReport;
+use crate::Retention;
+use crate::Version;
+
+// Units max_age can be given in, largest first, with their length in seconds
+const AGE_UNITS: [(char, u64); 5] = [
+    ('w', 7 * 24 * 60 * 60),
+    ('d', 24 * 60 * 60),
+    ('h', 60 * 60),
+    ('m', 60),
+    ('s', 1),
+];
+
+/// Format seconds as an age in the largest unit that divides them evenly
+fn format_age(secs: u64) -> String {
+    for (unit, size) in AGE_UNITS {
+        if secs.is_multiple_of(size) {
+            return format!("{}{}", secs / size, unit);
+        }
+    }
+    format!("{}s", secs)
+}
+
+// A policy error in a bin or blob config
+fn invalid(msg: &str) -> Error {
+    Error::InvalidBinConfig {
+        msg: msg.to_string(),
+    }
+}
+
+impl Retention {
+    /// Parse an age like 30d or 12h into seconds
+    pub fn parse_age(age: &str) -> Option<u64> {
+        let unit = age.chars().last()?;
+        let (_, size) = AGE_UNITS.iter().find(|(u, _)| *u == unit)?;
+        match age[..age.len() - 1].parse::<u64>() {
+            Ok(n) if n > 0 => n.checked_mul(*size),
+            _ => None,
+        }
+    }
+
+    /// Read a retention policy from the `retention` key of a bin or blob config
+    pub(crate) fn from_config(config: &serde_yaml::Value) -> Result<Retention, Error> {
+        let policy = match config.get("retention") {
+            None | Some(serde_yaml::Value::Null) => return Ok(Retention::default()),
+            Some(serde_yaml::Value::Mapping(m)) => m,
+            Some(_) => return Err(invalid("retention must be a mapping")),
+        };
+
+        let mut retention = Retention::default();
+        for (key, value) in policy {
+            match key.as_str() {
+                Some("keep_last") => match value.as_u64() {
+                    Some(n) if n >= 1 && n <= u32::MAX as u64 => {
+                        retention.keep_last = Some(n as u32)
+                    }
+                    _ => return Err(invalid("keep_last must be a positive number")),
+                },
+                Some("keep_tagged") => match value.as_bool() {
+                    Some(b) => retention.keep_tagged = Some(b),
+                    None => return Err(invalid("keep_tagged must be true or false")),
+                },
+                Some("max_age") => match value.as_str().and_then(Retention::parse_age) {
+                    Some(a) => retention.max_age = Some(a),
+                    None => return Err(invalid("max_age must be an age like 30d, 12h or 2w")),
+                },
+                _ => {
+                    return Err(invalid(
+                        "retention may only set keep_last, keep_tagged and max_age",
+                    ))
+                }
+            }
+        }
+
+        Ok(retention)
+    }
+
+    /// The policy as the value of a retention key
+    pub(crate) fn to_value(&self) -> serde_yaml::Value {
+        let mut policy = serde_yaml::Mapping::new();
+        if let Some(n) = self.keep_last {
+            policy.insert("keep_last".into(), n.into());
+        }
+        if let Some(b) = self.keep_tagged {
+            policy.insert("keep_tagged".into(), b.into());
+        }
+        if let Some(a) = self.max_age {
+            policy.insert("max_age".into(), format_age(a).into());
+        }
+        policy.into()
+    }
+
+    // The policy as a blob config with a single retention key
+    fn to_config(&self) -> serde_yaml::Value {
+        let mut config = serde_yaml::Mapping::new();
+        config.insert("retention".into(), self.to_value());
+        config.into()
+    }
+
+    /// Apply these rules over base; rules not set here are taken from base
+    pub fn over(&self, base: &Retention) -> Retention {
+        Retention {
+            keep_last: self.keep_last.or(base.keep_last),
+            keep_tagged: self.keep_tagged.or(base.keep_tagged),
+            max_age: self.max_age.or(base.max_age),
+        }
+    }
+
+    /// Check the policy prunes anything at all
+    pub fn is_set(&self) -> bool {
+        self.keep_last.is_some() || self.max_age.is_some()
+    }
+
+    // Check a version is kept; newest is its place among the config's versions, from 0
+    fn keeps(&self, newest: usize, v: &Version, now: i64) -> bool {
+        let by_count = self.keep_last.is_some_and(|n| newest < n as usize);
+        let by_tag = !v.tag.is_empty() && self.keep_tagged != Some(false);
+        let by_age = self
+            .max_age
+            .is_some_and(|a| now.saturating_sub(v.created) < a as i64);
+        !self.is_set() || newest == 0 || by_count || by_tag || by_age
+    }
+}
+
+impl fmt::Display for Retention {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.is_set() {
+            return write!(f, "keep every version");
+        }
+
+        let mut rules = Vec::new();
+        if let Some(n) = self.keep_last {
+            rules.push(format!("keep_last: {}", n));
+        }
+        rules.push(format!("keep_tagged: {}", self.keep_tagged != Some(false)));
+        if let Some(a) = self.max_age {
+            rules.push(format!("max_age: {}", format_age(a)));
+        }
+        write!(f, "{}", rules.join(", "))
+    }
+}
+
+impl Bin {
+    // Path of a blob's own config
+    fn blob_config_path(&self, blob: &String) -> PathBuf {
+        self.blobs.join(blob).join(BIN_CONFIG)
+    }
+
+    /// Retention set in a blob's own config.yml; unset rules if it has none
+    pub fn get_blob_retention(&self, blob: &String) -> Result<Retention, Error> {
+        let path = self.blob_config_path(blob);
+        if !path.exists() {
+            return Ok(Retention::default());
+        }
+
+        let contents = match fs::read_to_string(&path) {
+            Ok(c) => c,
+            Err(e) => {
+                return Err(Error::IOError {
+                    msg: format!("{:?}: {}", path, e),
+                })
+            }
+        };
+        let res = match serde_yaml::from_str(&contents) {
+            Ok(config) => Retention::from_config(&config),
+            Err(e) => Err(invalid(&e.to_string())),
+        };
+        match res {
+            Err(Error::InvalidBinConfig { msg }) => Err(Error::InvalidBinConfig {
+                msg: format!("{:?}: {}", path, msg),
+            }),
+            r => r,
+        }
+    }
+
+    /// Retention of a config: its blob's own rules over the bin-wide ones
+    pub fn get_retention(&self, blob: &String) -> Result<Retention, Error> {
+        Ok(self.get_blob_retention(blob)?.over(&self.config.retention))
+    }
+
+    /// Set a blob's own retention; an empty policy removes its config.yml
+    pub fn set_retention(
+        &self,
+        journal: &mut Journal,
+        blob: &String,
+        retention: &Retention,
+    ) -> Result<(), Error> {
+        let path = self.blob_config_path(blob);
+        if *retention == Retention::default() {
+            if path.exists() {
+                journal.backup(path.to_string_lossy().to_string())?;
+            }
+            return Ok(());
+        }
+
+        let contents = match serde_yaml::to_string(&retention.to_config()) {
+            Ok(c) => c,
+            Err(e) => return Err(Error::IOError { msg: e.to_string() }),
+        };
+        if let Some(parent) = path.parent() {
+            journal.create_dir(parent.to_string_lossy().to_string())?;
+        }
+        journal.write(path.to_string_lossy().to_string(), contents.as_bytes())
+    }
+
+    /// Remove the versions their config's retention no longer keeps
+    /// Map snapshots that reference a pruned version are dropped; versions in the latest
+    /// snapshot of a dir, or in a tagged one that is kept, are never pruned. Objects no
+    /// kept version needs, directly or as a delta base, are removed too, from packs as
+    /// well. Nothing is changed on a dry run
+    pub fn prune(&self, dry_run: bool) -> Result<PruneReport, Error> {
+        info!("Pruning bin {:?}", self.path);
+        let now = crate::now();
+        let mut report = PruneReport {
+            versions: Vec::new(),
+            maps: Vec::new(),
+            objects: Vec::new(),
+        };
+
+        let mut maps: BTreeMap<(String, u32), Vec<(String, u32)>> = BTreeMap::new();
+        let mut latest: HashMap<String, u32> = HashMap::new();
+        let mut tagged: HashSet<(String, u32)> = HashSet::new();
+        for m in self.db.get_all_maps()? {
+            let ver = latest.entry(m.blob.clone()).or_insert(m.ver);
+            *ver = (*ver).max(m.ver);
+            if !m.tag.is_empty() && self.get_retention(&m.blob)?.keep_tagged != Some(false) {
+                tagged.insert((m.blob.clone(), m.ver));
+            }
+            maps.insert(
+                (m.blob.clone(), m.ver),
+                self.get_map_entries(&m.blob, m.ver)?,
+            );
+        }
+
+        // versions the snapshots that are always kept depend on
+        let mut pinned: HashSet<(String, u32)> = HashSet::new();
+        for (key, entries) in &maps {
+            if latest.get(&key.0) == Some(&key.1) || tagged.contains(key) {
+                pinned.extend(entries.iter().cloned());
+            }
+        }
+
+        let mut owners: BTreeMap<String, Vec<Version>> = BTreeMap::new();
+        for v in self.db.get_all_versions()? {
+            owners.entry(v.owner.clone()).or_default().push(v);
+        }
+
+        let mut kept = Vec::new();
+        for (owner, mut versions) in owners {
+            let retention = self.get_retention(&owner)?;
+            versions.sort_by_key(|v| Reverse(v.ver));
+            for (newest, v) in versions.into_iter().enumerate() {
+                if retention.keeps(newest, &v, now) || pinned.contains(&(owner.clone(), v.ver)) {
+                    kept.push(v);
+                } else {
+                    debug!("{} V {} expired", owner, v.ver);
+                    report.versions.push((owner.clone(), v.ver));
+                }
+            }
+        }
+
+        let expired: HashSet<(String, u32)> = report.versions.iter().cloned().collect();
+        for (key, entries) in &maps {
+            if entries.iter().any(|e| expired.contains(e)) {
+                report.maps.push(key.clone());
+            }
+        }
+
+        // objects kept versions point to, and the bases of those stored as deltas
+        let mut live: HashSet<String> = HashSet::new();
+        for v in &kept {
+            if v.data_hash == "DIR" {
+                continue;
+            }
+            let mut hash = self.read_pointer(&v.owner, v.ver)?;
+            let mut codec = Some(v.codec);
+            while let Some(c) = codec {
+                if !live.insert(format!("{}{}", hash, c.extension())) || c != Codec::Delta {
+                    break;
+                }
+                hash = self.delta_base(&hash)?;
+                codec = self.find_object(&hash);
+            }
+        }
+
+        let mut loose: Vec<PathBuf> = Vec::new();
+        let mut dead: BTreeSet<String> = BTreeSet::new();
+        for (name, path) in self.loose_objects() {
+            if !live.contains(&name) {
+                loose.push(path);
+                dead.insert(name);
+            }
+        }
+
+        let mut repack = false;
+        for pack in self.get_packs() {
+            let names: Vec<&String> = pack.objects.keys().filter(|n| !live.contains(*n)).collect();
+            repack |= !names.is_empty() || pack.pointers.keys().any(|k| expired.contains(k));
+            dead.extend(names.into_iter().cloned());
+        }
+        report.objects = dead.into_iter().collect();
+
+        if dry_run {
+            return Ok(report);
+        }
+
+        self.transaction(|journal| {
+            for (owner, ver) in &report.versions {
+                self.db.remove_version(owner, *ver)?;
+                let pointer = self.pointer_path(owner, *ver);
+                if pointer.exists() {
+                    journal.backup(pointer.to_string_lossy().to_string())?;
+                }
+            }
+
+            for (blob, ver) in &report.maps {
+                self.db.remove_map(blob, *ver)?;
+                let file = format!("{}/{}-{}", self.get_maps_str()?, blob, ver);
+                if crate::exists(&file) {
+                    journal.backup(file)?;
+                }
+            }
+
+            for path in &loose {
+                journal.backup(path.to_string_lossy().to_string())?;
+            }
+
+            // packs are rewritten without what was pruned from them
+            if repack {
+                let (mut objects, mut pointers) = self.read_packs()?;
+                objects.retain(|name, _| live.contains(name));
+                pointers.retain(|key, _| !expired.contains(key));
+                self.replace_packs(journal, &objects, &pointers)?;
+            }
+            Ok(())
+        })?;
+
+        // fan dirs emptied here are removed, as pack does
+        for dir in loose.iter().filter_map(|p| p.parent()) {
+            if fs::remove_dir(dir).is_ok() {
+                debug!("Removed empty {:?}", dir);
+            }
+        }
+
+        info!(
+            "Pruned {} versions, {} maps and {} objects",
+            report.versions.len(),
+            report.maps.len(),
+            report.objects.len()
+        );
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{hash_bytes, Bin, Codec, FileMeta, LockWait, Retention, Version};
+
+    const DAY: i64 = 24 * 60 * 60;
+
+    fn version(owner: &str, ver: u32, tag: &str, created: i64) -> Version {
+        Version {
+            data_hash: String::new(),
+            ver,
+            tag: tag.to_string(),
+            owner: owner.to_string(),
+            codec: Codec::None,
+            encrypted: false,
+            created,
+            hostname: String::new(),
+            username: String::new(),
+            message: String::new(),
+            meta: FileMeta::default(),
+        }
+    }
+
+    // Which of versions 1..=5, created a day apart up to now, a policy keeps; v2 is tagged
+    fn kept(retention: &Retention) -> Vec<u32> {
+        let now = 10 * DAY;
+        let mut kept = Vec::new();
+        for (newest, ver) in (1..=5u32).rev().enumerate() {
+            let tag = if ver == 2 { "release" } else { "" };
+            let v = version("blob", ver, tag, now - (5 - ver as i64) * DAY);
+            if retention.keeps(newest, &v, now) {
+                kept.push(ver);
+            }
+        }
+        kept.sort();
+        kept
+    }
+
+    #[test]
+    fn versions_kept_by_policy() {
+        let policy = |keep_last, keep_tagged, max_age| Retention {
+            keep_last,
+            keep_tagged,
+            max_age,
+        };
+        let day = DAY as u64;
+
+        assert_eq!(kept(&policy(None, None, None)), vec![1, 2, 3, 4, 5]);
+        assert_eq!(kept(&policy(Some(2), None, None)), vec![2, 4, 5]);
+        assert_eq!(kept(&policy(Some(2), Some(false), None)), vec![4, 5]);
+        // ages are measured from the push; v3 is exactly two days old
+        assert_eq!(kept(&policy(None, Some(false), Some(2 * day))), vec![4, 5]);
+        assert_eq!(
+            kept(&policy(Some(1), Some(false), Some(3 * day))),
+            vec![3, 4, 5]
+        );
+        // the latest version is always kept
+        assert_eq!(kept(&policy(None, Some(false), Some(1))), vec![5]);
+    }
+
+    #[test]
+    fn policies_parse_and_layer() {
+        let parse = |yaml: &str| Retention::from_config(&serde_yaml::from_str(yaml).unwrap());
+
+        let blob = parse("retention:\n  keep_last: 3\n  max_age: 2w").unwrap();
+        assert_eq!(blob.keep_last, Some(3));
+        assert_eq!(blob.max_age, Some(14 * DAY as u64));
+        assert_eq!(
+            parse("retention:\n  keep_last: 3\n  max_age: 14d").unwrap(),
+            blob
+        );
+        assert_eq!(Retention::from_config(&blob.to_config()).unwrap(), blob);
+
+        let bin = parse("retention:\n  keep_last: 10\n  keep_tagged: false").unwrap();
+        let layered = blob.over(&bin);
+        assert_eq!(
+            (layered.keep_last, layered.keep_tagged, layered.max_age),
+            (Some(3), Some(false), blob.max_age)
+        );
+
+        assert_eq!(parse("name: x").unwrap(), Retention::default());
+        assert!(parse("retention:\n  keep_last: 0").is_err());
+        assert!(parse("retention:\n  max_age: 30").is_err());
+        assert!(parse("retention:\n  keep_first: 1").is_err());
+    }
+
+    #[test]
+    fn prune_keeps_delta_bases() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bin").to_string_lossy().to_string();
+        let mut bin = Bin::new(path, None, false, false, LockWait::Never).unwrap();
+        bin.config.delta = true;
+        bin.config.retention.keep_last = Some(1);
+
+        // "chain" is stored as deltas, each against the one before; "whole" is not
+        let now = crate::now();
+        let mut hashes = Vec::new();
+        for (owner, base_on_last) in [("chain", true), ("whole", false)] {
+            let blob = owner.to_string();
+            let mut base: Option<String> = None;
+            for ver in 1..=4u32 {
+                let lines: String = (0..100)
+                    .map(|i| format!("{} line {} of version {}\n", owner, i, ver.min(i)))
+                    .collect();
+                let data = lines.into_bytes();
+                let hash = hash_bytes(&data);
+                let base_ref = if base_on_last { base.as_ref() } else { None };
+                let codec = bin
+                    .transaction(|j| bin.store_version(j, &blob, ver, &hash, &data, base_ref))
+                    .unwrap();
+
+                let tag = if owner == "chain" && ver == 2 {
+                    "release"
+                } else {
+                    ""
+                };
+                let mut v = version(owner, ver, tag, now - (4 - ver as i64) * DAY);
+                v.data_hash = hash.clone();
+                v.codec = codec;
+                bin.db.add_version(&v).unwrap();
+                hashes.push((blob.clone(), ver, hash.clone(), codec, data));
+                base = Some(hash);
+            }
+        }
+        assert!(hashes[1..4].iter().all(|h| h.3 == Codec::Delta));
+
+        let report = bin.prune(true).unwrap();
+        let expected = vec![
+            ("chain".to_string(), 3),
+            ("chain".to_string(), 1),
+            ("whole".to_string(), 3),
+            ("whole".to_string(), 2),
+            ("whole".to_string(), 1),
+        ];
+        assert_eq!(report.versions, expected);
+        // the chain's objects are all bases of the kept v4
+        let mut dead: Vec<String> = hashes[4..7].iter().map(|h| h.2.clone()).collect();
+        dead.sort();
+        let mut objects: Vec<String> = report
+            .objects
+            .iter()
+            .map(|o| o.split('.').next().unwrap().to_string())
+            .collect();
+        objects.sort();
+        assert_eq!(objects, dead);
+
+        bin.prune(false).unwrap();
+        for (blob, ver, hash, codec, data) in &hashes {
+            let pruned = expected.contains(&(blob.clone(), *ver));
+            assert_eq!(bin.db.get_version(blob, *ver).unwrap().is_none(), pruned);
+            assert_eq!(bin.read_pointer(blob, *ver).is_err(), pruned);
+            if !pruned {
+                assert_eq!(&bin.read_object(hash, *codec).unwrap(), data);
+            }
+        }
+    }
+}
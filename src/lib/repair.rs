@@ -0,0 +1,765 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs,
+    path::PathBuf,
+};
+
+use log::{debug, error, info, warn};
+use sha2::{Digest, Sha512};
+use walkdir::WalkDir;
+
+use crate::bin::BIN_CONFIG;
+use crate::crypt::is_encrypted;
+use crate::hash_bytes;
+use crate::hash_path;
+use crate::objects::is_hash;
+use crate::pack::{INDEX_EXT, PACK_EXT};
+use crate::Bin;
+use crate::Codec;
+use crate::Config;
+use crate::Database;
+use crate::Error;
+use crate::FileMeta;
+use crate::Journal;
+use crate::Lock;
+use crate::LockMode;
+use crate::LockWait;
+use crate::Map;
+use crate::Mapper;
+use crate::Mapping;
+use crate::Pack;
+use crate::RepairReport;
+use crate::Version;
+
+const QUARANTINE_DIR: &str = "quarantine";
+
+/// Whatever could still be read from the damaged db
+/// every query is attempted on its own so one bad table does not lose the rest
+struct Salvage {
+    configs: Vec<Config>,
+    tags: HashMap<(String, u32), String>,
+    dirs: HashSet<(String, u32)>,
+    // keyed hashes of encrypted versions; these cannot be recomputed without the key
+    sealed: HashMap<(String, u32), String>,
+    created: HashMap<(String, u32), i64>,
+    // hostname, username and message of each version
+    origins: HashMap<(String, u32), (String, String, String)>,
+    // mode, ownership and mtime of each version
+    metas: HashMap<(String, u32), FileMeta>,
+    map_tags: HashMap<(String, u32), String>,
+    // created, hostname, username and message of each map
+    map_origins: HashMap<(String, u32), (i64, String, String, String)>,
+    mappings: Vec<Mapping>,
+    binfo: BTreeMap<String, String>,
+}
+
+impl Salvage {
+    fn from(db: &Database) -> Self {
+        let mut salvage = Salvage {
+            configs: Vec::new(),
+            tags: HashMap::new(),
+            dirs: HashSet::new(),
+            sealed: HashMap::new(),
+            created: HashMap::new(),
+            origins: HashMap::new(),
+            metas: HashMap::new(),
+            map_tags: HashMap::new(),
+            map_origins: HashMap::new(),
+            mappings: Vec::new(),
+            binfo: BTreeMap::new(),
+        };
+
+        if !db.path.exists() {
+            warn!("No db to salvage from");
+            return salvage;
+        }
+
+        match db.get_configs() {
+            Ok(c) => salvage.configs = c,
+            Err(e) => warn!("Could not salvage configs: {}", e),
+        }
+
+        match db.salvage_versions() {
+            Ok(versions) => {
+                for v in versions {
+                    if v.data_hash == "DIR" {
+                        salvage.dirs.insert((v.owner.clone(), v.ver));
+                    }
+                    if v.encrypted {
+                        salvage.sealed.insert((v.owner.clone(), v.ver), v.data_hash);
+                    }
+                    salvage.created.insert((v.owner.clone(), v.ver), v.created);
+                    salvage.origins.insert(
+                        (v.owner.clone(), v.ver),
+                        (v.hostname, v.username, v.message),
+                    );
+                    salvage.metas.insert((v.owner.clone(), v.ver), v.meta);
+                    salvage.tags.insert((v.owner, v.ver), v.tag);
+                }
+            }
+            Err(e) => warn!("Could not salvage versions: {}", e),
+        }
+
+        match db.salvage_maps() {
+            Ok(maps) => {
+                for m in maps {
+                    salvage.map_origins.insert(
+                        (m.blob.clone(), m.ver),
+                        (m.created, m.hostname, m.username, m.message),
+                    );
+                    salvage.map_tags.insert((m.blob, m.ver), m.tag);
+                }
+            }
+            Err(e) => warn!("Could not salvage maps: {}", e),
+        }
+
+        match db.get_mappings(&None) {
+            Ok(m) => salvage.mappings = m,
+            Err(e) => warn!("Could not salvage mappings: {}", e),
+        }
+
+        match db.get_profiles() {
+            Ok(profiles) => {
+                for p in profiles {
+                    match db.get_mappings(&Some(p.clone())) {
+                        Ok(m) => salvage.mappings.extend(m),
+                        Err(e) => warn!("Could not salvage profile {}: {}", p, e),
+                    }
+                }
+            }
+            Err(e) => warn!("Could not salvage profiles: {}", e),
+        }
+
+        match db.get_binfo() {
+            Ok(b) => salvage.binfo = b,
+            Err(e) => warn!("Could not salvage binfo: {}", e),
+        }
+
+        info!(
+            "Salvaged {} configs, {} versions and {} mappings",
+            salvage.configs.len(),
+            salvage.tags.len(),
+            salvage.mappings.len()
+        );
+
+        salvage
+    }
+}
+
+/// Move an unreadable file out of the bin's tree
+/// the move is journaled, so a failed repair puts the file back
+fn quarantine(
+    bin: &Bin,
+    journal: &mut Journal,
+    path: &PathBuf,
+    name: &String,
+) -> Result<(), Error> {
+    let dir = bin.path.join(QUARANTINE_DIR);
+    if !dir.exists() {
+        journal.create_dir(dir.to_string_lossy().to_string())?;
+    }
+
+    warn!("Quarantining {:?}", path);
+    journal.rename(
+        path.to_string_lossy().to_string(),
+        dir.join(name).to_string_lossy().to_string(),
+    )
+}
+
+/// Find the object a version's blob file points to, and whether it is encrypted
+/// Format 1 blob files hold the contents themselves; those are moved into objects
+fn recover_pointer(
+    bin: &Bin,
+    journal: &mut Journal,
+    blob: &String,
+    ver: u32,
+    objects: &mut HashMap<String, Option<bool>>,
+) -> Result<(String, bool), Error> {
+    let path = bin.pointer_path(blob, ver);
+    let data = match fs::read(&path) {
+        Ok(d) => d,
+        Err(e) => {
+            return Err(Error::IOError {
+                msg: format!("{:?}: {}", path, e),
+            })
+        }
+    };
+
+    // contents that merely look like a hash are kept, unless that object is stored
+    let text = String::from_utf8_lossy(&data).trim().to_string();
+    if is_hash(&text) && bin.find_object(&text).is_some() {
+        return match check_object(bin, &text, objects)? {
+            Some(encrypted) => Ok((text, encrypted)),
+            None => Err(Error::IOError {
+                msg: format!("{:?}: object {} is missing or corrupt", path, text),
+            }),
+        };
+    }
+
+    info!("Moving contents of {:?} into objects", path);
+    let hash = hash_bytes(&data);
+    journal.backup(path.to_string_lossy().to_string())?;
+    let codec = bin.store_version(journal, blob, ver, &hash, &data, None)?;
+    objects.insert(format!("{}{}", hash, codec.extension()), Some(false));
+    Ok((hash, false))
+}
+
+/// Check the object with the given hash is intact, and whether it is encrypted
+/// each object is rehashed once; None if it is missing or corrupt
+fn check_object(
+    bin: &Bin,
+    hash: &String,
+    objects: &mut HashMap<String, Option<bool>>,
+) -> Result<Option<bool>, Error> {
+    let checked = match bin.find_object(hash) {
+        Some(codec) => *objects
+            .entry(format!("{}{}", hash, codec.extension()))
+            .or_insert_with(|| match bin.read_object(hash, codec) {
+                Ok(d) if hash_bytes(&d) == *hash => Some(is_encrypted(&d)),
+                _ => None,
+            }),
+        None => None,
+    };
+    if checked.is_some() {
+        keep_delta_bases(bin, hash, objects)?;
+    }
+    Ok(checked)
+}
+
+/// Mark the bases a delta object is rebuilt from as intact, so they are kept
+/// the delta rebuilt to its own hash, so its bases must be intact too
+fn keep_delta_bases(
+    bin: &Bin,
+    hash: &str,
+    objects: &mut HashMap<String, Option<bool>>,
+) -> Result<(), Error> {
+    let mut cur = hash.to_string();
+    while bin.find_object(&cur) == Some(Codec::Delta) {
+        cur = bin.delta_base(&cur)?;
+        if let Some(codec) = bin.find_object(&cur) {
+            objects
+                .entry(format!("{}{}", cur, codec.extension()))
+                .or_insert(Some(false));
+        }
+    }
+    Ok(())
+}
+
+/// Sorted names of all entries in a dir; empty if the dir is gone
+fn dir_entries(path: &PathBuf) -> Vec<(String, PathBuf)> {
+    let mut entries: Vec<(String, PathBuf)> = match fs::read_dir(path) {
+        Ok(e) => e
+            .flatten()
+            .map(|e| (e.file_name().to_string_lossy().to_string(), e.path()))
+            .collect(),
+        Err(e) => {
+            warn!("{:?}: {}", path, e);
+            Vec::new()
+        }
+    };
+    entries.sort();
+    entries
+}
+
+impl Bin {
+    /// Rebuild the db of a damaged bin from its blobs and maps
+    /// Versions are recovered from their pointers and rehashed objects, and maps are
+    /// re-read from their files; unreadable files and unreferenced objects are moved to
+    /// quarantine/. Config rows only store the map path,
+    /// so they come from the old db or from walking the search paths
+    /// The old db is kept as meld.db.bak, or meld.db.bak.N if a backup already exists
+    pub fn repair(path: String, search: &[String], wait: LockWait) -> Result<RepairReport, Error> {
+        info!("Repairing bin at {}", path);
+        let mut bin = Bin::at(&path);
+
+        if !bin.path.exists() {
+            return Err(Error::FileNotFound { msg: path });
+        }
+        bin.lock = Some(Lock::acquire(&bin.path, LockMode::Exclusive, wait)?);
+        bin.load_config()?;
+        for dir in [&bin.blobs, &bin.maps, &bin.objects] {
+            if let Err(e) = fs::create_dir_all(dir) {
+                return Err(Error::IOError { msg: e.to_string() });
+            }
+        }
+
+        // build the new db next to the old one and swap it in at the end
+        let db = Database::new(bin.path.join(format!("{}.repair", crate::bin::MELD_DB)));
+        if db.path.exists() {
+            if let Err(e) = fs::remove_file(&db.path) {
+                return Err(Error::IOError { msg: e.to_string() });
+            }
+        }
+        db.create_db_schema()?;
+
+        // file moves are undone if the repair fails
+        let mut journal = Journal::new();
+        let report = match rebuild(&bin, &db, search, &mut journal) {
+            Ok(r) => r,
+            Err(e) => {
+                error!("Repair failed; rolling back");
+                journal.rollback();
+                return Err(e);
+            }
+        };
+        journal.finish();
+
+        // close both dbs before swapping the files
+        let root = bin.path.clone();
+        let (old_path, new_path) = (bin.db.path.clone(), db.path.clone());
+        let lock = bin.lock.take();
+        drop(bin);
+        drop(db);
+
+        // keep the damaged db around in case anything else can be pulled from it
+        if old_path.exists() {
+            let mut backup = root.join(format!("{}.bak", crate::bin::MELD_DB));
+            let mut n = 1;
+            while backup.exists() {
+                backup = root.join(format!("{}.bak.{}", crate::bin::MELD_DB, n));
+                n += 1;
+            }
+            if let Err(e) = fs::rename(&old_path, backup) {
+                return Err(Error::IOError { msg: e.to_string() });
+            }
+        }
+        if let Err(e) = fs::rename(&new_path, &old_path) {
+            return Err(Error::IOError { msg: e.to_string() });
+        }
+        drop(lock);
+
+        Ok(report)
+    }
+}
+
+/// Recover every version, map and config the bin's files and old db still hold into db
+/// unreadable files and unreferenced objects are moved to quarantine/ through the journal
+fn rebuild(
+    bin: &Bin,
+    db: &Database,
+    search: &[String],
+    journal: &mut Journal,
+) -> Result<RepairReport, Error> {
+    let salvage = Salvage::from(&bin.db);
+    let mut report = RepairReport {
+        versions: 0,
+        maps: 0,
+        configs: 0,
+        quarantined: Vec::new(),
+        unrecovered: Vec::new(),
+    };
+
+    for m in &salvage.mappings {
+        db.add_mapping(m)?;
+    }
+    // a db lost with its mappings starts over from the bin config's defaults
+    db.init_mappings(&bin.get_config().mappings)?;
+    for (key, value) in &salvage.binfo {
+        db.set_binfo(key, value)?;
+    }
+
+    // (blob, ver) -> data hash of every stored version
+    let mut versions: BTreeMap<(String, u32), String> = BTreeMap::new();
+    let mut empty_blobs = Vec::new();
+    // object hash of every encrypted version
+    let mut sealed: HashMap<(String, u32), String> = HashMap::new();
+    // object file name -> whether it is encrypted, or None if it is corrupt
+    let mut objects: HashMap<String, Option<bool>> = HashMap::new();
+    // (blob, ver) -> object hash and whether it is encrypted
+    let mut recovered: BTreeMap<(String, u32), (String, bool)> = BTreeMap::new();
+
+    // packs with an unreadable index are moved aside whole, before any are read
+    if bin.packs.exists() {
+        for (file, path) in dir_entries(&bin.packs) {
+            let index = path.with_extension(INDEX_EXT);
+            match Pack::open(&index) {
+                Ok(_) => continue,
+                Err(e) if path.exists() => warn!("{}", e),
+                // already moved with its index
+                Err(_) => continue,
+            }
+
+            for p in [path, index.clone(), index.with_extension(PACK_EXT)] {
+                if let (true, Some(name)) = (p.exists(), p.file_name()) {
+                    let name = name.to_string_lossy().to_string();
+                    quarantine(bin, journal, &p, &name)?;
+                    report.quarantined.push(name);
+                }
+            }
+            debug!("Quarantined pack {}", file);
+        }
+    }
+
+    for (blob, blob_path) in dir_entries(&bin.blobs) {
+        if !blob_path.is_dir() {
+            quarantine(bin, journal, &blob_path, &blob)?;
+            report.quarantined.push(blob);
+            continue;
+        }
+
+        let mut found = false;
+        for (file, file_path) in dir_entries(&blob_path) {
+            let ver = match file.parse::<u32>() {
+                Ok(v) => v,
+                // a blob's own config is kept as long as it parses
+                Err(_) if file == BIN_CONFIG && bin.get_blob_retention(&blob).is_ok() => continue,
+                Err(_) => {
+                    let name = format!("{}-{}", blob, file);
+                    quarantine(bin, journal, &file_path, &name)?;
+                    report.quarantined.push(name);
+                    continue;
+                }
+            };
+
+            match recover_pointer(bin, journal, &blob, ver, &mut objects) {
+                Ok(r) => {
+                    recovered.insert((blob.clone(), ver), r);
+                    found = true;
+                }
+                Err(e) => {
+                    warn!("{}", e);
+                    let name = format!("{}-{}", blob, file);
+                    quarantine(bin, journal, &file_path, &name)?;
+                    report.quarantined.push(name);
+                }
+            }
+        }
+
+        // dirs are stored as an empty blob dir
+        if !found {
+            empty_blobs.push(blob);
+        }
+    }
+
+    // packed pointers are only used for versions without a loose one
+    for pack in bin.get_packs() {
+        for (key, hash) in &pack.pointers {
+            if recovered.contains_key(key) {
+                continue;
+            }
+            match check_object(bin, hash, &mut objects)? {
+                Some(encrypted) => {
+                    recovered.insert(key.clone(), (hash.clone(), encrypted));
+                }
+                None => warn!(
+                    "{} V {} in pack {}: object {} is missing or corrupt",
+                    key.0,
+                    key.1,
+                    pack.name(),
+                    hash
+                ),
+            }
+        }
+    }
+    empty_blobs.retain(|b| !recovered.keys().any(|(o, _)| o == b));
+
+    for (key, (hash, encrypted)) in recovered {
+        if !encrypted {
+            versions.insert(key, hash);
+            continue;
+        }
+        // without the old hash the version shows as drifted until pushed again
+        let data_hash = match salvage.sealed.get(&key) {
+            Some(h) => h.to_string(),
+            None => {
+                warn!("No recorded hash for encrypted {} V {}", key.0, key.1);
+                hash.clone()
+            }
+        };
+        versions.insert(key.clone(), data_hash);
+        sealed.insert(key, hash);
+    }
+
+    // keep only intact objects some version points to
+    for (fan, fan_path) in dir_entries(&bin.objects) {
+        if !fan_path.is_dir() {
+            quarantine(bin, journal, &fan_path, &fan)?;
+            report.quarantined.push(fan);
+            continue;
+        }
+        for (name, path) in dir_entries(&fan_path) {
+            if matches!(objects.get(&name), Some(Some(_))) && name.starts_with(&fan) {
+                continue;
+            }
+            quarantine(bin, journal, &path, &name)?;
+            report.quarantined.push(name);
+        }
+    }
+
+    // (map blob, ver) -> entries of every readable map file
+    let mut maps: BTreeMap<(String, u32), Vec<(String, u32)>> = BTreeMap::new();
+    for (file, file_path) in dir_entries(&bin.maps) {
+        let parsed = file
+            .split_once('-')
+            .and_then(|(b, v)| v.parse::<u32>().ok().map(|v| (b.to_string(), v)));
+
+        let result = match parsed {
+            Some((blob, ver)) => match bin.get_map_entries(&blob, ver) {
+                Ok(entries) => Ok((blob, ver, entries)),
+                Err(e) => Err(e.to_string()),
+            },
+            None => Err("not a map file".to_string()),
+        };
+
+        match result {
+            Ok((blob, ver, entries)) => {
+                maps.insert((blob, ver), entries);
+            }
+            Err(e) => {
+                warn!("{}: {}", file, e);
+                quarantine(bin, journal, &file_path, &file)?;
+                report.quarantined.push(file);
+            }
+        }
+    }
+
+    // dirs have no contents; their versions are only known from maps or the old db
+    for entries in maps.values() {
+        for (blob, ver) in entries {
+            let key = (blob.clone(), *ver);
+            if !versions.contains_key(&key) && empty_blobs.contains(blob) {
+                versions.insert(key, "DIR".to_string());
+            }
+        }
+    }
+    for key in &salvage.dirs {
+        if empty_blobs.contains(&key.0) {
+            versions.insert(key.clone(), "DIR".to_string());
+        }
+    }
+    for blob in &empty_blobs {
+        if !versions.keys().any(|(b, _)| b == blob) {
+            versions.insert((blob.clone(), 1), "DIR".to_string());
+        }
+    }
+
+    for ((owner, ver), data_hash) in &versions {
+        let key = (owner.clone(), *ver);
+        let object = sealed.get(&key).unwrap_or(data_hash);
+        let (hostname, username, message) = salvage.origins.get(&key).cloned().unwrap_or_default();
+        db.add_version(&Version {
+            data_hash: data_hash.to_string(),
+            ver: *ver,
+            tag: salvage.tags.get(&key).cloned().unwrap_or_default(),
+            owner: owner.to_string(),
+            codec: bin.find_object(object).unwrap_or(Codec::None),
+            encrypted: sealed.contains_key(&key),
+            created: match salvage.created.get(&key) {
+                Some(c) => *c,
+                None => bin.pointer_time(owner, *ver),
+            },
+            hostname,
+            username,
+            message,
+            meta: salvage.metas.get(&key).cloned().unwrap_or_default(),
+        })?;
+        report.versions += 1;
+    }
+
+    // the map hash covers the data hashes of its entries, in order
+    for ((blob, ver), entries) in &maps {
+        let mut hasher = Sha512::new();
+        for (b, v) in entries {
+            match versions.get(&(b.clone(), *v)) {
+                Some(h) => hasher.update(h),
+                None => warn!(
+                    "Map {}-{} references missing version {}-{}",
+                    blob, ver, b, v
+                ),
+            }
+        }
+
+        let (created, hostname, username, message) =
+            match salvage.map_origins.get(&(blob.clone(), *ver)) {
+                Some(o) => o.clone(),
+                None => (
+                    bin.map_time(blob, *ver),
+                    String::new(),
+                    String::new(),
+                    String::new(),
+                ),
+            };
+        db.add_map(&Map {
+            blob: blob.to_string(),
+            ver: *ver,
+            hash: format!("{:x}", hasher.finalize()),
+            tag: salvage
+                .map_tags
+                .get(&(blob.clone(), *ver))
+                .cloned()
+                .unwrap_or_default(),
+            created,
+            hostname,
+            username,
+            message,
+            configs: Vec::new(),
+        })?;
+        report.maps += 1;
+    }
+
+    // config blobs are hashed map paths; only a known path can restore the row
+    let owners: HashSet<&String> = versions.keys().map(|(b, _)| b).collect();
+    let mut restored: HashSet<String> = HashSet::new();
+
+    for c in salvage.configs {
+        if owners.contains(&c.blob) && restored.insert(c.blob.clone()) {
+            db.add_config(&c)?;
+        }
+    }
+
+    if !search.is_empty() {
+        let mapper = repair_mapper(db)?;
+        for root in search {
+            for e in WalkDir::new(root).into_iter().flatten() {
+                let real_path = e.path().to_string_lossy().to_string();
+                let map_path = match mapper.real_path_to_map(&real_path) {
+                    Ok(m) => m,
+                    Err(_) => continue,
+                };
+                let blob = hash_path(&map_path);
+                if owners.contains(&blob) && restored.insert(blob.clone()) {
+                    info!("Recovered {} from {}", map_path, real_path);
+                    db.add_config(&Config {
+                        blob,
+                        real_path,
+                        subset: String::new(),
+                        family: String::new(),
+                        map_path,
+                        tag: String::new(),
+                        hash: String::new(),
+                        meta: FileMeta::default(),
+                        encrypt: false,
+                        message: String::new(),
+                        versions: HashMap::new(),
+                    })?;
+                }
+            }
+        }
+    }
+
+    report.configs = restored.len();
+    for blob in owners {
+        if !restored.contains(blob) {
+            report.unrecovered.push(blob.to_string());
+        }
+    }
+    report.unrecovered.sort();
+
+    Ok(report)
+}
+
+/// Mapper for recovering map paths; uses this host's profile if the bin has one
+fn repair_mapper(db: &Database) -> Result<Mapper, Error> {
+    let host = crate::hostname();
+    let mappings = db.get_mappings(&None)?;
+
+    if db.get_profiles()?.contains(&host) {
+        return Ok(Mapper::with_profile(
+            mappings,
+            db.get_mappings(&Some(host))?,
+        ));
+    }
+    Ok(Mapper::new(mappings))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use rusqlite::Connection;
+
+    use crate::{hash_bytes, Bin, FileMeta, LockMode, LockWait, Version};
+
+    fn bin_path(dir: &tempfile::TempDir) -> String {
+        dir.path().join("bin").to_string_lossy().to_string()
+    }
+
+    // Store a tagged version of a blob as push would
+    fn store(bin: &Bin, blob: &str, ver: u32, data: &[u8], tag: &str) {
+        let blob = blob.to_string();
+        let hash = hash_bytes(data);
+        bin.transaction(|j| {
+            let codec = bin.store_version(j, &blob, ver, &hash, data, None)?;
+            bin.db.add_version(&Version {
+                data_hash: hash.clone(),
+                ver,
+                tag: tag.to_string(),
+                owner: blob.clone(),
+                codec,
+                encrypted: false,
+                created: 1000,
+                hostname: "host".to_string(),
+                username: "user".to_string(),
+                message: "message".to_string(),
+                meta: FileMeta::default(),
+            })
+        })
+        .unwrap();
+    }
+
+    fn repaired(dir: &tempfile::TempDir) -> Bin {
+        Bin::repair(bin_path(dir), &[], LockWait::Never).unwrap();
+        Bin::from(bin_path(dir), None, LockMode::Shared, LockWait::Never).unwrap()
+    }
+
+    #[test]
+    fn versions_salvaged_from_older_schemas() {
+        let dir = tempfile::tempdir().unwrap();
+        let bin = Bin::new(bin_path(&dir), None, false, false, LockWait::Never).unwrap();
+        store(&bin, "blob", 1, b"one", "first");
+        drop(bin);
+
+        // as a db from before the columns were added
+        let con = Connection::open(dir.path().join("bin/meld.db")).unwrap();
+        con.execute_batch(
+            "ALTER TABLE versions DROP COLUMN created;
+            ALTER TABLE versions DROP COLUMN message;
+            ALTER TABLE versions DROP COLUMN mtime;",
+        )
+        .unwrap();
+        drop(con);
+
+        let bin = repaired(&dir);
+        let v = bin.db.get_version(&"blob".to_string(), 1).unwrap().unwrap();
+        assert_eq!(v.tag, "first");
+        assert_eq!(v.hostname, "host");
+        assert_eq!(v.message, "");
+        assert_eq!(bin.read_blob(&"blob".to_string(), 1).unwrap(), b"one");
+    }
+
+    #[test]
+    fn hash_like_contents_are_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let bin = Bin::new(bin_path(&dir), None, false, false, LockWait::Never).unwrap();
+        store(&bin, "pointer", 1, b"one", "");
+        drop(bin);
+
+        // a blob file from before objects, whose contents look like a pointer
+        let contents = hash_bytes(b"not stored");
+        fs::create_dir_all(dir.path().join("bin/blobs/contents")).unwrap();
+        fs::write(dir.path().join("bin/blobs/contents/1"), &contents).unwrap();
+
+        let bin = repaired(&dir);
+        assert_eq!(
+            bin.read_blob(&"contents".to_string(), 1).unwrap(),
+            contents.as_bytes()
+        );
+        assert_eq!(bin.read_blob(&"pointer".to_string(), 1).unwrap(), b"one");
+    }
+
+    #[test]
+    fn failed_repair_restores_quarantined_files() {
+        let dir = tempfile::tempdir().unwrap();
+        drop(Bin::new(bin_path(&dir), None, false, false, LockWait::Never).unwrap());
+
+        let blobs = dir.path().join("bin/blobs");
+        fs::write(blobs.join("a"), "stray").unwrap();
+        fs::write(blobs.join("z"), "stray").unwrap();
+        // z can not be moved over a dir, which fails the repair after a was moved
+        fs::create_dir_all(dir.path().join("bin/quarantine/z/taken")).unwrap();
+
+        assert!(Bin::repair(bin_path(&dir), &[], LockWait::Never).is_err());
+        assert!(blobs.join("a").exists());
+        assert!(!dir.path().join("bin/quarantine/a").exists());
+    }
+}
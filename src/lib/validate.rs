@@ -0,0 +1,506 @@
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    fmt, fs,
+};
+
+use log::{info, warn};
+
+use crate::bin::BIN_CONFIG;
+use crate::hash_bytes;
+use crate::objects::is_hash;
+use crate::pack::{INDEX_EXT, PACK_EXT};
+use crate::Bin;
+use crate::Codec;
+use crate::Error;
+use crate::Issue;
+use crate::Pack;
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Issue::Schema { msg } => write!(f, "schema: {}", msg),
+            Issue::HashMismatch { blob, ver } => {
+                write!(f, "hash mismatch: {}/{}", blob, ver)
+            }
+            Issue::MissingBlob { blob, ver } => write!(f, "missing blob: {}/{}", blob, ver),
+            Issue::MissingObject { blob, ver, hash } => {
+                write!(f, "missing object: {} for {}/{}", hash, blob, ver)
+            }
+            Issue::CorruptObject { hash } => write!(f, "corrupt object: {}", hash),
+            Issue::CorruptPack { pack } => write!(f, "corrupt pack: {}", pack),
+            Issue::OrphanObject { hash } => write!(f, "object without version: {}", hash),
+            Issue::OrphanBlob { blob } => write!(f, "orphan blob dir: {}", blob),
+            Issue::OrphanBlobFile { blob, file } => {
+                write!(f, "orphan blob file: {}/{}", blob, file)
+            }
+            Issue::OrphanVersion { blob, ver } => {
+                write!(f, "version without config: {}/{}", blob, ver)
+            }
+            Issue::UnversionedConfig { blob } => write!(f, "config without versions: {}", blob),
+            Issue::MissingMapFile { blob, ver } => {
+                write!(f, "missing map file: {}-{}", blob, ver)
+            }
+            Issue::InvalidBlobConfig { blob } => {
+                write!(f, "invalid blob config: {}/{}", blob, BIN_CONFIG)
+            }
+            Issue::OrphanMapFile { file } => write!(f, "map file without map: {}", file),
+            Issue::BadMapEntry { file, entry } => {
+                write!(f, "map {} references unknown version {}", file, entry)
+            }
+        }
+    }
+}
+
+/// List the names of all entries in a dir
+fn dir_names(path: &String) -> Result<Vec<String>, Error> {
+    match fs::read_dir(path) {
+        Ok(entries) => Ok(entries
+            .flatten()
+            .map(|e| e.file_name().to_string_lossy().to_string())
+            .collect()),
+        Err(e) => Err(Error::IOError {
+            msg: format!("{}: {}", path, e),
+        }),
+    }
+}
+
+impl Bin {
+    /// Check the db, blobs and maps of the bin against each other
+    /// Every stored blob is rehashed; encrypted objects are named by the hash of their
+    /// ciphertext, so they are checked without the key. Returns all issues found
+    pub fn validate(&self) -> Result<Vec<Issue>, Error> {
+        info!("Validating bin {:?}", self.path);
+        let mut issues = Vec::new();
+
+        for msg in self.db.check_schema()? {
+            issues.push(Issue::Schema { msg });
+        }
+        // the remaining checks read the db through the current schema
+        if !issues.is_empty() {
+            return Ok(issues);
+        }
+
+        let configs: HashSet<String> = self
+            .db
+            .get_configs()?
+            .iter()
+            .map(|c| c.get_blob().to_string())
+            .collect();
+
+        // owner -> stored versions
+        let mut versions: HashMap<String, HashSet<u32>> = HashMap::new();
+        let mut referenced: HashSet<String> = HashSet::new();
+        for v in self.db.get_all_versions()? {
+            versions.entry(v.owner.clone()).or_default().insert(v.ver);
+
+            if !configs.contains(&v.owner) {
+                issues.push(Issue::OrphanVersion {
+                    blob: v.owner.clone(),
+                    ver: v.ver,
+                });
+            }
+
+            // dirs have no stored contents
+            if v.data_hash == "DIR" {
+                continue;
+            }
+
+            // encrypted versions point at their ciphertext's hash instead
+            if !v.encrypted {
+                referenced.insert(format!("{}{}", v.data_hash, v.codec.extension()));
+            }
+            match self.read_pointer(&v.owner, v.ver) {
+                Ok(hash) if !v.encrypted && hash != v.data_hash => {
+                    issues.push(Issue::HashMismatch {
+                        blob: v.owner,
+                        ver: v.ver,
+                    });
+                }
+                Ok(hash) => {
+                    referenced.insert(format!("{}{}", hash, v.codec.extension()));
+                    if !self.has_object(&hash, v.codec) {
+                        issues.push(Issue::MissingObject {
+                            blob: v.owner,
+                            ver: v.ver,
+                            hash,
+                        });
+                    }
+                }
+                Err(e) => {
+                    warn!("{}", e);
+                    issues.push(Issue::MissingBlob {
+                        blob: v.owner,
+                        ver: v.ver,
+                    });
+                }
+            }
+        }
+
+        for c in &configs {
+            if !versions.contains_key(c) {
+                issues.push(Issue::UnversionedConfig {
+                    blob: c.to_string(),
+                });
+            }
+        }
+
+        // every blob dir and file must belong to a config version
+        let blobs = self.get_blobs_str()?;
+        for blob in dir_names(&blobs)? {
+            let known = match versions.get(&blob) {
+                Some(k) => k,
+                None => {
+                    issues.push(Issue::OrphanBlob { blob });
+                    continue;
+                }
+            };
+
+            for file in dir_names(&format!("{}/{}", blobs, blob))? {
+                if file == BIN_CONFIG {
+                    if self.get_blob_retention(&blob).is_err() {
+                        issues.push(Issue::InvalidBlobConfig { blob: blob.clone() });
+                    }
+                    continue;
+                }
+                let tracked = match file.parse::<u32>() {
+                    Ok(ver) => known.contains(&ver),
+                    Err(_) => false,
+                };
+                if !tracked {
+                    issues.push(Issue::OrphanBlobFile {
+                        blob: blob.clone(),
+                        file,
+                    });
+                }
+            }
+        }
+
+        // packs are named by the hash of their contents and index
+        let mut packs = Vec::new();
+        if self.packs.exists() {
+            let dir = self.packs.to_string_lossy().to_string();
+            for file in dir_names(&dir)? {
+                let index = self.packs.join(&file).with_extension(INDEX_EXT);
+                let (name, ext) = file.split_once('.').unwrap_or((&file, ""));
+                if ext == PACK_EXT && index.exists() {
+                    continue;
+                }
+
+                let intact = ext == INDEX_EXT
+                    && match fs::read(index.with_extension(PACK_EXT))
+                        .and_then(|p| fs::read(&index).map(|i| [p, i].concat()))
+                    {
+                        Ok(data) => hash_bytes(&data) == name,
+                        Err(e) => {
+                            warn!("{}", e);
+                            false
+                        }
+                    };
+                // a readable pack is still checked object by object
+                match Pack::open(&index) {
+                    Ok(pack) => {
+                        if !intact {
+                            issues.push(Issue::CorruptPack { pack: file });
+                        }
+                        packs.push(pack);
+                    }
+                    Err(e) => {
+                        warn!("{}", e);
+                        issues.push(Issue::CorruptPack { pack: file });
+                    }
+                }
+            }
+        }
+
+        for pack in &packs {
+            for (blob, ver) in pack.pointers.keys() {
+                if !versions.get(blob).is_some_and(|k| k.contains(ver)) {
+                    issues.push(Issue::OrphanBlobFile {
+                        blob: blob.clone(),
+                        file: format!("{} (pack {})", ver, pack.name()),
+                    });
+                }
+            }
+        }
+
+        // objects are shared, so each is decompressed and rehashed once
+        let objects = self.objects.to_string_lossy().to_string();
+        let mut names: BTreeSet<String> = BTreeSet::new();
+        for fan in dir_names(&objects)? {
+            for name in dir_names(&format!("{}/{}", objects, fan))? {
+                match Codec::from_object_name(&name) {
+                    Some((hash, _)) if hash.starts_with(&fan) && is_hash(&hash) => {
+                        names.insert(name);
+                    }
+                    _ => issues.push(Issue::CorruptObject { hash: name }),
+                }
+            }
+        }
+        for pack in &packs {
+            names.extend(pack.objects.keys().cloned());
+        }
+
+        for name in &names {
+            let intact = match Codec::from_object_name(name) {
+                Some((hash, codec)) => match self.read_object(&hash, codec) {
+                    Ok(data) => hash_bytes(&data) == hash,
+                    Err(e) => {
+                        warn!("{}", e);
+                        false
+                    }
+                },
+                None => false,
+            };
+            if !intact {
+                issues.push(Issue::CorruptObject { hash: name.clone() });
+            }
+        }
+
+        // objects that referenced deltas are rebuilt from are in use too
+        let mut pending: Vec<String> = referenced.iter().cloned().collect();
+        while let Some(name) = pending.pop() {
+            let hash = match Codec::from_object_name(&name) {
+                Some((hash, Codec::Delta)) => hash,
+                _ => continue,
+            };
+            let base = match self.delta_base(&hash) {
+                Ok(b) => b,
+                Err(_) => continue,
+            };
+            if let Some(codec) = self.find_object(&base) {
+                let base = format!("{}{}", base, codec.extension());
+                if referenced.insert(base.clone()) {
+                    pending.push(base);
+                }
+            }
+        }
+
+        for name in names {
+            if !referenced.contains(&name) {
+                issues.push(Issue::OrphanObject { hash: name });
+            }
+        }
+
+        // every map row needs a file, and every file entry a version
+        let maps: HashSet<String> = self
+            .db
+            .get_all_maps()?
+            .iter()
+            .map(|m| format!("{}-{}", m.blob, m.ver))
+            .collect();
+
+        let map_files: HashSet<String> = dir_names(&self.get_maps_str()?)?.into_iter().collect();
+
+        for m in &maps {
+            if !map_files.contains(m) {
+                let (blob, ver) = m.split_once('-').unwrap();
+                issues.push(Issue::MissingMapFile {
+                    blob: blob.to_string(),
+                    ver: ver.parse().unwrap_or(0),
+                });
+            }
+        }
+
+        for file in map_files {
+            if !maps.contains(&file) {
+                issues.push(Issue::OrphanMapFile { file });
+                continue;
+            }
+
+            let (blob, ver) = file.split_once('-').unwrap();
+            let entries = match self.get_map_entries(&blob.to_string(), ver.parse().unwrap_or(0)) {
+                Ok(e) => e,
+                Err(e) => {
+                    warn!("{}", e);
+                    issues.push(Issue::BadMapEntry {
+                        file: file.clone(),
+                        entry: "(unreadable)".to_string(),
+                    });
+                    continue;
+                }
+            };
+
+            for (b, v) in entries {
+                let known = match versions.get(&b) {
+                    Some(k) => k.contains(&v),
+                    None => false,
+                };
+                if !known {
+                    issues.push(Issue::BadMapEntry {
+                        file: file.clone(),
+                        entry: format!("{}-{}", b, v),
+                    });
+                }
+            }
+        }
+
+        info!("Found {} issues", issues.len());
+
+        Ok(issues)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, fs};
+
+    use rusqlite::Connection;
+
+    use crate::{hash_bytes, Bin, Config, FileMeta, Issue, LockWait, Version};
+
+    // Create an empty bin, then break its db with the given sql
+    fn broken_bin(dir: &tempfile::TempDir, sql: &str) -> Bin {
+        let path = dir.path().join("bin").to_string_lossy().to_string();
+        drop(Bin::new(path.clone(), None, false, false, LockWait::Never).unwrap());
+
+        let con = Connection::open(dir.path().join("bin/meld.db")).unwrap();
+        con.execute_batch(sql).unwrap();
+        drop(con);
+
+        Bin::inspect(path, LockWait::Never).unwrap()
+    }
+
+    fn schema_issues(bin: &Bin) -> Vec<String> {
+        bin.validate()
+            .unwrap()
+            .into_iter()
+            .map(|i| match i {
+                Issue::Schema { msg } => msg,
+                other => panic!("unexpected issue: {}", other),
+            })
+            .collect()
+    }
+
+    fn new_bin(dir: &tempfile::TempDir) -> Bin {
+        let path = dir.path().join("bin").to_string_lossy().to_string();
+        Bin::new(path, None, false, false, LockWait::Never).unwrap()
+    }
+
+    // Store a version of a blob, and its config row if it has one
+    fn track(bin: &Bin, blob: &str, data: Option<&[u8]>, config: bool) -> String {
+        let blob = blob.to_string();
+        let hash = data.map(hash_bytes).unwrap_or_default();
+        bin.transaction(|j| {
+            if let Some(data) = data {
+                let codec = bin.store_version(j, &blob, 1, &hash, data, None)?;
+                bin.db.add_version(&Version {
+                    data_hash: hash.clone(),
+                    ver: 1,
+                    tag: String::new(),
+                    owner: blob.clone(),
+                    codec,
+                    encrypted: false,
+                    created: 0,
+                    hostname: String::new(),
+                    username: String::new(),
+                    message: String::new(),
+                    meta: FileMeta::default(),
+                })?;
+            }
+            if config {
+                bin.db.add_config(&Config {
+                    blob: blob.clone(),
+                    real_path: String::new(),
+                    subset: String::new(),
+                    family: String::new(),
+                    map_path: format!("$T$/{}", blob),
+                    tag: String::new(),
+                    hash: hash.clone(),
+                    meta: FileMeta::default(),
+                    encrypt: false,
+                    message: String::new(),
+                    versions: HashMap::new(),
+                })?;
+            }
+            Ok(())
+        })
+        .unwrap();
+        hash
+    }
+
+    fn issues(bin: &Bin) -> Vec<String> {
+        let mut issues: Vec<String> = bin
+            .validate()
+            .unwrap()
+            .iter()
+            .map(|i| i.to_string())
+            .collect();
+        issues.sort();
+        issues
+    }
+
+    #[test]
+    fn tracked_versions_are_valid() {
+        let dir = tempfile::tempdir().unwrap();
+        let bin = new_bin(&dir);
+        track(&bin, "a", Some(b"a"), true);
+
+        assert_eq!(issues(&bin), Vec::<String>::new());
+    }
+
+    #[test]
+    fn mismatches_are_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let bin = new_bin(&dir);
+        let a = track(&bin, "a", Some(b"a"), true);
+        track(&bin, "b", None, true);
+        let c = track(&bin, "c", Some(b"c"), false);
+
+        // a's object no longer holds what it is named by
+        let codec = bin.find_object(&a).unwrap();
+        fs::write(bin.object_path(&a, codec), "damaged").unwrap();
+        // c's object is gone
+        let c_codec = bin.find_object(&c).unwrap();
+        fs::remove_file(bin.object_path(&c, c_codec)).unwrap();
+        fs::write(bin.blobs.join("a/stray"), "").unwrap();
+        fs::create_dir(bin.blobs.join("d")).unwrap();
+        fs::write(bin.maps.join("e-1"), "").unwrap();
+
+        assert_eq!(
+            issues(&bin),
+            vec![
+                "config without versions: b".to_string(),
+                format!("corrupt object: {}{}", a, codec.extension()),
+                "map file without map: e-1".to_string(),
+                format!("missing object: {} for c/1", c),
+                "orphan blob dir: d".to_string(),
+                "orphan blob file: a/stray".to_string(),
+                "version without config: c/1".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn missing_table_is_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let bin = broken_bin(&dir, "DROP TABLE maps;");
+
+        assert_eq!(schema_issues(&bin), vec!["table maps is missing"]);
+    }
+
+    #[test]
+    fn missing_column_is_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let bin = broken_bin(&dir, "ALTER TABLE versions DROP COLUMN message;");
+
+        assert_eq!(
+            schema_issues(&bin),
+            vec!["table versions is missing column message"]
+        );
+    }
+
+    #[test]
+    fn old_schema_is_reported_without_migrating() {
+        let dir = tempfile::tempdir().unwrap();
+        let bin = broken_bin(&dir, "PRAGMA user_version = 3;");
+
+        assert_eq!(
+            schema_issues(&bin),
+            vec![format!(
+                "schema version is 3, expected {}",
+                crate::db::SCHEMA_VERSION
+            )]
+        );
+        assert_eq!(bin.db.get_schema_version().unwrap(), 3);
+    }
+}
@@ -0,0 +1,200 @@
+use std::str::FromStr;
+
+use crate::Args;
+use glob::Pattern;
+use libmeld::{ConfigSummary, Error};
+use log::warn;
+use serde_json::json;
+use structopt::StructOpt;
+
+/// Supported output formats
+#[derive(Debug, Clone)]
+pub enum Format {
+    Table,
+    Paths,
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "table" => Ok(Format::Table),
+            "paths" => Ok(Format::Paths),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("unknown format {}; use table, paths or json", s)),
+        }
+    }
+}
+
+// Define Module Arguments
+#[derive(Debug, StructOpt, Clone)]
+pub struct ListArgs {
+    #[structopt(short = "s", long = "subset", help = "only list configs in subset")]
+    pub(crate) subset: Option<String>,
+
+    #[structopt(short = "f", long = "family", help = "only list configs in family")]
+    pub(crate) family: Option<String>,
+
+    #[structopt(
+        short = "t",
+        long = "tag",
+        help = "only list configs with a version tagged"
+    )]
+    pub(crate) tag: Option<String>,
+
+    #[structopt(
+        short = "o",
+        long = "format",
+        default_value = "table",
+        help = "output format: table, paths or json"
+    )]
+    pub(crate) format: Format,
+
+    #[structopt(help = "only list configs whose map or real path matches this glob")]
+    pub(crate) pattern: Option<String>,
+}
+
+/// Check a config against the subset, family and tag filters shared by list and sync down
+pub(crate) fn selected(
+    c: &ConfigSummary,
+    subset: &Option<String>,
+    family: &Option<String>,
+    tag: &Option<String>,
+) -> bool {
+    if let Some(s) = subset {
+        if &c.subset != s {
+            return false;
+        }
+    }
+    if let Some(f) = family {
+        if &c.family != f {
+            return false;
+        }
+    }
+    if let Some(t) = tag {
+        if !c.tags.contains(t) {
+            return false;
+        }
+    }
+    true
+}
+
+/// Check a config against the requested filters
+fn matches(args: &ListArgs, pattern: &Option<Pattern>, c: &ConfigSummary, real: &str) -> bool {
+    if !selected(c, &args.subset, &args.family, &args.tag) {
+        return false;
+    }
+    if let Some(p) = pattern {
+        if !p.matches(&c.map_path) && !p.matches(real) {
+            return false;
+        }
+    }
+    true
+}
+
+/// Print configs as aligned columns
+fn print_table(rows: &Vec<(&ConfigSummary, String)>) {
+    let headers = ["MAP PATH", "SUBSET", "FAMILY", "VER", "MAP", "TAGS"];
+    let cells: Vec<[String; 6]> = rows
+        .iter()
+        .map(|(c, _)| {
+            [
+                c.map_path.clone(),
+                c.subset.clone(),
+                c.family.clone(),
+                c.latest.to_string(),
+                match c.map_ver {
+                    Some(v) => v.to_string(),
+                    None => "-".to_string(),
+                },
+                c.tags.join(","),
+            ]
+        })
+        .collect();
+
+    let mut widths = headers.map(|h| h.len());
+    for row in &cells {
+        for (i, cell) in row.iter().enumerate() {
+            widths[i] = widths[i].max(cell.len());
+        }
+    }
+
+    let print_row = |row: [&str; 6]| {
+        let line: Vec<String> = row
+            .iter()
+            .enumerate()
+            .map(|(i, cell)| format!("{:width$}", cell, width = widths[i]))
+            .collect();
+        println!("{}", line.join("  ").trim_end());
+    };
+
+    print_row(headers);
+    for row in &cells {
+        print_row([&row[0], &row[1], &row[2], &row[3], &row[4], &row[5]]);
+    }
+}
+
+/// Main handler for listing tracked configs
+pub fn handler(main_args: Args, args: ListArgs) -> Result<(), libmeld::Error> {
+    let bin = main_args.open_bin()?;
+
+    let pattern = match &args.pattern {
+        Some(p) => match Pattern::new(p) {
+            Ok(p) => Some(p),
+            Err(e) => return Err(Error::IOError { msg: e.to_string() }),
+        },
+        None => None,
+    };
+
+    let summaries = bin.db.get_config_summaries()?;
+
+    // resolve real paths for display; unresolvable paths are still listed
+    let mut rows = Vec::new();
+    for c in &summaries {
+        let real = match bin.mapper.map_to_real_path(&c.map_path) {
+            Ok(r) => r,
+            Err(e) => {
+                warn!("{}: {}", c.map_path, e);
+                String::new()
+            }
+        };
+        if matches(&args, &pattern, c, &real) {
+            rows.push((c, real));
+        }
+    }
+
+    match args.format {
+        Format::Table => print_table(&rows),
+        Format::Paths => {
+            for (c, real) in &rows {
+                if real.is_empty() {
+                    println!("{}", c.map_path);
+                } else {
+                    println!("{}", real);
+                }
+            }
+        }
+        Format::Json => {
+            let configs: Vec<serde_json::Value> = rows
+                .iter()
+                .map(|(c, real)| {
+                    json!({
+                        "blob": c.blob,
+                        "map_path": c.map_path,
+                        "real_path": real,
+                        "subset": c.subset,
+                        "family": c.family,
+                        "version": c.latest,
+                        "map_version": c.map_ver,
+                        "tags": c.tags,
+                    })
+                })
+                .collect();
+            println!("{}", serde_json::Value::Array(configs));
+        }
+    }
+
+    Ok(())
+}
@@ -1,13 +1,43 @@
 #![crate_name = "meld"]
+use diff::DiffArgs;
+use history::LogArgs;
+use info::InfoArgs;
 use init::InitArgs;
+use libmeld::{Bin, LockMode, LockWait};
+use list::ListArgs;
 use log::{error, info};
+use mapping::{MappingAction, MappingArgs};
+use pack::PackArgs;
+use pivot::PivotArgs;
+use prune::PruneArgs;
 use pull::PullArgs;
 use push::PushArgs;
+use repair::RepairArgs;
+use retention::RetentionArgs;
+use status::StatusArgs;
+use std::{path::PathBuf, time::Duration};
 use structopt::StructOpt;
+use sync::{SyncArgs, SyncDirection};
+use validate::ValidateArgs;
 
+mod diff;
+mod history;
+mod info;
 mod init;
+mod list;
+mod mapping;
+mod pack;
+mod pivot;
+mod prune;
 mod pull;
 mod push;
+mod repair;
+mod retention;
+mod status;
+mod sync;
+#[cfg(test)]
+mod testing;
+mod validate;
 
 /// Declare submodule argument types for matching
 #[derive(Debug, StructOpt, Clone)]
@@ -15,6 +45,20 @@ pub enum Command {
     Init(InitArgs),
     Push(PushArgs),
     Pull(PullArgs),
+    Mapping(MappingArgs),
+    Pivot(PivotArgs),
+    List(ListArgs),
+    Log(LogArgs),
+    Status(StatusArgs),
+    Diff(DiffArgs),
+    Sync(SyncArgs),
+    Validate(ValidateArgs),
+    Repair(RepairArgs),
+    Pack(PackArgs),
+    #[structopt(alias = "gc")]
+    Prune(PruneArgs),
+    Retention(RetentionArgs),
+    Info(InfoArgs),
 }
 
 #[derive(Debug, StructOpt, Clone)]
@@ -28,11 +72,104 @@ pub struct Args {
     #[structopt(help = "path to the meld bin")]
     pub bin: String,
 
+    // Mapping profile to use instead of this host's profile
+    #[structopt(
+        short = "P",
+        long = "profile",
+        help = "mapping profile to use (default: this host's profile, if defined)"
+    )]
+    pub profile: Option<String>,
+
+    // Lock waiting policy; by default wait up to DEFAULT_LOCK_WAIT
+    #[structopt(
+        long = "wait",
+        conflicts_with = "no-wait",
+        help = "wait as long as needed for a locked bin"
+    )]
+    pub wait: bool,
+
+    #[structopt(long = "no-wait", help = "fail immediately if the bin is locked")]
+    pub no_wait: bool,
+
+    // age identity used for encrypted configs; never stored in the bin
+    #[structopt(
+        short = "k",
+        long = "key",
+        help = "age identity file for encrypted configs (default: ~/.config/meld/key.txt)"
+    )]
+    pub key: Option<String>,
+
     // Meld command
     #[structopt(help = "meld command", subcommand)]
     pub command: Command,
 }
 
+/// How long to wait for a locked bin without --wait or --no-wait
+const DEFAULT_LOCK_WAIT: Duration = Duration::from_secs(30);
+
+/// Key file used without --key, relative to the user's home
+const DEFAULT_KEY_FILE: &str = ".config/meld/key.txt";
+
+impl Args {
+    /// Open the selected bin with the selected mapping profile
+    pub(crate) fn open_bin(&self) -> Result<Bin, libmeld::Error> {
+        let mut bin = Bin::from(
+            self.bin.clone(),
+            self.profile.clone(),
+            self.lock_mode(),
+            self.lock_wait(),
+        )?;
+        bin.set_key(self.key_path());
+        Ok(bin)
+    }
+
+    /// Open the selected bin as it is on disk, without migrating or checking it
+    pub(crate) fn inspect_bin(&self) -> Result<Bin, libmeld::Error> {
+        Bin::inspect(self.bin.clone(), self.lock_wait())
+    }
+
+    /// The selected key file, or the default one in the user's config dir
+    fn key_path(&self) -> PathBuf {
+        match &self.key {
+            Some(k) => PathBuf::from(k),
+            None => PathBuf::from(std::env::var("HOME").unwrap_or_default()).join(DEFAULT_KEY_FILE),
+        }
+    }
+
+    /// Commands that write to the bin hold it alone; everything else can share it
+    fn lock_mode(&self) -> LockMode {
+        match &self.command {
+            Command::Push(_)
+            | Command::Pivot(_)
+            | Command::Init(_)
+            | Command::Repair(_)
+            | Command::Pack(_) => LockMode::Exclusive,
+            Command::Prune(p) if !p.dry_run => LockMode::Exclusive,
+            Command::Retention(r) if r.is_change() => LockMode::Exclusive,
+            Command::Info(i) if i.action.is_some() => LockMode::Exclusive,
+            Command::Mapping(m) => match m.action {
+                MappingAction::List | MappingAction::Profiles => LockMode::Shared,
+                _ => LockMode::Exclusive,
+            },
+            Command::Sync(s) => match s.direction {
+                SyncDirection::Up(_) => LockMode::Exclusive,
+                SyncDirection::Down(_) => LockMode::Shared,
+            },
+            _ => LockMode::Shared,
+        }
+    }
+
+    pub(crate) fn lock_wait(&self) -> LockWait {
+        if self.wait {
+            LockWait::Forever
+        } else if self.no_wait {
+            LockWait::Never
+        } else {
+            LockWait::For(DEFAULT_LOCK_WAIT)
+        }
+    }
+}
+
 fn main() {
     env_logger::init();
 
@@ -43,6 +180,19 @@ fn main() {
         Command::Init(mod_args) => init::handler(main_args, mod_args),
         Command::Push(mod_args) => push::handler(main_args, mod_args),
         Command::Pull(mod_args) => pull::handler(main_args, mod_args),
+        Command::Mapping(mod_args) => mapping::handler(main_args, mod_args),
+        Command::Pivot(mod_args) => pivot::handler(main_args, mod_args),
+        Command::List(mod_args) => list::handler(main_args, mod_args),
+        Command::Log(mod_args) => history::handler(main_args, mod_args),
+        Command::Status(mod_args) => status::handler(main_args, mod_args),
+        Command::Diff(mod_args) => diff::handler(main_args, mod_args),
+        Command::Sync(mod_args) => sync::handler(main_args, mod_args),
+        Command::Validate(mod_args) => validate::handler(main_args, mod_args),
+        Command::Repair(mod_args) => repair::handler(main_args, mod_args),
+        Command::Pack(mod_args) => pack::handler(main_args, mod_args),
+        Command::Prune(mod_args) => prune::handler(main_args, mod_args),
+        Command::Retention(mod_args) => retention::handler(main_args, mod_args),
+        Command::Info(mod_args) => info::handler(main_args, mod_args),
     };
 
     match res {
@@ -52,7 +202,7 @@ fn main() {
         }
         Err(e) => {
             error!("{}", e);
-            std::process::exit(1);
+            std::process::exit(e.exit_code());
         }
     };
 }
@@ -0,0 +1,74 @@
+use crate::Args;
+use libmeld::{Error, Mapping};
+use log::warn;
+use structopt::StructOpt;
+
+// Define Module Arguments
+#[derive(Debug, StructOpt, Clone)]
+pub struct MappingArgs {
+    #[structopt(help = "mapping action", subcommand)]
+    pub(crate) action: MappingAction,
+}
+
+#[derive(Debug, StructOpt, Clone)]
+pub enum MappingAction {
+    /// Add or replace a mapping variable (in --profile, if given)
+    Add {
+        #[structopt(help = "variable name, ie $HOME$")]
+        var: String,
+        #[structopt(help = "prefix the variable resolves to; ${ENV} references are expanded")]
+        prefix: String,
+    },
+    /// Remove a mapping variable (from --profile, if given)
+    Remove {
+        #[structopt(help = "variable name, ie $HOME$")]
+        var: String,
+    },
+    /// List all mapping variables and how they resolve on this system
+    List,
+    /// List all profiles defined in the bin
+    Profiles,
+}
+
+/// Main handler for managing the bin's mapping variables
+pub fn handler(main_args: Args, args: MappingArgs) -> Result<(), libmeld::Error> {
+    let bin = main_args.open_bin()?;
+
+    match args.action {
+        MappingAction::Add { var, prefix } => {
+            bin.db
+                .add_mapping(&Mapping::new(var, prefix, main_args.profile.clone())?)?;
+        }
+        MappingAction::Remove { var } => {
+            if !bin.db.remove_mapping(&var, &main_args.profile)? {
+                return Err(Error::MappingNotFound { var });
+            }
+        }
+        MappingAction::List => {
+            for m in bin.mapper.get_mappings() {
+                let source = match &m.profile {
+                    Some(p) => p.as_str(),
+                    None => "*",
+                };
+                match m.resolve() {
+                    Some(p) => println!("{}\t{}\t{}\t{}", m.var, source, m.prefix, p),
+                    None => {
+                        warn!("{} does not resolve on this system", m.var);
+                        println!("{}\t{}\t{}\t-", m.var, source, m.prefix);
+                    }
+                }
+            }
+        }
+        MappingAction::Profiles => {
+            for p in bin.db.get_profiles()? {
+                if Some(&p) == bin.get_profile().as_ref() {
+                    println!("{} (active)", p);
+                } else {
+                    println!("{}", p);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
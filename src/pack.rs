@@ -0,0 +1,24 @@
+use crate::Args;
+use structopt::StructOpt;
+
+// Define Module Arguments
+#[derive(Debug, StructOpt, Clone)]
+pub struct PackArgs {}
+
+/// Main handler for consolidating loose objects into a pack
+pub fn handler(main_args: Args, _args: PackArgs) -> Result<(), libmeld::Error> {
+    let bin = main_args.open_bin()?;
+
+    let report = bin.pack()?;
+    if report.loose == 0 && report.replaced == 0 {
+        println!("Nothing to pack");
+        return Ok(());
+    }
+
+    println!(
+        "{} objects and {} pointers packed; {} loose files and {} packs consolidated",
+        report.objects, report.pointers, report.loose, report.replaced
+    );
+
+    Ok(())
+}
@@ -0,0 +1,81 @@
+use crate::Args;
+use log::info;
+use structopt::StructOpt;
+
+// Define Module Arguments
+#[derive(Debug, StructOpt, Clone)]
+pub struct PivotArgs {
+    #[structopt(help = "variable to rename, ie $HOME$")]
+    pub(crate) from: String,
+
+    #[structopt(help = "new variable name, ie $HOME_ICON$")]
+    pub(crate) to: String,
+}
+
+/// Main handler for renaming mapping variables across a bin
+pub fn handler(main_args: Args, args: PivotArgs) -> Result<(), libmeld::Error> {
+    let bin = main_args.open_bin()?;
+
+    let moved = bin.pivot(&args.from, &args.to)?;
+    info!(
+        "Pivoted {} configs from {} to {}",
+        moved, args.from, args.to
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use libmeld::hash_path;
+
+    use crate::pull::pull_file;
+    use crate::testing;
+
+    #[test]
+    fn pivot_packed_bin() {
+        let dir = tempfile::tempdir().unwrap();
+        let bin = testing::new_bin(dir.path());
+        let file = testing::write(dir.path(), "a.conf", "a\n");
+        let inner = testing::write(dir.path(), "d/x", "x\n");
+        testing::push(&bin, &file).unwrap();
+        testing::push(&bin, &testing::path(dir.path(), "home/d")).unwrap();
+        bin.pack().unwrap();
+        drop(bin);
+
+        // each command opens the bin afresh
+        let bin = testing::open_bin(dir.path());
+        bin.pivot(&"$T$".to_string(), &"$T2$".to_string()).unwrap();
+        drop(bin);
+        fs::remove_file(&file).unwrap();
+        fs::remove_file(&inner).unwrap();
+
+        let bin = testing::open_bin(dir.path());
+        let none = String::new();
+        bin.transaction(|journal| {
+            pull_file(
+                &bin,
+                journal,
+                &hash_path("$T2$/a.conf"),
+                &none,
+                false,
+                0,
+                None,
+            )?;
+            pull_file(&bin, journal, &hash_path("$T2$/d/x"), &none, false, 0, None)
+        })
+        .unwrap();
+
+        assert_eq!(fs::read_to_string(&file).unwrap(), "a\n");
+        assert_eq!(fs::read_to_string(&inner).unwrap(), "x\n");
+        let issues: Vec<String> = bin
+            .validate()
+            .unwrap()
+            .iter()
+            .map(|i| i.to_string())
+            .collect();
+        assert!(issues.is_empty(), "{:?}", issues);
+    }
+}
@@ -0,0 +1,50 @@
+use crate::Args;
+use structopt::StructOpt;
+
+// Define Module Arguments
+#[derive(Debug, StructOpt, Clone)]
+pub struct PruneArgs {
+    #[structopt(
+        short = "n",
+        long = "dry-run",
+        help = "show what would be pruned without changing the bin"
+    )]
+    pub(crate) dry_run: bool,
+}
+
+/// Main handler for removing versions their retention policy no longer keeps
+pub fn handler(main_args: Args, args: PruneArgs) -> Result<(), libmeld::Error> {
+    let bin = main_args.open_bin()?;
+
+    let report = bin.prune(args.dry_run)?;
+    if report.versions.is_empty() && report.objects.is_empty() {
+        println!("Nothing to prune");
+        return Ok(());
+    }
+
+    for (blob, ver) in &report.versions {
+        let path = bin.db.get_mapped_path(blob)?.unwrap_or(blob.clone());
+        println!("version {} V {}", path, ver);
+    }
+    for (blob, ver) in &report.maps {
+        let path = bin.db.get_mapped_path(blob)?.unwrap_or(blob.clone());
+        println!("map {} V {}", path, ver);
+    }
+    for name in &report.objects {
+        println!("object {}", name);
+    }
+
+    println!(
+        "{} versions, {} maps and {} objects {}",
+        report.versions.len(),
+        report.maps.len(),
+        report.objects.len(),
+        if args.dry_run {
+            "would be pruned"
+        } else {
+            "pruned"
+        }
+    );
+
+    Ok(())
+}
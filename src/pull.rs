@@ -1,10 +1,7 @@
-use std::{
-    fs,
-    io::{BufRead, BufReader},
-};
+use std::path::Path;
 
 use crate::Args;
-use libmeld::{hash_contents, hash_path, mapper, Bin, Error};
+use libmeld::{hash_contents, hash_path, parse_time, Bin, Error, FileMeta, Journal, Map};
 use log::{debug, error, info, warn};
 use structopt::StructOpt;
 
@@ -22,41 +19,61 @@ pub struct PullArgs {
     pub(crate) tag: String,
     #[structopt(short = "v", long = "version", default_value = "0", help = "version")]
     pub(crate) version: u32,
+    #[structopt(
+        long = "at",
+        parse(try_from_str = parse_time),
+        help = "pull the newest version pushed by this local time (YYYY-MM-DD [HH:MM[:SS]] or RFC 3339)"
+    )]
+    pub(crate) at: Option<i64>,
     #[structopt(help = "config file/folder to pull")]
     pub(crate) config_path: String,
 }
 
-/// Function to actually copy configs from the blobs directory
+/// Function to actually install configs from the bin
 fn copy_file(
+    bin: &Bin,
+    journal: &mut Journal,
     path: &String,
     blob_name: &String,
-    blobs_dir: &String,
     version: u32,
 ) -> Result<(), Error> {
-    // construct blob and version path for config
-    let blob_ver_path = format!("{}/{}/{}", blobs_dir, blob_name, version);
+    let data = bin.read_blob(blob_name, version)?;
 
-    debug!("copy {} -> {}", blob_ver_path, path);
+    // mapped paths may land in dirs that do not exist on this system yet
+    if let Some(parent) = Path::new(path).parent() {
+        journal.create_dir(parent.to_string_lossy().to_string())?;
+    }
 
-    let real_path = mapper::map_to_real_path(path)?;
+    journal.write(path.to_string(), &data)
+}
 
-    return match fs::copy(blob_ver_path, real_path) {
-        Ok(_) => Ok(()),
-        Err(e) => Err(Error::IOError { msg: e.to_string() }),
-    };
+/// Snapshot of a dir map as of a time; the current snapshot if no time is given
+pub(crate) fn map_at(bin: &Bin, blob: &String, at: Option<i64>) -> Result<Option<Map>, Error> {
+    match at {
+        None => bin.db.get_current_map(blob),
+        // snapshots are newest first
+        Some(t) => Ok(bin
+            .db
+            .get_map_versions(blob)?
+            .into_iter()
+            .find(|m| m.created <= t)),
+    }
 }
 
 /// Pull single file from the DB
-fn pull_file(
+/// with a time, only versions pushed by then are considered
+pub(crate) fn pull_file(
     bin: &Bin,
+    journal: &mut Journal,
     blob: &String,
     tag: &String,
     recent: bool,
     version: u32,
+    at: Option<i64>,
 ) -> Result<(), libmeld::Error> {
     let config_versions = bin.db.get_versions(blob)?;
 
-    let map_path = match bin.db.get_mapped_path(&blob)? {
+    let map_path = match bin.db.get_mapped_path(blob)? {
         Some(s) => s,
         None => {
             return Err(Error::FileNotFound {
@@ -65,51 +82,66 @@ fn pull_file(
         }
     };
 
-    let path = mapper::map_to_real_path(&map_path)?;
+    let path = bin.mapper.map_to_real_path(&map_path)?;
 
+    // contents that cannot be read are replaced like any other drift
     let cur_hash = if libmeld::exists(&path) {
-        Some(hash_contents(&path)?).unwrap()
+        match hash_contents(&path) {
+            Ok(h) => h,
+            Err(e) => {
+                warn!("{}", e);
+                "".to_string()
+            }
+        }
     } else {
         "".to_string()
     };
 
     let mut found_ver = 0;
     let mut max_ver = 0;
-    let mut pull_ver = "";
+    let mut pull_ver = 0;
 
     for (k, v) in &config_versions {
-        if tag != "" && tag == &v.tag {
+        if at.is_some_and(|t| v.created > t) {
+            continue;
+        }
+        if !tag.is_empty() && tag == &v.tag {
             debug!("Found matching tag: \"{}\" - {}", tag, k);
             found_ver = v.ver;
-            pull_ver = k;
+            pull_ver = *k;
             break;
         }
         if version != 0 && version == v.ver {
             debug!("Found matching version: \"{}\" - {}", version, k);
             found_ver = v.ver;
-            pull_ver = k;
+            pull_ver = *k;
             break;
         }
         if v.ver > max_ver {
             max_ver = v.ver;
-            pull_ver = k;
+            pull_ver = *k;
         }
     }
 
-    if tag != "" && version != 0 && found_ver == 0 {
+    // a time alone selects the newest version by then, so only a tag or version can miss
+    if (!tag.is_empty() || version != 0) && found_ver == 0 {
         warn!("Failed to find specified matching version");
         if recent {
             info!("Updating to most recent version");
         } else {
             error!("Run with -r to override to most recent");
-            return Err(Error::TagNotFound {
-                msg: tag.to_string(),
-            });
+            let msg = if tag.is_empty() {
+                format!("version {}", version)
+            } else {
+                tag.to_string()
+            };
+            return Err(Error::TagNotFound { msg });
         }
     }
 
-    let pulled_version = match config_versions.get(pull_ver) {
+    let pulled_version = match config_versions.get(&pull_ver) {
         Some(v) => v,
+        None if at.is_some() => return Err(Error::NoVersionAt { msg: map_path }),
         None => {
             error!("Something failed in matching versions");
             return Err(Error::SomethingFailed);
@@ -119,32 +151,35 @@ fn pull_file(
     info!("Pulling version {}", pulled_version.ver);
 
     // check if update needed
-    let update_needed = pulled_version.data_hash != cur_hash;
+    let update_needed = !bin.matches_hash(pulled_version, &cur_hash)?;
 
     if update_needed {
         info!("Updating config");
         if pulled_version.data_hash == "DIR" {
             info!("creating new dir");
-            if fs::create_dir(path).is_err() {
-                return Err(Error::IOError {
-                    msg: "Failed to create path".to_string(),
-                });
-            }
+            // mapped paths may land in dirs that do not exist on this system yet
+            journal.create_dir(path.clone())?;
         } else {
-            copy_file(&path, blob, &bin.get_blobs_str()?, pulled_version.ver)?;
+            copy_file(bin, journal, &path, blob, pulled_version.ver)?;
         }
     } else {
         info!("Content matches, not updating");
     }
 
-    return Ok(());
+    // mode, owner and mtime come with new contents, or on their own if they drifted
+    let meta = &pulled_version.meta;
+    if update_needed || meta.differs(&FileMeta::read(&path)?) {
+        journal.set_meta(path, meta)?;
+    }
+
+    Ok(())
 }
 
 /// Main handler for pulling configs from the Meld Bins
 pub fn handler(main_args: Args, args: PullArgs) -> Result<(), libmeld::Error> {
-    let bin = Bin::from(main_args.bin)?;
+    let bin = main_args.open_bin()?;
 
-    let config_map_path = mapper::real_path_to_map(&args.config_path)?;
+    let config_map_path = bin.mapper.real_path_to_map(&args.config_path)?;
 
     // Look up the config in the db
     let blob = match bin.db.config_exists(&config_map_path)? {
@@ -158,47 +193,183 @@ pub fn handler(main_args: Args, args: PullArgs) -> Result<(), libmeld::Error> {
 
     info!("Config path matched: {}", blob);
 
-    let map_blob = hash_path(&args.config_path);
-    let map = bin.db.get_current_map(&map_blob)?;
+    let map_blob = hash_path(&config_map_path);
+    let map = match map_at(&bin, &map_blob, args.at)? {
+        // a dir with no snapshot by then did not exist yet
+        None if args.at.is_some() && bin.db.get_current_map(&map_blob)?.is_some() => {
+            return Err(Error::NoVersionAt {
+                msg: config_map_path,
+            })
+        }
+        m => m,
+    };
 
-    if map.is_none() {
-        debug!("Config is single file; pull directly");
-        pull_file(&bin, &blob, &args.tag, args.recent, args.version)?;
-    } else {
-        let map = map.unwrap();
-        debug!("Config is map; parsing");
-        let mut map_file_str: String = bin.get_maps_str()?;
-        // build map file string from bin parts and found version
-        map_file_str.push('/');
-        map_file_str.push_str(&map_blob);
-        map_file_str.push('-');
-        map_file_str.push_str(&map.ver.to_string());
-        info!("Parsing {}", map_file_str);
-        let map_file = match fs::File::open(map_file_str) {
-            Ok(f) => f,
-            Err(e) => {
-                return Err(Error::IOError { msg: e.to_string() });
+    // a dir is installed completely or not at all
+    bin.transaction(|journal| {
+        match map {
+            None => {
+                debug!("Config is single file; pull directly");
+                pull_file(
+                    &bin,
+                    journal,
+                    &blob,
+                    &args.tag,
+                    args.recent,
+                    args.version,
+                    args.at,
+                )?;
             }
-        };
-        let map_reader = BufReader::new(map_file);
-
-        for config in map_reader.lines() {
-            match config {
-                Ok(c) => {
-                    let (blob, version) = c.split_once('-').unwrap();
+            Some(map) => {
+                debug!("Config is map; parsing");
+                // the snapshot already pins each file's version
+                for (blob, version) in bin.get_map_entries(&map_blob, map.ver)? {
                     debug!("Pulling {} V {}", blob, version);
-                    pull_file(
-                        &bin,
-                        &blob.to_string(),
-                        &args.tag,
-                        args.recent,
-                        version.parse::<u32>().unwrap(),
-                    )?;
+                    pull_file(&bin, journal, &blob, &args.tag, args.recent, version, None)?;
                 }
-                Err(e) => return Err(Error::IOError { msg: e.to_string() }),
             }
         }
+        Ok(())
+    })?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use libmeld::{hash_path, Error};
+    use rusqlite::{params, Connection};
+
+    use libmeld::{Bin, LockWait, Mapping};
+    use structopt::StructOpt;
+
+    use super::{handler, map_at, pull_file, PullArgs};
+    use crate::testing;
+
+    // Push a file and a dir three times over, then date each push 1000s apart from 1000
+    fn pushed_three_times(dir: &std::path::Path) -> (String, String) {
+        let bin = testing::new_bin(dir);
+        let file = testing::write(dir, "a.conf", "");
+        let inner = testing::write(dir, "d/x", "");
+        for n in 1..=3 {
+            fs::write(&file, n.to_string()).unwrap();
+            fs::write(&inner, n.to_string()).unwrap();
+            testing::push(&bin, &file).unwrap();
+            testing::push(&bin, &testing::path(dir, "home/d")).unwrap();
+        }
+        drop(bin);
+
+        let con = Connection::open(dir.join("bin/meld.db")).unwrap();
+        con.execute("UPDATE versions SET created = ver * 1000", params![])
+            .unwrap();
+        con.execute("UPDATE maps SET created = ver * 1000", params![])
+            .unwrap();
+        (file, inner)
+    }
+
+    #[test]
+    fn pull_at_time() {
+        let dir = tempfile::tempdir().unwrap();
+        let (file, _) = pushed_three_times(dir.path());
+        let bin = testing::open_bin(dir.path());
+        let blob = hash_path("$T$/a.conf");
+
+        let pull = |at: i64| {
+            bin.transaction(|journal| {
+                pull_file(&bin, journal, &blob, &String::new(), false, 0, Some(at))
+            })?;
+            Ok::<String, Error>(fs::read_to_string(&file).unwrap())
+        };
+
+        // nothing was pushed yet
+        assert!(matches!(pull(999), Err(Error::NoVersionAt { .. })));
+        // a version pushed at the very time counts
+        assert_eq!(pull(1000).unwrap(), "1");
+        assert_eq!(pull(2999).unwrap(), "2");
+        assert_eq!(pull(3000).unwrap(), "3");
+        assert_eq!(pull(i64::MAX).unwrap(), "3");
+    }
+
+    #[test]
+    fn unmatched_selectors_fail() {
+        let dir = tempfile::tempdir().unwrap();
+        let (file, _) = pushed_three_times(dir.path());
+        let bin = testing::open_bin(dir.path());
+        let blob = hash_path("$T$/a.conf");
+        fs::write(&file, "local").unwrap();
+
+        let pull = |tag: &str, recent: bool, version: u32, at: Option<i64>| {
+            bin.transaction(|journal| {
+                pull_file(&bin, journal, &blob, &tag.to_string(), recent, version, at)
+            })
+        };
+
+        assert!(matches!(
+            pull("nope", false, 0, None),
+            Err(Error::TagNotFound { .. })
+        ));
+        assert!(matches!(
+            pull("", false, 9, None),
+            Err(Error::TagNotFound { .. })
+        ));
+        // versions pushed after the time do not match either
+        assert!(matches!(
+            pull("", false, 3, Some(2000)),
+            Err(Error::TagNotFound { .. })
+        ));
+        assert_eq!(fs::read_to_string(&file).unwrap(), "local");
+
+        // -r falls back to the most recent version
+        pull("nope", true, 0, None).unwrap();
+        assert_eq!(fs::read_to_string(&file).unwrap(), "3");
+    }
+
+    #[test]
+    fn map_at_time() {
+        let dir = tempfile::tempdir().unwrap();
+        pushed_three_times(dir.path());
+        let bin = testing::open_bin(dir.path());
+        let blob = hash_path("$T$/d");
+
+        let ver = |at| map_at(&bin, &blob, at).unwrap().map(|m| m.ver);
+        assert_eq!(ver(Some(999)), None);
+        assert_eq!(ver(Some(1000)), Some(1));
+        assert_eq!(ver(Some(1999)), Some(1));
+        assert_eq!(ver(Some(2000)), Some(2));
+        assert_eq!(ver(None), Some(3));
     }
 
-    return Ok(());
+    #[test]
+    fn pull_missing_file_under_symlinked_prefix() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("real")).unwrap();
+        std::os::unix::fs::symlink(dir.path().join("real"), dir.path().join("link")).unwrap();
+
+        let bin = Bin::new(
+            testing::path(dir.path(), "bin"),
+            None,
+            false,
+            false,
+            LockWait::Never,
+        )
+        .unwrap();
+        let link = testing::path(dir.path(), "link");
+        bin.db
+            .add_mapping(&Mapping::new("$L$".to_string(), link, None).unwrap())
+            .unwrap();
+        drop(bin);
+
+        let file = testing::path(dir.path(), "link/a.conf");
+        fs::write(&file, "a\n").unwrap();
+        let bin = testing::open_bin(dir.path());
+        testing::push(&bin, &file).unwrap();
+        drop(bin);
+
+        // the missing file is found through the symlink, as the prefix is
+        fs::remove_file(&file).unwrap();
+        let args = PullArgs::from_iter(["pull", &file]);
+        handler(testing::args(dir.path()), args).unwrap();
+        assert_eq!(fs::read_to_string(&file).unwrap(), "a\n");
+    }
 }
@@ -1,10 +1,7 @@
-use std::{
-    fs::{self, File},
-    io::Write,
-};
-
 use crate::Args;
-use libmeld::{is_dir, mapper, Bin, Config, Error, Map, Version};
+use std::fs;
+
+use libmeld::{hash_bytes, is_dir, Bin, Codec, Config, Error, Journal, Map, Version};
 use log::{debug, info};
 use structopt::StructOpt;
 
@@ -30,78 +27,117 @@ pub struct PushArgs {
     )]
     pub(crate) family: String,
 
+    #[structopt(
+        short = "e",
+        long = "encrypt",
+        help = "encrypt new versions with the key (see --key)"
+    )]
+    pub(crate) encrypt: bool,
+
+    #[structopt(
+        short = "m",
+        long = "message",
+        default_value = "",
+        help = "message recorded with new versions"
+    )]
+    pub(crate) message: String,
+
     #[structopt(help = "config file/folder to add")]
     pub(crate) config_path: String,
 }
 
-/// Function to actually copy configs to the blobs directory
+/// Function to actually store configs in the bin
+/// base is the previous version's contents hash, which deltas are stored against
+/// Returns the codec the contents are stored with and whether they were encrypted
 fn copy_file(
-    path: &String,
-    blob_name: &String,
-    blobs_dir: &String,
+    bin: &Bin,
+    journal: &mut Journal,
+    config: &Config,
     version: u32,
-) -> Result<(), Error> {
+    encrypt: bool,
+    base: Option<&String>,
+) -> Result<(Codec, bool), Error> {
     // ignore dirs if the are copied
-    if is_dir(&path)? {
-        return Ok(());
+    if is_dir(config.get_real_path())? {
+        return Ok((Codec::None, false));
     }
 
-    // construct blob and version path for config
-    let blob_ver_path = format!("{}/{}/{}", blobs_dir, blob_name, version);
+    let data = match fs::read(config.get_real_path()) {
+        Ok(d) => d,
+        Err(e) => return Err(Error::IOError { msg: e.to_string() }),
+    };
+
+    // objects are keyed by their contents, so they must match the hash being versioned
+    if &hash_bytes(&data) != config.get_hash() {
+        return Err(Error::IOError {
+            msg: format!("{} changed while pushing", config.get_real_path()),
+        });
+    }
 
-    debug!("copy {} -> {}", path, blob_ver_path);
+    if encrypt {
+        bin.store_encrypted_version(journal, config.get_blob(), version, &data)?;
+        return Ok((Codec::None, true));
+    }
 
-    return match fs::copy(path, blob_ver_path) {
-        Ok(_) => Ok(()),
-        Err(e) => Err(Error::IOError { msg: e.to_string() }),
-    };
+    let codec = bin.store_version(
+        journal,
+        config.get_blob(),
+        version,
+        config.get_hash(),
+        &data,
+        base,
+    )?;
+    Ok((codec, false))
 }
 
 /// Push new config to Bin
 /// will determine updates needed per config
 /// Returns the version of either currently tracked config
-fn push_config(bin: &Bin, config: &Config) -> Result<u32, libmeld::Error> {
+pub(crate) fn push_config(
+    bin: &Bin,
+    journal: &mut Journal,
+    config: &Config,
+) -> Result<u32, libmeld::Error> {
     let cur_version = bin.db.get_current_version(config.get_blob())?;
+    let encrypt = bin.must_encrypt(config, &cur_version)?;
 
     // if config is not in DB, add it
     // if config is in DB, determine updates
     if cur_version.is_none() {
         info!("Adding new config to bin");
-        let version = Version {
-            data_hash: config.get_hash().to_string(),
-            ver: 1,
-            tag: config.get_tag().to_string(),
-            owner: config.get_blob().to_string(),
-        };
 
         // create the blob dir
-        match fs::create_dir(format!("{}/{}", bin.get_blobs_str()?, config.get_blob())) {
-            Ok(_) => (),
-            Err(e) => return Err(Error::IOError { msg: e.to_string() }),
-        }
+        journal.create_dir(format!("{}/{}", bin.get_blobs_str()?, config.get_blob()))?;
 
         // copy to blobs
-        copy_file(
-            config.get_real_path(),
-            config.get_blob(),
-            &bin.get_blobs_str()?,
-            1,
-        )?;
+        let (codec, encrypted) = copy_file(bin, journal, config, 1, encrypt, None)?;
 
         // add to db after a successful copy
-        bin.db.add_version(&version)?;
-        bin.db.add_config(&config)?;
+        bin.db.add_version(&Version {
+            data_hash: bin.recorded_hash(config.get_hash(), encrypted)?,
+            ver: 1,
+            tag: config.get_tag().to_string(),
+            owner: config.get_blob().to_string(),
+            codec,
+            encrypted,
+            created: libmeld::now(),
+            hostname: libmeld::hostname(),
+            username: libmeld::username(),
+            message: config.message.clone(),
+            meta: config.get_meta().clone(),
+        })?;
+        bin.db.add_config(config)?;
 
         // version is one since just added
         return Ok(1);
     }
 
     // handle admin updates
-    if config.subset != "" {
+    if !config.subset.is_empty() {
         bin.db.update_subset(config.get_blob(), &config.subset)?;
     }
 
-    if config.family != "" {
+    if !config.family.is_empty() {
         bin.db.update_family(config.get_blob(), &config.family)?;
     }
 
@@ -113,31 +149,42 @@ fn push_config(bin: &Bin, config: &Config) -> Result<u32, libmeld::Error> {
     let new_ver = cur_version.ver + 1;
 
     // do proper update action; return the current version num in db
-    let db_ver = if cur_version.data_hash != config_hash {
-        info!("Content differs; adding new version");
-        let new_ver = Version {
-            data_hash: config_hash,
-            ver: new_ver,
-            tag: config.get_tag().to_string(),
-            owner: config.get_blob().to_string(),
+    // a config that must now be encrypted gets an encrypted version even if unchanged,
+    // as does one whose mode or owner changed
+    let unchanged = bin.matches_hash(&cur_version, &config_hash)?;
+    let db_ver = if !unchanged
+        || (encrypt && !cur_version.encrypted)
+        || cur_version.meta.differs(config.get_meta())
+    {
+        info!("Content or metadata differs, or must be encrypted; adding new version");
+
+        // encrypted contents are never stored as deltas, nor used as a base
+        let base = if cur_version.encrypted || cur_version.data_hash == "DIR" {
+            None
+        } else {
+            Some(&cur_version.data_hash)
         };
 
         // copy to blobs
-        copy_file(
-            config.get_real_path(),
-            config.get_blob(),
-            &bin.get_blobs_str()?,
-            new_ver.ver,
-        )?;
+        let (codec, encrypted) = copy_file(bin, journal, config, new_ver, encrypt, base)?;
 
         // add to db after good copy
-        bin.db.add_version(&new_ver)?;
+        bin.db.add_version(&Version {
+            data_hash: bin.recorded_hash(&config_hash, encrypted)?,
+            ver: new_ver,
+            tag: config.get_tag().to_string(),
+            owner: config.get_blob().to_string(),
+            codec,
+            encrypted,
+            created: libmeld::now(),
+            hostname: libmeld::hostname(),
+            username: libmeld::username(),
+            message: config.message.clone(),
+            meta: config.get_meta().clone(),
+        })?;
 
-        new_ver.ver
-    } else if cur_version.data_hash == config_hash
-        && cur_version.tag != config.get_tag().to_string()
-        && cur_version.tag != ""
-    {
+        new_ver
+    } else if unchanged && cur_version.tag != *config.get_tag() && !cur_version.tag.is_empty() {
         info!("Tag differs; updating");
         bin.db.update_version_tag(&cur_version, config.get_tag())?;
         cur_version.ver
@@ -147,78 +194,206 @@ fn push_config(bin: &Bin, config: &Config) -> Result<u32, libmeld::Error> {
     };
 
     // return the new version
-    return Ok(db_ver);
+    Ok(db_ver)
+}
+
+/// Push every config in a dir map to the Bin
+/// a new map snapshot is only written if the dir contents changed
+/// Returns the blobs of all configs pushed
+pub(crate) fn push_map(
+    bin: &Bin,
+    journal: &mut Journal,
+    mut map: Map,
+) -> Result<Vec<String>, libmeld::Error> {
+    info!("Map contains {} configs", map.configs.len());
+    map.hash = bin.recorded_map_hash(&map.configs)?;
+
+    // the map hash only covers contents; new modes or owners also need a new snapshot
+    let mut meta_changed = false;
+    for c in &map.configs {
+        if let Some(v) = bin.db.get_current_version(c.get_blob())? {
+            meta_changed |= v.meta.differs(c.get_meta());
+        }
+    }
+
+    // check if map exists; if it does, check if the hashes match
+    // update the map version accordingly; 0 if update not needed
+    map.ver = match bin.db.get_current_map(&map.blob)? {
+        Some(m) => {
+            info!("Map {} exists; determining if update needed", map.blob);
+            if m.hash == map.hash && !meta_changed {
+                info!("Stored map matches current map; not updating");
+                0
+            } else {
+                m.ver + 1
+            }
+        }
+        None => {
+            info!("Map not in db; adding");
+            1
+        }
+    };
+
+    let mut pushed = Vec::new();
+
+    // add the map to the db if new or not matching most recent hash
+    if map.ver != 0 {
+        bin.db.add_map(&map)?;
+
+        // push all the configs in the map and write map file
+        // each config will update and track state separetly
+        let mut contents = String::new();
+        for c in map.configs {
+            info!("Handling Config {}", c.get_real_path());
+            let cv = push_config(bin, journal, &c)?;
+            contents.push_str(&format!("{}-{}\n", c.get_blob(), cv));
+            pushed.push(c.get_blob().to_string());
+        }
+
+        let map_file = format!("{}/{}-{}", bin.get_maps_str()?, map.blob, map.ver);
+        journal.create(map_file, contents.as_bytes())?;
+    } else {
+        // if we dont need to rewrite the config
+        // still update the configs as contents may have changed
+        for c in map.configs {
+            push_config(bin, journal, &c)?;
+            pushed.push(c.get_blob().to_string());
+        }
+    }
+
+    Ok(pushed)
 }
 
 /// Main handler for pushing configs to Meld Bins
 pub fn handler(main_args: Args, args: PushArgs) -> Result<(), libmeld::Error> {
-    let bin = Bin::from(main_args.bin)?;
+    let bin = main_args.open_bin()?;
 
     // handle single file config pushes
     if !is_dir(&args.config_path)? {
         debug!("Pushing single file");
-        let map_path = mapper::real_path_to_map(&args.config_path)?;
-        let config = Config::from(
+        let map_path = bin.mapper.real_path_to_map(&args.config_path)?;
+        let mut config = Config::from(
             args.config_path,
             map_path,
             args.subset.clone(),
             args.family.clone(),
             args.tag.clone(),
         )?;
-        push_config(&bin, &config)?;
+        config.encrypt = args.encrypt;
+        config.message = args.message;
+        bin.transaction(|journal| push_config(&bin, journal, &config))?;
     } else {
         debug!("Pushing dir tree");
         // create map and add to db
-        let mut map = Map::new(&args.config_path, args.subset, args.family, args.tag)?;
-        info!("Map contains {} configs", map.configs.len());
-
-        // check if map exists; if it does, check if the hashes match
-        // update the map version accordingly; 0 if update not needed
-        map.ver = match bin.db.get_current_map(&map.blob)? {
-            Some(m) => {
-                info!(
-                    "Map for {} exists; determining if update needed",
-                    args.config_path
-                );
-                if m.hash == map.hash {
-                    info!("Stored map matches current map; not updating");
-                    0
-                } else {
-                    m.ver + 1
-                }
-            }
-            None => {
-                info!("Map not in db; adding");
-                1
-            }
-        };
-        // add the map to the db if new or not matching most recent hash
-        if map.ver != 0 {
-            bin.db.add_map(&map)?;
-            let mut map_file =
-                match File::create(format!("{}/{}-{}", bin.get_maps_str()?, map.blob, map.ver)) {
-                    Ok(f) => f,
-                    Err(e) => return Err(Error::IOError { msg: e.to_string() }),
-                };
-
-            // push all the configs in the map and write map file
-            // each config will update and track state separetly
-            for c in map.configs {
-                info!("Handling Config {}", c.get_real_path());
-                let cv = push_config(&bin, &c)?;
-                match map_file.write_fmt(format_args!("{}-{}\n", c.get_blob(), cv)) {
-                    Ok(_) => (),
-                    Err(e) => return Err(Error::IOError { msg: e.to_string() }),
-                };
-            }
-        } else {
-            // if we dont need to rewrite the config
-            // still update the configs as contents may have changed
-            for c in map.configs {
-                push_config(&bin, &c)?;
-            }
+        let mut map = Map::new(
+            &bin.mapper,
+            bin.get_config(),
+            &args.config_path,
+            args.subset,
+            args.family,
+            args.tag,
+        )?;
+        map.message = args.message.clone();
+        for c in map.configs.iter_mut() {
+            c.encrypt = args.encrypt;
+            c.message = args.message.clone();
         }
+        // the whole dir is pushed or nothing is
+        bin.transaction(|journal| push_map(&bin, journal, map))?;
     }
 
-    return Ok(());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use age::{secrecy::ExposeSecret, x25519::Identity};
+    use libmeld::{hash_contents, Config};
+    use structopt::StructOpt;
+
+    use super::{handler, push_config, PushArgs};
+    use crate::status::{get_status, State};
+    use crate::testing;
+
+    #[test]
+    fn encrypted_versions_record_a_keyed_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut bin = testing::new_bin(dir.path());
+        let key = dir.path().join("key.txt");
+        fs::write(&key, Identity::generate().to_string().expose_secret()).unwrap();
+        bin.set_key(key);
+
+        let file = testing::write(dir.path(), "s.conf", "secret");
+        let mut config = Config::from(
+            file.clone(),
+            bin.mapper.real_path_to_map(&file).unwrap(),
+            String::new(),
+            String::new(),
+            String::new(),
+        )
+        .unwrap();
+        config.encrypt = true;
+        let push = || bin.transaction(|j| push_config(&bin, j, &config)).unwrap();
+
+        assert_eq!(push(), 1);
+        let v = bin
+            .db
+            .get_current_version(config.get_blob())
+            .unwrap()
+            .unwrap();
+        let hash = hash_contents(&file).unwrap();
+        assert!(v.encrypted);
+        assert_ne!(v.data_hash, hash);
+        assert!(bin.matches_hash(&v, &hash).unwrap());
+
+        // unchanged contents still match through the key
+        assert_eq!(push(), 1);
+        assert_eq!(get_status(&bin).unwrap(), vec![(State::Unchanged, file)]);
+    }
+
+    #[test]
+    fn pushes_record_who_when_and_why() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = testing::write(dir.path(), "d/a.conf", "a");
+        testing::new_bin(dir.path());
+
+        let before = libmeld::now();
+        let home = testing::path(dir.path(), "home/d");
+        let args = PushArgs::from_iter(["push", "-m", "first", &home]);
+        handler(testing::args(dir.path()), args).unwrap();
+
+        let bin = testing::open_bin(dir.path());
+        let maps = bin.db.get_current_maps().unwrap();
+        assert_eq!(maps.len(), 1);
+        let config = Config::from(
+            file.clone(),
+            bin.mapper.real_path_to_map(&file).unwrap(),
+            String::new(),
+            String::new(),
+            String::new(),
+        )
+        .unwrap();
+        let v = bin
+            .db
+            .get_current_version(config.get_blob())
+            .unwrap()
+            .unwrap();
+
+        for (created, hostname, username, message) in [
+            (
+                maps[0].created,
+                &maps[0].hostname,
+                &maps[0].username,
+                &maps[0].message,
+            ),
+            (v.created, &v.hostname, &v.username, &v.message),
+        ] {
+            assert!(created >= before && created <= libmeld::now());
+            assert_eq!(*hostname, libmeld::hostname());
+            assert_eq!(*username, libmeld::username());
+            assert_eq!(message, "first");
+        }
+    }
 }
@@ -0,0 +1,39 @@
+use crate::Args;
+use libmeld::Bin;
+use structopt::StructOpt;
+
+// Define Module Arguments
+#[derive(Debug, StructOpt, Clone)]
+pub struct RepairArgs {
+    #[structopt(
+        short = "s",
+        long = "search",
+        number_of_values = 1,
+        help = "dir to walk for paths matching unrecovered configs"
+    )]
+    pub(crate) search: Vec<String>,
+}
+
+/// Main handler for rebuilding a damaged bin
+pub fn handler(main_args: Args, args: RepairArgs) -> Result<(), libmeld::Error> {
+    // the bin may not open normally, so it is never validated here
+    let report = Bin::repair(main_args.bin.clone(), &args.search, main_args.lock_wait())?;
+
+    for file in &report.quarantined {
+        println!("quarantined {}", file);
+    }
+    for blob in &report.unrecovered {
+        println!("unrecovered {}", blob);
+    }
+
+    println!(
+        "{} versions, {} maps, {} configs recovered; {} quarantined, {} unrecovered",
+        report.versions,
+        report.maps,
+        report.configs,
+        report.quarantined.len(),
+        report.unrecovered.len()
+    );
+
+    Ok(())
+}
@@ -0,0 +1,100 @@
+use crate::Args;
+use libmeld::{Error, Retention};
+use structopt::StructOpt;
+
+// Define Module Arguments
+#[derive(Debug, StructOpt, Clone)]
+pub struct RetentionArgs {
+    #[structopt(long = "keep-last", help = "keep the newest N versions")]
+    pub(crate) keep_last: Option<u32>,
+
+    #[structopt(
+        long = "keep-tagged",
+        help = "keep tagged versions and snapshots regardless of age (true|false)"
+    )]
+    pub(crate) keep_tagged: Option<bool>,
+
+    #[structopt(
+        long = "max-age",
+        help = "keep versions younger than this, e.g. 30d, 12h or 2w"
+    )]
+    pub(crate) max_age: Option<String>,
+
+    #[structopt(
+        long = "clear",
+        conflicts_with_all = &["keep-last", "keep-tagged", "max-age"],
+        help = "remove the config's own policy, falling back to the bin's"
+    )]
+    pub(crate) clear: bool,
+
+    #[structopt(help = "config file/folder to show or set retention for")]
+    pub(crate) config_path: String,
+}
+
+impl RetentionArgs {
+    /// Check the policy is changed rather than shown
+    pub(crate) fn is_change(&self) -> bool {
+        self.clear
+            || self.keep_last.is_some()
+            || self.keep_tagged.is_some()
+            || self.max_age.is_some()
+    }
+}
+
+/// Main handler for showing or setting the retention policy of a config
+pub fn handler(main_args: Args, args: RetentionArgs) -> Result<(), libmeld::Error> {
+    let bin = main_args.open_bin()?;
+
+    let config_map_path = bin.mapper.real_path_to_map(&args.config_path)?;
+
+    // Look up the config in the db
+    let blob = match bin.db.config_exists(&config_map_path)? {
+        Some(b) => b,
+        None => {
+            return Err(Error::FileNotFound {
+                msg: config_map_path,
+            })
+        }
+    };
+
+    if args.is_change() {
+        let mut retention = if args.clear {
+            Retention::default()
+        } else {
+            bin.get_blob_retention(&blob)?
+        };
+        if args.keep_last == Some(0) {
+            return Err(Error::InvalidBinConfig {
+                msg: "keep_last must be a positive number".to_string(),
+            });
+        }
+        retention.keep_last = args.keep_last.or(retention.keep_last);
+        retention.keep_tagged = args.keep_tagged.or(retention.keep_tagged);
+        if let Some(age) = &args.max_age {
+            retention.max_age = match Retention::parse_age(age) {
+                Some(a) => Some(a),
+                None => {
+                    return Err(Error::InvalidBinConfig {
+                        msg: "max_age must be an age like 30d, 12h or 2w".to_string(),
+                    })
+                }
+            };
+        }
+
+        bin.transaction(|journal| bin.set_retention(journal, &blob, &retention))?;
+    }
+
+    let own = bin.get_blob_retention(&blob)?;
+    println!("{}", config_map_path);
+    println!(
+        "  retention: {}{}",
+        bin.get_retention(&blob)?,
+        if own == Retention::default() {
+            " (bin default)"
+        } else {
+            ""
+        }
+    );
+
+    Ok(())
+}
@@ -0,0 +1,183 @@
+use std::{collections::HashSet, fmt};
+
+use crate::Args;
+use libmeld::{exists, hash_contents, hash_path, Bin, Error, FileMeta};
+use log::{info, warn};
+use structopt::StructOpt;
+
+// Define Module Arguments
+#[derive(Debug, StructOpt, Clone)]
+pub struct StatusArgs {
+    #[structopt(short = "a", long = "all", help = "also show unchanged configs")]
+    pub(crate) all: bool,
+}
+
+/// State of a config on this system compared to the bin
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum State {
+    Unchanged,
+    Modified,
+    // contents match, but the mode or owner does not
+    Metadata,
+    Missing,
+    // exists, but its contents could not be read
+    Unreadable,
+    Untracked,
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            State::Unchanged => "unchanged",
+            State::Modified => "modified",
+            State::Metadata => "metadata",
+            State::Missing => "missing",
+            State::Unreadable => "unreadable",
+            State::Untracked => "untracked",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+/// Compare every tracked config and map dir against the filesystem
+/// Returns (state, real path) pairs; unresolvable configs are reported as missing
+pub(crate) fn get_status(bin: &Bin) -> Result<Vec<(State, String)>, Error> {
+    let mut results = Vec::new();
+    let configs = bin.db.get_configs()?;
+    let tracked: HashSet<&String> = configs.iter().map(|c| c.get_blob()).collect();
+
+    for c in &configs {
+        let path = match bin.mapper.map_to_real_path(c.get_map_path()) {
+            Ok(p) => p,
+            Err(e) => {
+                warn!("{}: {}", c.get_map_path(), e);
+                results.push((State::Missing, c.get_map_path().to_string()));
+                continue;
+            }
+        };
+
+        let state = if !exists(&path) {
+            State::Missing
+        } else {
+            let hash = match hash_contents(&path) {
+                Ok(h) => h,
+                Err(e) => {
+                    warn!("{}", e);
+                    results.push((State::Unreadable, path));
+                    continue;
+                }
+            };
+            match bin.db.get_current_version(c.get_blob())? {
+                Some(v) if bin.matches_hash(&v, &hash)? => {
+                    if v.meta.differs(&FileMeta::read(&path)?) {
+                        State::Metadata
+                    } else {
+                        State::Unchanged
+                    }
+                }
+                _ => State::Modified,
+            }
+        };
+
+        results.push((state, path));
+    }
+
+    // new files in tracked dirs are anything not in the latest snapshot
+    for map in bin.db.get_current_maps()? {
+        let dir = match bin.db.get_mapped_path(&map.blob)? {
+            Some(p) => match bin.mapper.map_to_real_path(&p) {
+                Ok(d) => d,
+                Err(_) => continue,
+            },
+            None => continue,
+        };
+
+        if !exists(&dir) {
+            continue;
+        }
+
+        let snapshot: HashSet<String> = bin
+            .get_map_entries(&map.blob, map.ver)?
+            .into_iter()
+            .map(|(b, _)| b)
+            .collect();
+
+        for path in bin.get_config().walk_dir(&bin.mapper, &dir) {
+            let blob = hash_path(&bin.mapper.real_path_to_map(&path)?);
+            if !snapshot.contains(&blob) && !tracked.contains(&blob) {
+                results.push((State::Untracked, path));
+            }
+        }
+    }
+
+    Ok(results)
+}
+
+/// Main handler for comparing the filesystem against the bin
+pub fn handler(main_args: Args, args: StatusArgs) -> Result<(), libmeld::Error> {
+    let bin = main_args.open_bin()?;
+
+    let results = get_status(&bin)?;
+
+    let mut drift = 0;
+    for (state, path) in &results {
+        if state != &State::Unchanged {
+            drift += 1;
+        } else if !args.all {
+            continue;
+        }
+        println!("{:<10} {}", state.to_string(), path);
+    }
+
+    info!("{} of {} configs drifted", drift, results.len());
+
+    if drift > 0 {
+        Err(Error::DriftDetected { count: drift })
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, os::unix::fs::PermissionsExt};
+
+    use super::{get_status, State};
+    use crate::testing;
+
+    #[test]
+    fn drift_is_classified() {
+        let dir = tempfile::tempdir().unwrap();
+        let bin = testing::new_bin(dir.path());
+        let same = testing::write(dir.path(), "same.conf", "same");
+        let changed = testing::write(dir.path(), "changed.conf", "old");
+        let moded = testing::write(dir.path(), "moded.conf", "mode");
+        let gone = testing::write(dir.path(), "gone.conf", "gone");
+        testing::write(dir.path(), "d/x", "x");
+        for path in [&same, &changed, &moded, &gone] {
+            testing::push(&bin, path).unwrap();
+        }
+        testing::push(&bin, &testing::path(dir.path(), "home/d")).unwrap();
+
+        fs::write(&changed, "new").unwrap();
+        let mode = fs::metadata(&moded).unwrap().permissions().mode();
+        fs::set_permissions(&moded, fs::Permissions::from_mode(mode ^ 0o001)).unwrap();
+        fs::remove_file(&gone).unwrap();
+        let new = testing::write(dir.path(), "d/new", "new");
+
+        let mut status = get_status(&bin).unwrap();
+        status.sort_by(|a, b| a.1.cmp(&b.1));
+        assert_eq!(
+            status,
+            vec![
+                (State::Modified, changed),
+                (State::Unchanged, testing::path(dir.path(), "home/d")),
+                (State::Untracked, new),
+                (State::Unchanged, testing::path(dir.path(), "home/d/x")),
+                (State::Missing, gone),
+                (State::Metadata, moded),
+                (State::Unchanged, same),
+            ]
+        );
+    }
+}
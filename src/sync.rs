@@ -0,0 +1,338 @@
+use std::io::{self, BufRead, Write};
+
+use std::collections::{HashMap, HashSet};
+
+use crate::list::selected;
+use crate::pull::{map_at, pull_file};
+use crate::push::{push_config, push_map};
+use crate::Args;
+use libmeld::{exists, hash_contents, parse_time, Bin, Config, Error, Map};
+use log::{info, warn};
+use structopt::StructOpt;
+
+// Define Module Arguments
+#[derive(Debug, StructOpt, Clone)]
+pub struct SyncArgs {
+    #[structopt(help = "sync direction", subcommand)]
+    pub(crate) direction: SyncDirection,
+}
+
+#[derive(Debug, StructOpt, Clone)]
+pub enum SyncDirection {
+    /// Install every config in the bin onto this system
+    Down(SyncDownArgs),
+    /// Push new versions of every tracked config from this system
+    Up(SyncUpArgs),
+}
+
+#[derive(Debug, StructOpt, Clone)]
+pub struct SyncUpArgs {
+    #[structopt(
+        short = "t",
+        long = "tag",
+        default_value = "",
+        help = "tag for any new versions"
+    )]
+    pub(crate) tag: String,
+}
+
+#[derive(Debug, StructOpt, Clone)]
+pub struct SyncDownArgs {
+    #[structopt(short = "s", long = "subset", help = "only sync configs in subset")]
+    pub(crate) subset: Option<String>,
+
+    #[structopt(short = "f", long = "family", help = "only sync configs in family")]
+    pub(crate) family: Option<String>,
+
+    #[structopt(
+        short = "t",
+        long = "tag",
+        help = "only sync configs with a tagged version, and install that version"
+    )]
+    pub(crate) tag: Option<String>,
+
+    #[structopt(
+        long = "at",
+        parse(try_from_str = parse_time),
+        help = "install configs as they were at this local time (YYYY-MM-DD [HH:MM[:SS]] or RFC 3339)"
+    )]
+    pub(crate) at: Option<i64>,
+
+    #[structopt(long = "force", help = "overwrite local changes without asking")]
+    pub(crate) force: bool,
+
+    #[structopt(
+        short = "i",
+        long = "interactive",
+        help = "prompt before overwriting local changes instead of skipping them"
+    )]
+    pub(crate) interactive: bool,
+
+    #[structopt(
+        short = "n",
+        long = "dry-run",
+        help = "only show what would be installed"
+    )]
+    pub(crate) dry_run: bool,
+}
+
+/// Ask the user whether to overwrite a file
+fn confirm(path: &String) -> Result<bool, Error> {
+    print!(
+        "{} has local changes not in the bin; overwrite? [y/N] ",
+        path
+    );
+    if let Err(e) = io::stdout().flush() {
+        return Err(Error::IOError { msg: e.to_string() });
+    }
+
+    let mut answer = String::new();
+    if let Err(e) = io::stdin().lock().read_line(&mut answer) {
+        return Err(Error::IOError { msg: e.to_string() });
+    }
+
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+/// Install all selected configs from the bin
+fn sync_down(bin: &Bin, args: &SyncDownArgs) -> Result<(), Error> {
+    let tag = args.tag.clone().unwrap_or_default();
+    let mut installed = 0;
+    let mut skipped = 0;
+
+    // Map Path of each dir -> the versions its snapshot at the time pins
+    let mut dirs: Vec<(String, Option<HashMap<String, u32>>)> = Vec::new();
+
+    // summaries are ordered by map path, so dirs come before their contents
+    for c in bin.db.get_config_summaries()? {
+        if args.at.is_some() && c.map_ver.is_some() {
+            let pinned = match map_at(bin, &c.blob, args.at)? {
+                Some(m) => Some(bin.get_map_entries(&c.blob, m.ver)?.into_iter().collect()),
+                None => None,
+            };
+            dirs.push((c.map_path.clone(), pinned));
+        }
+
+        if !selected(&c, &args.subset, &args.family, &args.tag) {
+            continue;
+        }
+
+        // files of a dir are installed as of the dir's snapshot, if they were in it
+        let mut version = 0;
+        let dir = dirs
+            .iter()
+            .filter(|(d, _)| c.map_path.starts_with(&format!("{}/", d)))
+            .max_by_key(|(d, _)| d.len());
+        if let Some((_, pinned)) = dir {
+            match pinned.as_ref().and_then(|p| p.get(&c.blob)) {
+                Some(v) => version = *v,
+                None => {
+                    println!("skipped    {} (not in its dir then)", c.map_path);
+                    skipped += 1;
+                    continue;
+                }
+            }
+        } else if let Some(t) = args.at {
+            let pushed = bin.db.get_versions(&c.blob)?;
+            // with a tag, that tagged version has to be pushed by then
+            if !pushed
+                .values()
+                .any(|v| v.created <= t && (tag.is_empty() || v.tag == tag))
+            {
+                println!("skipped    {} (not pushed by then)", c.map_path);
+                skipped += 1;
+                continue;
+            }
+        }
+
+        let path = match bin.mapper.map_to_real_path(&c.map_path) {
+            Ok(p) => p,
+            Err(e) => {
+                warn!("Skipping {}: {}", c.map_path, e);
+                println!("skipped    {} (unmapped)", c.map_path);
+                skipped += 1;
+                continue;
+            }
+        };
+
+        // local contents matching any stored version are safe to replace
+        if exists(&path) && !args.force {
+            let local_hash = match hash_contents(&path) {
+                Ok(h) => h,
+                Err(e) => {
+                    warn!("Skipping {}: {}", path, e);
+                    println!("skipped    {} (unreadable)", path);
+                    skipped += 1;
+                    continue;
+                }
+            };
+            let mut known = false;
+            for v in bin.db.get_versions(&c.blob)?.values() {
+                if bin.matches_hash(v, &local_hash)? {
+                    known = true;
+                    break;
+                }
+            }
+
+            if !known {
+                let overwrite = args.interactive && !args.dry_run && confirm(&path)?;
+                if !overwrite {
+                    warn!("Skipping {}: local changes not in the bin", path);
+                    println!("skipped    {} (local changes)", path);
+                    skipped += 1;
+                    continue;
+                }
+            }
+        }
+
+        if args.dry_run {
+            println!("install    {}", path);
+            continue;
+        }
+
+        info!("Installing {}", path);
+        bin.transaction(|journal| {
+            if version != 0 {
+                return pull_file(bin, journal, &c.blob, &String::new(), false, version, None);
+            }
+            pull_file(bin, journal, &c.blob, &tag, false, 0, args.at)
+        })?;
+        installed += 1;
+    }
+
+    info!("Installed {} configs; skipped {}", installed, skipped);
+
+    Ok(())
+}
+
+/// Re-push every tracked config and dir map from this system
+fn sync_up(bin: &Bin, args: &SyncUpArgs) -> Result<(), Error> {
+    let mut pushed: HashSet<String> = HashSet::new();
+    let mut missing: HashSet<String> = HashSet::new();
+
+    // dirs first so new files are picked up and snapshots are updated
+    for map in bin.db.get_current_maps()? {
+        let map_path = match bin.db.get_mapped_path(&map.blob)? {
+            Some(p) => p,
+            None => {
+                warn!("Map {} has no config; skipping", map.blob);
+                continue;
+            }
+        };
+
+        let dir = match bin.mapper.map_to_real_path(&map_path) {
+            Ok(d) if exists(&d) => d,
+            Ok(_) | Err(_) => {
+                warn!("{} is missing on this system", map_path);
+                println!("missing    {}", map_path);
+                missing.insert(map.blob);
+                continue;
+            }
+        };
+
+        info!("Syncing dir {}", dir);
+        // empty subset and family leave the stored values untouched
+        let map = Map::new(
+            &bin.mapper,
+            bin.get_config(),
+            &dir,
+            String::new(),
+            String::new(),
+            args.tag.clone(),
+        )?;
+        pushed.extend(bin.transaction(|journal| push_map(bin, journal, map))?);
+    }
+
+    for c in bin.db.get_configs()? {
+        if pushed.contains(c.get_blob()) || missing.contains(c.get_blob()) {
+            continue;
+        }
+
+        let path = match bin.mapper.map_to_real_path(c.get_map_path()) {
+            Ok(p) if exists(&p) => p,
+            Ok(_) | Err(_) => {
+                warn!("{} is missing on this system", c.get_map_path());
+                println!("missing    {}", c.get_map_path());
+                missing.insert(c.get_blob().to_string());
+                continue;
+            }
+        };
+
+        info!("Syncing {}", path);
+        let config = match Config::from(
+            path.clone(),
+            c.get_map_path().to_string(),
+            String::new(),
+            String::new(),
+            args.tag.clone(),
+        ) {
+            Ok(c) => c,
+            Err(e) => {
+                warn!("Skipping {}: {}", path, e);
+                println!("skipped    {} (unreadable)", path);
+                continue;
+            }
+        };
+        bin.transaction(|journal| push_config(bin, journal, &config))?;
+        pushed.insert(c.get_blob().to_string());
+    }
+
+    info!("Synced {} configs; {} missing", pushed.len(), missing.len());
+
+    Ok(())
+}
+
+/// Main handler for syncing whole bins
+pub fn handler(main_args: Args, args: SyncArgs) -> Result<(), libmeld::Error> {
+    let bin = main_args.open_bin()?;
+
+    match args.direction {
+        SyncDirection::Down(down) => sync_down(&bin, &down),
+        SyncDirection::Up(up) => sync_up(&bin, &up),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use structopt::StructOpt;
+
+    use super::{sync_down, SyncDownArgs};
+    use crate::testing;
+
+    fn down(flags: &[&str]) -> SyncDownArgs {
+        SyncDownArgs::from_iter(["down"].iter().chain(flags))
+    }
+
+    #[test]
+    fn sync_down_keeps_unknown_local_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let bin = testing::new_bin(dir.path());
+        let gone = testing::write(dir.path(), "gone.conf", "gone");
+        let old = testing::write(dir.path(), "old.conf", "1");
+        let local = testing::write(dir.path(), "local.conf", "pushed");
+        for path in [&gone, &old, &local] {
+            testing::push(&bin, path).unwrap();
+        }
+        fs::write(&old, "2").unwrap();
+        testing::push(&bin, &old).unwrap();
+
+        fs::remove_file(&gone).unwrap();
+        // an older version is known to the bin, so it is safe to replace
+        fs::write(&old, "1").unwrap();
+        fs::write(&local, "edited").unwrap();
+
+        sync_down(&bin, &down(&["--dry-run"])).unwrap();
+        assert!(!std::path::Path::new(&gone).exists());
+        assert_eq!(fs::read_to_string(&old).unwrap(), "1");
+
+        sync_down(&bin, &down(&[])).unwrap();
+        assert_eq!(fs::read_to_string(&gone).unwrap(), "gone");
+        assert_eq!(fs::read_to_string(&old).unwrap(), "2");
+        assert_eq!(fs::read_to_string(&local).unwrap(), "edited");
+
+        sync_down(&bin, &down(&["--force"])).unwrap();
+        assert_eq!(fs::read_to_string(&local).unwrap(), "pushed");
+    }
+}
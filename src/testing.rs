@@ -0,0 +1,72 @@
+// Helpers for tests that run commands against a scratch bin
+use std::{fs, path::Path};
+
+use libmeld::{Bin, Config, Error, LockMode, LockWait, Map, Mapping};
+use structopt::StructOpt;
+
+use crate::push::{push_config, push_map};
+use crate::Args;
+
+/// Variable mapped to the home dir of a scratch bin
+pub(crate) const VAR: &str = "$T$";
+
+/// Create a bin at dir/bin with $T$ mapped to dir/home, and open it
+pub(crate) fn new_bin(dir: &Path) -> Bin {
+    let home = dir.join("home");
+    fs::create_dir_all(&home).unwrap();
+
+    let bin = Bin::new(path(dir, "bin"), None, false, false, LockWait::Never).unwrap();
+    let mapping = Mapping::new(VAR.to_string(), path(dir, "home"), None).unwrap();
+    bin.db.add_mapping(&mapping).unwrap();
+    drop(bin);
+
+    open_bin(dir)
+}
+
+/// Open the bin at dir/bin, held alone
+pub(crate) fn open_bin(dir: &Path) -> Bin {
+    Bin::from(path(dir, "bin"), None, LockMode::Exclusive, LockWait::Never).unwrap()
+}
+
+/// Real path of a file under dir
+pub(crate) fn path(dir: &Path, rel: &str) -> String {
+    dir.join(rel).to_string_lossy().to_string()
+}
+
+/// Write a file under dir/home, creating its parents; returns its real path
+pub(crate) fn write(dir: &Path, rel: &str, contents: &str) -> String {
+    let path = dir.join("home").join(rel);
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(&path, contents).unwrap();
+    path.to_string_lossy().to_string()
+}
+
+/// Push a file or dir as meld push does
+pub(crate) fn push(bin: &Bin, path: &String) -> Result<(), Error> {
+    if Path::new(path).is_dir() {
+        let map = Map::new(
+            &bin.mapper,
+            bin.get_config(),
+            path,
+            String::new(),
+            String::new(),
+            String::new(),
+        )?;
+        bin.transaction(|journal| push_map(bin, journal, map))?;
+    } else {
+        let config = Config::from(
+            path.clone(),
+            bin.mapper.real_path_to_map(path)?,
+            String::new(),
+            String::new(),
+            String::new(),
+        )?;
+        bin.transaction(|journal| push_config(bin, journal, &config))?;
+    }
+    Ok(())
+}
+
+/// Global args for a command against the bin at dir/bin
+pub(crate) fn args(dir: &Path) -> Args {
+    Args::from_iter(["meld", "--no-wait", &path(dir, "bin"), "list"])
+}
@@ -0,0 +1,27 @@
+use crate::Args;
+use libmeld::Error;
+use log::info;
+use structopt::StructOpt;
+
+// Define Module Arguments
+#[derive(Debug, StructOpt, Clone)]
+pub struct ValidateArgs {}
+
+/// Main handler for checking a bin's integrity
+pub fn handler(main_args: Args, _args: ValidateArgs) -> Result<(), libmeld::Error> {
+    let bin = main_args.inspect_bin()?;
+
+    let issues = bin.validate()?;
+    for issue in &issues {
+        println!("{}", issue);
+    }
+
+    if issues.is_empty() {
+        info!("Bin is valid");
+        Ok(())
+    } else {
+        Err(Error::ValidationFailed {
+            count: issues.len(),
+        })
+    }
+}